@@ -0,0 +1,106 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::Instant,
+};
+
+use crate::replication::PendingReplica;
+
+// Changed by CLIENT REPLY, the connection decides per command whether its reply is written
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplyMode {
+    On,
+    Off,
+    // Only the reply to the command after CLIENT REPLY SKIP is dropped
+    Skip,
+}
+
+// Everything that belongs to a single connection rather than to the server
+#[derive(Debug)]
+pub struct ClientState {
+    pub id: u64,
+    pub addr: String,
+    pub name: Option<String>,
+    pub connected_at: Instant,
+    pub last_interaction: Instant,
+    pub last_command: String,
+    pub reply_mode: ReplyMode,
+    // Announced by a replica with REPLCONF listening-port, before it asks for a full resync
+    pub listening_port: Option<u16>,
+    // Set by PSYNC, the connection is handed over to replication once the reply is sent
+    pub pending_replica: Option<PendingReplica>,
+    // Set by commands after which the connection should be closed, once their reply is sent
+    pub close_connection: bool,
+}
+
+impl ClientState {
+    fn new(id: u64, addr: &str) -> Self {
+        let now = Instant::now();
+
+        Self {
+            id,
+            addr: addr.to_string(),
+            name: None,
+            connected_at: now,
+            last_interaction: now,
+            last_command: String::new(),
+            reply_mode: ReplyMode::On,
+            listening_port: None,
+            pending_replica: None,
+            close_connection: false,
+        }
+    }
+
+    // One line of CLIENT LIST
+    pub fn describe(&self) -> String {
+        format!(
+            "id={} addr={} name={} age={} idle={} cmd={}",
+            self.id,
+            self.addr,
+            self.name.as_deref().unwrap_or_default(),
+            self.connected_at.elapsed().as_secs(),
+            self.last_interaction.elapsed().as_secs(),
+            self.last_command
+        )
+    }
+}
+
+// Owned by the connection handler, anyone else only holds on to it while looking at it
+pub type ClientHandle = Arc<Mutex<ClientState>>;
+
+#[derive(Debug, Default)]
+pub struct ClientRegistry {
+    last_id: AtomicU64,
+    // Weak, so a client is gone from the registry as soon as its connection is dropped
+    clients: Mutex<HashMap<u64, Weak<Mutex<ClientState>>>>,
+}
+
+impl ClientRegistry {
+    pub fn register(&self, addr: &str) -> ClientHandle {
+        let id = self.last_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let client = Arc::new(Mutex::new(ClientState::new(id, addr)));
+
+        let mut clients = self.clients.lock().unwrap();
+        clients.retain(|_, client| client.strong_count() > 0);
+        clients.insert(id, Arc::downgrade(&client));
+
+        client
+    }
+
+    // The clients that are still connected, ordered by id
+    pub fn connected(&self) -> Vec<ClientHandle> {
+        let mut clients: Vec<_> = self
+            .clients
+            .lock()
+            .unwrap()
+            .values()
+            .filter_map(Weak::upgrade)
+            .collect();
+
+        clients.sort_by_key(|client| client.lock().unwrap().id);
+        clients
+    }
+}
@@ -1,118 +1,589 @@
 use std::{
-    collections::HashMap,
-    time::{Duration, SystemTime},
+    collections::{HashMap, HashSet},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use crate::{
+    errors::CommandError,
     messages::{unpack_string, Message},
-    store::{Entry, StreamData, StreamId},
-    Command, XADDParams, XRANGEParams, XREADParams,
+    store::{Entry, ExpireCondition, StreamData},
+    Command, GetExExpiry, SCANParams, SETParams, SetCondition, XADDParams, XRANGEParams,
+    XREADParams,
 };
-use anyhow::{anyhow, bail, Ok, Result};
 
-pub fn get_key_value_from_args(args: &Vec<Message>) -> Result<(String, String)> {
-    if args.len() < 2 {
-        bail!("Incomplete command for set")
-    }
+type Result<T> = std::result::Result<T, CommandError>;
+
+// The helpers below don't know which command they are parsing, so the errors naming the command
+// leave it empty and `parse_client_command` fills it in
+fn wrong_arity() -> CommandError {
+    CommandError::WrongArity { cmd: String::new() }
+}
+
+fn invalid_expire_time() -> CommandError {
+    CommandError::InvalidExpireTime { cmd: String::new() }
+}
+
+fn unknown_command() -> CommandError {
+    CommandError::unknown_command(b"", &[])
+}
+
+fn unpack_arg(arg: &Message) -> Result<String> {
+    unpack_string(arg).map_err(|_| CommandError::SyntaxError)
+}
 
-    let key = unpack_string(args.first().unwrap())?;
-    let value = unpack_string(args.get(1).unwrap())?;
+pub fn get_string_from_args(args: &[Message], n: usize) -> Result<String> {
+    let Some(arg) = args.get(n) else {
+        return Err(wrong_arity());
+    };
 
-    Ok((key, value))
+    unpack_arg(arg)
 }
 
-pub fn get_string_from_args(args: &Vec<Message>, n: usize) -> Result<String> {
-    if args.len() < n {
-        bail!("Args is too small to have nth arg");
+// Unlike the other helpers this accepts binary data, like the payloads of RESTORE
+pub fn get_bytes_from_args(args: &[Message], n: usize) -> Result<Vec<u8>> {
+    match args.get(n) {
+        Some(Message::BulkBytes(bytes)) => Ok(bytes.clone()),
+        Some(arg) => Ok(unpack_arg(arg)?.into_bytes()),
+        None => Err(wrong_arity()),
     }
+}
 
-    Ok(unpack_string(args.get(n).unwrap())?)
+pub fn get_integer_from_args(args: &[Message], n: usize) -> Result<i64> {
+    get_string_from_args(args, n)?
+        .parse::<i64>()
+        .map_err(|_| CommandError::NotAnInteger)
+}
+
+// Like redis, bitmaps are capped at the default proto-max-bulk-len of 512MB, a lower configured
+// limit is checked when the bit is set
+const MAX_BIT_OFFSET: u64 = 512 * 1024 * 1024 * 8 - 1;
+
+pub fn get_bit_offset_from_args(args: &[Message], n: usize) -> Result<u64> {
+    get_string_from_args(args, n)?
+        .parse::<u64>()
+        .ok()
+        .filter(|offset| *offset <= MAX_BIT_OFFSET)
+        .ok_or(CommandError::InvalidBitOffset)
+}
+
+pub fn get_strings_from_args(args: &[Message]) -> Result<Vec<String>> {
+    args.iter().map(unpack_arg).collect()
 }
 
 pub fn get_stream_data(messages: &[Message]) -> Result<StreamData> {
-    if messages.len() % 2 != 0 {
-        bail!("Messages need to be in pairs of 2");
+    if messages.is_empty() || !messages.len().is_multiple_of(2) {
+        return Err(wrong_arity());
     }
 
-    let mut map = HashMap::new();
+    let mut data = Vec::with_capacity(messages.len() / 2);
 
     for i in (0..messages.len()).step_by(2) {
-        let key = unpack_string(&messages[i + 0]).unwrap();
-        let val = unpack_string(&messages[i + 1]).unwrap();
+        let key = unpack_arg(&messages[i])?;
+        let val = unpack_arg(&messages[i + 1])?;
 
-        map.insert(key, val);
+        data.push((key, val));
     }
 
-    Ok(StreamData { data: map })
+    Ok(StreamData { data })
 }
 
-pub fn get_config_params_from_args(args: &Vec<Message>) -> Result<(String, String)> {
-    if args.len() < 2 {
-        bail!("Incomplete command for args")
+#[derive(Debug, Clone, Copy)]
+pub enum ExpireUnit {
+    Seconds,
+    Milliseconds,
+}
+
+// Shared by every command taking a ttl. Like redis, a ttl has to be positive, and must not
+// overflow once it is turned into a unix time in milliseconds.
+pub fn validate_expire(value: i64, unit: ExpireUnit) -> Result<Duration> {
+    let millis = match unit {
+        ExpireUnit::Seconds => value.checked_mul(1000),
+        ExpireUnit::Milliseconds => Some(value),
+    };
+
+    match millis {
+        Some(millis) if millis > 0 && millis.checked_add(unix_millis()).is_some() => {
+            Ok(Duration::from_millis(millis as u64))
+        }
+        _ => Err(invalid_expire_time()),
     }
+}
+
+// EXPIRE and friends all turn into an absolute unix time in milliseconds, which is also what gets
+// propagated, so the clock of a replica can't shift the expiry
+pub fn get_expire_at_from_args(args: &[Message], unit: ExpireUnit, relative: bool) -> Result<i64> {
+    let value = get_integer_from_args(args, 1)?;
+
+    let millis = match unit {
+        ExpireUnit::Seconds => value.checked_mul(1000),
+        ExpireUnit::Milliseconds => Some(value),
+    };
+
+    let base = if relative { unix_millis() } else { 0 };
+
+    millis
+        .and_then(|millis| millis.checked_add(base))
+        .ok_or_else(invalid_expire_time)
+}
+
+// NX may not be combined with anything, GT and LT only with XX, which then has to hold as well
+fn get_expire_conditions(args: &[Message]) -> Result<Vec<ExpireCondition>> {
+    let (mut nx, mut xx, mut gt, mut lt) = (false, false, false, false);
+
+    for arg in args {
+        let option = unpack_arg(arg)?;
+
+        match option.to_lowercase().as_str() {
+            "nx" => nx = true,
+            "xx" => xx = true,
+            "gt" => gt = true,
+            "lt" => lt = true,
+            _ => {
+                return Err(CommandError::Custom(format!(
+                    "ERR Unsupported option {}",
+                    option
+                )))
+            }
+        }
+    }
+
+    if nx && (xx || gt || lt) {
+        return Err(CommandError::Custom(
+            "ERR NX and XX, GT or LT options at the same time are not compatible".to_string(),
+        ));
+    }
+
+    if gt && lt {
+        return Err(CommandError::Custom(
+            "ERR GT and LT options at the same time are not compatible".to_string(),
+        ));
+    }
+
+    let flags = [
+        (nx, ExpireCondition::NotExists),
+        (xx, ExpireCondition::Exists),
+        (gt, ExpireCondition::GreaterThan),
+        (lt, ExpireCondition::LessThan),
+    ];
+
+    Ok(flags
+        .into_iter()
+        .filter_map(|(set, condition)| set.then_some(condition))
+        .collect())
+}
+
+fn unix_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
 
-    let action = unpack_string(args.get(0).unwrap())?;
-    let key = unpack_string(args.get(1).unwrap())?;
+// The absolute forms only need to be positive and fit in milliseconds
+pub fn validate_expire_at(value: i64, unit: ExpireUnit) -> Result<SystemTime> {
+    let millis = match unit {
+        ExpireUnit::Seconds => value.checked_mul(1000),
+        ExpireUnit::Milliseconds => Some(value),
+    };
 
-    Ok((action, key))
+    match millis {
+        Some(millis) if millis > 0 => Ok(UNIX_EPOCH + Duration::from_millis(millis as u64)),
+        _ => Err(invalid_expire_time()),
+    }
 }
 
-pub fn get_expiry_from_args(args: &Vec<Message>) -> Option<Duration> {
-    if args.len() < 4 {
-        return None;
+// SET key value [NX | XX] [GET] [EX s | PX ms | EXAT s | PXAT ms | KEEPTTL], the options may come
+// in any order and any case, but only one of each group
+pub fn get_set_params(args: &[Message]) -> Result<SETParams> {
+    let key = get_string_from_args(args, 0)?;
+    let value = get_bytes_from_args(args, 1)?;
+
+    let mut condition = None;
+    let mut get = false;
+    let mut keep_ttl = false;
+    let mut expiry_at = None;
+    let mut index = 2;
+
+    while index < args.len() {
+        let option = unpack_arg(&args[index])?.to_lowercase();
+        index += 1;
+
+        match option.as_str() {
+            "nx" if condition.is_none() => condition = Some(SetCondition::NotExists),
+            "xx" if condition.is_none() => condition = Some(SetCondition::Exists),
+            "get" if !get => get = true,
+            "keepttl" if !keep_ttl && expiry_at.is_none() => keep_ttl = true,
+            "ex" | "px" | "exat" | "pxat" if !keep_ttl && expiry_at.is_none() => {
+                // A missing value is a syntax error, not an arity one
+                if index >= args.len() {
+                    return Err(CommandError::SyntaxError);
+                }
+
+                let value = get_integer_from_args(args, index)?;
+                index += 1;
+
+                expiry_at = Some(match option.as_str() {
+                    "ex" => SystemTime::now() + validate_expire(value, ExpireUnit::Seconds)?,
+                    "px" => SystemTime::now() + validate_expire(value, ExpireUnit::Milliseconds)?,
+                    "exat" => validate_expire_at(value, ExpireUnit::Seconds)?,
+                    _ => validate_expire_at(value, ExpireUnit::Milliseconds)?,
+                });
+            }
+            _ => return Err(CommandError::SyntaxError),
+        }
     }
 
-    if let Some(px) = args.get(2) {
-        let tag = unpack_string(px).unwrap_or_default();
-        if tag != "px" {
-            return None;
+    Ok(SETParams {
+        key,
+        entry: Entry {
+            value: value.into(),
+            expiry_at,
+        },
+        condition,
+        keep_ttl,
+        get,
+    })
+}
+
+const SCAN_DEFAULT_COUNT: usize = 10;
+
+// SCAN cursor [MATCH pattern] [COUNT count] [TYPE type], COUNT is how many keys a call looks at,
+// not how many it returns
+pub fn get_scan_params(args: &[Message]) -> Result<SCANParams> {
+    let cursor = get_string_from_args(args, 0)?
+        .parse::<u64>()
+        .map_err(|_| CommandError::InvalidCursor)?;
+
+    let mut pattern = None;
+    let mut count = SCAN_DEFAULT_COUNT;
+    let mut value_type = None;
+
+    // Every option takes a value, like redis a later one overrides an earlier one
+    for pair in args[1..].chunks(2) {
+        let [option, value] = pair else {
+            return Err(CommandError::SyntaxError);
+        };
+
+        match unpack_arg(option)?.to_lowercase().as_str() {
+            "match" => pattern = Some(unpack_arg(value)?),
+            "count" => {
+                count = unpack_arg(value)?
+                    .parse::<i64>()
+                    .map_err(|_| CommandError::NotAnInteger)?
+                    .try_into()
+                    .ok()
+                    .filter(|count| *count > 0)
+                    .ok_or(CommandError::SyntaxError)?;
+            }
+            "type" => value_type = Some(unpack_arg(value)?.to_lowercase()),
+            _ => return Err(CommandError::SyntaxError),
         }
     }
 
-    if let Some(duration) = args.get(3) {
-        let time = unpack_string(duration).unwrap_or_default();
-        let duration_time = time.parse::<u64>().unwrap_or_default();
+    Ok(SCANParams {
+        cursor,
+        pattern,
+        count,
+        value_type,
+    })
+}
 
-        return Some(Duration::from_millis(duration_time));
+// GETEX key [EX s | PX ms | EXAT s | PXAT ms | PERSIST] [NX | XX | GT | LT], the conditions
+// only go with a new expiry and conflict with each other like they do for EXPIRE
+fn get_getex_params(args: &[Message]) -> Result<Command> {
+    let key = get_string_from_args(args, 0)?;
+
+    let mut expire_at = None;
+    let mut persist = false;
+    let mut conditions = Vec::new();
+    let mut index = 1;
+
+    while index < args.len() {
+        let option = unpack_arg(&args[index])?.to_lowercase();
+        index += 1;
+
+        match option.as_str() {
+            "persist" if !persist && expire_at.is_none() => persist = true,
+            "ex" | "px" | "exat" | "pxat" if !persist && expire_at.is_none() => {
+                if index >= args.len() {
+                    return Err(CommandError::SyntaxError);
+                }
+
+                let value = get_integer_from_args(args, index)?;
+                index += 1;
+
+                let expiry_at = match option.as_str() {
+                    "ex" => SystemTime::now() + validate_expire(value, ExpireUnit::Seconds)?,
+                    "px" => SystemTime::now() + validate_expire(value, ExpireUnit::Milliseconds)?,
+                    "exat" => validate_expire_at(value, ExpireUnit::Seconds)?,
+                    _ => validate_expire_at(value, ExpireUnit::Milliseconds)?,
+                };
+
+                let millis = expiry_at.duration_since(UNIX_EPOCH).unwrap_or_default();
+                expire_at = Some(millis.as_millis() as i64);
+            }
+            "nx" | "xx" | "gt" | "lt" => conditions.push(args[index - 1].clone()),
+            _ => return Err(CommandError::SyntaxError),
+        }
     }
 
-    None
+    let conditions = get_expire_conditions(&conditions)?;
+
+    let expiry = match (expire_at, persist) {
+        (Some(expire_at), _) => Some(GetExExpiry::At(expire_at, conditions)),
+        _ if !conditions.is_empty() => return Err(CommandError::SyntaxError),
+        (None, true) => Some(GetExExpiry::Persist),
+        (None, false) => None,
+    };
+
+    Ok(Command::GetEx(key, expiry))
 }
 
-pub fn get_wait_args(args: &Vec<Message>) -> Result<(usize, u64)> {
+pub fn get_wait_args(args: &[Message]) -> Result<(usize, u64)> {
     if args.len() < 2 {
-        bail!("Incomplete command for wait")
+        return Err(wrong_arity());
     }
 
-    let num_replicas = unpack_string(args.get(0).unwrap())?.parse::<usize>()?;
+    let num_replicas = unpack_arg(&args[0])?
+        .parse::<usize>()
+        .map_err(|_| CommandError::NotAnInteger)?;
 
-    let timeout = unpack_string(args.get(1).unwrap())?.parse::<u64>()?;
+    let timeout = unpack_arg(&args[1])?
+        .parse::<u64>()
+        .map_err(|_| CommandError::NotAnInteger)?;
 
     Ok((num_replicas, timeout))
 }
 
 pub fn parse_client_command(message: &Message) -> Result<Command> {
-    let (command, args) = parse_command(message)?;
-    let command = command.to_lowercase();
+    let (name, args) = parse_command(message)?;
+    let command = name.to_lowercase();
+
+    parse_arguments(&command, &args).map_err(|err| match err {
+        CommandError::WrongArity { .. } => CommandError::WrongArity { cmd: command },
+        CommandError::InvalidExpireTime { .. } => CommandError::InvalidExpireTime { cmd: command },
+        CommandError::UnknownCommand { .. } => echoed_unknown_command(&name, &args),
+        err => err,
+    })
+}
 
-    match command.as_str() {
-        "ping" => Ok(Command::Ping),
-        "echo" => Ok(Command::Echo(unpack_string(args.first().unwrap())?)),
-        "set" => {
-            let (key, value) = get_key_value_from_args(&args)?;
-            let expiry = get_expiry_from_args(&args);
+// Echoed as the client sent it
+fn echoed_unknown_command(name: &str, args: &[Message]) -> CommandError {
+    let args: Vec<_> = (0..args.len())
+        .filter_map(|n| get_bytes_from_args(args, n).ok())
+        .collect();
+
+    CommandError::unknown_command(name.as_bytes(), &args)
+}
+
+// Like rename-command of redis: a renamed command only runs under its new name, and an empty new
+// name disables it. Fixed at startup
+#[derive(Debug, Default)]
+pub struct CommandRenames {
+    // Keyed by the new name
+    originals: HashMap<String, String>,
+    renamed: HashSet<String>,
+}
 
-            let entry = Entry::new(value, expiry);
+impl CommandRenames {
+    // Pairs of a command and its new name
+    pub fn new(renames: &[String]) -> Self {
+        let mut command_renames = Self::default();
 
-            Ok(Command::Set(key, entry))
+        for pair in renames.chunks_exact(2) {
+            let (command, new_name) = (pair[0].to_lowercase(), pair[1].to_lowercase());
+
+            if !new_name.is_empty() {
+                command_renames.originals.insert(new_name, command.clone());
+            }
+            command_renames.renamed.insert(command);
         }
+
+        command_renames
+    }
+
+    // The command under its original name, None when it wasn't renamed. Calling a command by the
+    // name it was renamed away from is an unknown command
+    pub fn resolve(&self, message: &Message) -> Result<Option<Message>> {
+        let Ok((name, args)) = parse_command(message) else {
+            return Ok(None);
+        };
+        let lowercase = name.to_lowercase();
+
+        if let Some(original) = self.originals.get(&lowercase) {
+            let mut original = vec![Message::BulkString(original.clone())];
+            original.extend(args);
+
+            Ok(Some(Message::Array(original)))
+        } else if self.renamed.contains(&lowercase) {
+            Err(echoed_unknown_command(&name, &args))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+fn parse_arguments(command: &str, args: &[Message]) -> Result<Command> {
+    match command {
+        "ping" => Ok(Command::Ping),
+        "quit" => Ok(Command::Quit),
+        "echo" => Ok(Command::Echo(get_string_from_args(args, 0)?)),
+        "set" => Ok(Command::Set(get_set_params(args)?)),
         "get" => {
-            let key: String = unpack_string(args.first().unwrap())?;
+            let key = get_string_from_args(args, 0)?;
             Ok(Command::Get(key))
         }
+        "getex" => get_getex_params(args),
+        "getrange" => {
+            let key = get_string_from_args(args, 0)?;
+            let start = get_integer_from_args(args, 1)?;
+            let end = get_integer_from_args(args, 2)?;
+
+            Ok(Command::GetRange(key, start, end))
+        }
+        "setrange" => {
+            let key = get_string_from_args(args, 0)?;
+            let offset = get_integer_from_args(args, 1)?;
+            let value = get_bytes_from_args(args, 2)?;
+
+            Ok(Command::SetRange(key, offset, value))
+        }
+        "append" => {
+            let key = get_string_from_args(args, 0)?;
+            let value = get_bytes_from_args(args, 1)?;
+
+            Ok(Command::Append(key, value))
+        }
+        "setbit" => {
+            let key = get_string_from_args(args, 0)?;
+            let offset = get_bit_offset_from_args(args, 1)?;
+            let bit = match get_string_from_args(args, 2)?.as_str() {
+                "0" => false,
+                "1" => true,
+                _ => return Err(CommandError::InvalidBit),
+            };
+
+            Ok(Command::SetBit(key, offset, bit))
+        }
+        "getbit" => {
+            let key = get_string_from_args(args, 0)?;
+            let offset = get_bit_offset_from_args(args, 1)?;
+
+            Ok(Command::GetBit(key, offset))
+        }
+        "dump" => {
+            let key = get_string_from_args(args, 0)?;
+
+            Ok(Command::Dump(key))
+        }
+        "restore" => {
+            let key = get_string_from_args(args, 0)?;
+            let ttl = get_integer_from_args(args, 1)?;
+            let payload = get_bytes_from_args(args, 2)?;
+
+            let ttl = u64::try_from(ttl).map_err(|_| CommandError::InvalidTtl)?;
+            let mut replace = false;
+
+            for option in get_strings_from_args(&args[3..])? {
+                match option.to_lowercase().as_str() {
+                    "replace" => replace = true,
+                    _ => return Err(CommandError::SyntaxError),
+                }
+            }
+
+            Ok(Command::Restore(key, ttl, payload, replace))
+        }
+        "copy" => {
+            let source = get_string_from_args(args, 0)?;
+            let destination = get_string_from_args(args, 1)?;
+            let mut replace = false;
+
+            let options = get_strings_from_args(&args[2..])?;
+            let mut options = options.iter();
+
+            while let Some(option) = options.next() {
+                match option.to_lowercase().as_str() {
+                    "replace" => replace = true,
+                    // There is only a single database to copy into
+                    "db" => match options.next().map(|db| db.parse::<i64>()) {
+                        Some(Ok(0)) => {}
+                        Some(Ok(_)) => return Err(CommandError::DbIndexOutOfRange),
+                        Some(Err(_)) => return Err(CommandError::NotAnInteger),
+                        None => return Err(CommandError::SyntaxError),
+                    },
+                    _ => return Err(CommandError::SyntaxError),
+                }
+            }
+
+            Ok(Command::Copy(source, destination, replace))
+        }
+        "expire" | "pexpire" | "expireat" | "pexpireat" => {
+            let key = get_string_from_args(args, 0)?;
+            let expire_at = match command {
+                "expire" => get_expire_at_from_args(args, ExpireUnit::Seconds, true)?,
+                "pexpire" => get_expire_at_from_args(args, ExpireUnit::Milliseconds, true)?,
+                "expireat" => get_expire_at_from_args(args, ExpireUnit::Seconds, false)?,
+                _ => get_expire_at_from_args(args, ExpireUnit::Milliseconds, false)?,
+            };
+
+            let conditions = get_expire_conditions(&args[2..])?;
+
+            Ok(Command::Expire(key, expire_at, conditions))
+        }
+        "incr" | "decr" | "incrby" | "decrby" => {
+            let key = get_string_from_args(args, 0)?;
+            let delta = match command {
+                "incr" => 1,
+                "decr" => -1,
+                "incrby" => get_integer_from_args(args, 1)?,
+                _ => get_integer_from_args(args, 1)?
+                    .checked_neg()
+                    .ok_or(CommandError::Overflow)?,
+            };
+
+            Ok(Command::IncrBy(key, delta))
+        }
+        "del" => {
+            if args.is_empty() {
+                return Err(wrong_arity());
+            }
+
+            Ok(Command::Del(get_strings_from_args(args)?))
+        }
+        "exists" => {
+            if args.is_empty() {
+                return Err(wrong_arity());
+            }
+
+            Ok(Command::Exists(get_strings_from_args(args)?))
+        }
+        "randomkey" => Ok(Command::RandomKey),
+        // There is a single database, so both flush the same thing
+        "flushall" | "flushdb" => {
+            if args.len() > 1 {
+                return Err(CommandError::SyntaxError);
+            }
+
+            let lazy = match args.first() {
+                None => false,
+                Some(_) => match get_string_from_args(args, 0)?.to_lowercase().as_str() {
+                    "async" => true,
+                    "sync" => false,
+                    _ => return Err(CommandError::SyntaxError),
+                },
+            };
+
+            Ok(Command::FlushAll(lazy))
+        }
+        "dbsize" => Ok(Command::DbSize),
+        "persist" => {
+            let key = get_string_from_args(args, 0)?;
+
+            Ok(Command::Persist(key))
+        }
         "info" => {
             let section = if !args.is_empty() {
-                unpack_string(args.first().unwrap())?
+                get_string_from_args(args, 0)?
             } else {
                 String::new()
             };
@@ -120,47 +591,81 @@ pub fn parse_client_command(message: &Message) -> Result<Command> {
             Ok(Command::Info(section))
         }
         "replconf" => {
-            let repl_args: Vec<_> = args.iter().map(|x| unpack_string(x).unwrap()).collect();
+            let repl_args = get_strings_from_args(args)?;
 
             Ok(Command::Replconf(repl_args))
         }
         "psync" => {
-            let psync_args: Vec<_> = args.iter().map(|x| unpack_string(x).unwrap()).collect();
+            let psync_args = get_strings_from_args(args)?;
 
             Ok(Command::Psync(psync_args))
         }
         "wait" => {
-            let (num_replicas, timeout) = get_wait_args(&args)?;
+            let (num_replicas, timeout) = get_wait_args(args)?;
 
             Ok(Command::Wait(num_replicas, timeout))
         }
+        "command" => {
+            let subcommand = if !args.is_empty() {
+                get_string_from_args(args, 0)?
+            } else {
+                String::new()
+            };
+            let command_args = get_strings_from_args(args.get(1..).unwrap_or_default())?;
+
+            Ok(Command::Command(subcommand, command_args))
+        }
+        "cluster" => {
+            let subcommand = get_string_from_args(args, 0)?;
+            let cluster_args = get_strings_from_args(&args[1..])?;
+
+            Ok(Command::Cluster(subcommand, cluster_args))
+        }
+        "client" => {
+            let subcommand = get_string_from_args(args, 0)?;
+            let client_args = get_strings_from_args(&args[1..])?;
+
+            Ok(Command::Client(subcommand, client_args))
+        }
+        "memory" => {
+            let subcommand = get_string_from_args(args, 0)?;
+            let memory_args = get_strings_from_args(&args[1..])?;
+
+            Ok(Command::Memory(subcommand, memory_args))
+        }
+        "debug" => {
+            let subcommand = get_string_from_args(args, 0)?;
+            let debug_args = get_strings_from_args(&args[1..])?;
+
+            Ok(Command::Debug(subcommand, debug_args))
+        }
         "config" => {
-            let (action, key) = get_config_params_from_args(&args)?;
+            let action = get_string_from_args(args, 0)?;
+            let config_args = get_strings_from_args(&args[1..])?;
 
-            Ok(Command::Config(action, key))
+            Ok(Command::Config(action, config_args))
         }
         "keys" => {
             let pattern = if !args.is_empty() {
-                unpack_string(args.first().unwrap())?
+                get_string_from_args(args, 0)?
             } else {
                 String::new()
             };
 
             Ok(Command::Keys(pattern))
         }
-        "type" => {
-            let key = if !args.is_empty() {
-                unpack_string(args.first().unwrap())?
-            } else {
-                String::new()
-            };
+        "object" => {
+            let subcommand = get_string_from_args(args, 0)?;
+            let key = get_string_from_args(args, 1)?;
 
-            Ok(Command::Type(key))
+            Ok(Command::Object(subcommand, key))
         }
+        "scan" => Ok(Command::Scan(get_scan_params(args)?)),
+        "type" => Ok(Command::Type(get_string_from_args(args, 0)?)),
         "xadd" => {
-            let key = get_string_from_args(&args, 0)?;
-            let id = get_string_from_args(&args, 1)?;
-            let data = get_stream_data(&args[2..])?;
+            let key = get_string_from_args(args, 0)?;
+            let id = get_string_from_args(args, 1)?;
+            let data = get_stream_data(args.get(2..).unwrap_or_default())?;
 
             Ok(Command::XADD(XADDParams {
                 key,
@@ -169,15 +674,21 @@ pub fn parse_client_command(message: &Message) -> Result<Command> {
             }))
         }
         "xrange" => {
-            let key = get_string_from_args(&args, 0)?;
-            let start = get_string_from_args(&args, 1)?;
-            let end = get_string_from_args(&args, 2)?;
+            let key = get_string_from_args(args, 0)?;
+            let start = get_string_from_args(args, 1)?;
+            let end = get_string_from_args(args, 2)?;
 
             Ok(Command::XRANGE(XRANGEParams { key, start, end }))
         }
+        "xinfo" => {
+            let subcommand = get_string_from_args(args, 0)?;
+            let xinfo_args = get_strings_from_args(&args[1..])?;
+
+            Ok(Command::XInfo(subcommand, xinfo_args))
+        }
         "xread" => {
             let mut marker = 0;
-            let first_args = get_string_from_args(&args, 0)?;
+            let first_args = get_string_from_args(args, 0)?;
 
             let mut expiration_time: Option<SystemTime> = None;
             let mut wait = false;
@@ -185,11 +696,13 @@ pub fn parse_client_command(message: &Message) -> Result<Command> {
             match first_args.to_lowercase().as_str() {
                 "streams" => marker += 1,
                 "block" => {
-                    let duration = get_string_from_args(&args, 1)?;
+                    let duration = get_string_from_args(args, 1)?;
 
-                    let duration = duration
-                        .parse::<u64>()
-                        .expect("Duration is not a valid number");
+                    let duration = duration.parse::<u64>().map_err(|_| {
+                        CommandError::Custom(
+                            "ERR timeout is not an integer or out of range".to_string(),
+                        )
+                    })?;
 
                     wait = duration == 0;
 
@@ -198,13 +711,17 @@ pub fn parse_client_command(message: &Message) -> Result<Command> {
 
                     marker += 3 // [block][duration][streams][...data];
                 }
-                _ => {
-                    bail!("Unexpected first argument for xread");
-                }
+                _ => return Err(CommandError::SyntaxError),
+            }
+
+            if args.len() <= marker {
+                return Err(wrong_arity());
             }
 
-            if (args.len() - marker) % 2 != 0 {
-                bail!("Unexpected amount of arguments for xread");
+            if !(args.len() - marker).is_multiple_of(2) {
+                return Err(CommandError::Custom(
+                    "ERR Unbalanced 'xread' list of streams: for each stream key an ID or '$' must be specified.".to_string(),
+                ));
             }
 
             let amount_of_streams = (args.len() - marker) / 2;
@@ -214,8 +731,8 @@ pub fn parse_client_command(message: &Message) -> Result<Command> {
             let mut requests: Vec<(String, String)> = Vec::with_capacity(amount_of_streams);
 
             for i in 0..amount_of_streams {
-                let key = get_string_from_args(&args, key_marker + i)?;
-                let id = get_string_from_args(&args, id_marker + i)?;
+                let key = get_string_from_args(args, key_marker + i)?;
+                let id = get_string_from_args(args, id_marker + i)?;
 
                 requests.push((key, id));
             }
@@ -226,18 +743,158 @@ pub fn parse_client_command(message: &Message) -> Result<Command> {
                 requests,
             }))
         }
-        _ => Err(anyhow!(format!("Unsupported command, {}", command))),
+        _ => Err(unknown_command()),
     }
 }
 
 fn parse_command(message: &Message) -> Result<(String, Vec<Message>)> {
     match message {
         Message::Array(x) => {
-            let command = unpack_string(x.first().unwrap())?;
-            let args = x.clone().into_iter().skip(1).collect();
+            let Some(command) = x.first() else {
+                return Err(CommandError::Custom("ERR empty command".to_string()));
+            };
+
+            let command = unpack_arg(command)?;
+            let args = x.iter().skip(1).cloned().collect();
 
             Ok((command, args))
         }
-        _ => Err(anyhow!("Unexpected command format")),
+        _ => Err(CommandError::Custom(
+            "ERR Protocol error: expected an array of bulk strings".to_string(),
+        )),
+    }
+}
+
+// What COMMAND reports about a command, in the shape redis uses: a negative arity is a minimum,
+// and the key positions are the first, the last (negative counts from the end) and the step
+#[derive(Debug)]
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub arity: i64,
+    pub flags: &'static [&'static str],
+    pub first_key: i64,
+    pub last_key: i64,
+    pub key_step: i64,
+}
+
+const fn spec(
+    name: &'static str,
+    arity: i64,
+    flags: &'static [&'static str],
+    keys: (i64, i64, i64),
+) -> CommandSpec {
+    CommandSpec {
+        name,
+        arity,
+        flags,
+        first_key: keys.0,
+        last_key: keys.1,
+        key_step: keys.2,
     }
 }
+
+impl CommandSpec {
+    // Whether the changes the command makes belong on the replicas too, which isn't the case
+    // for the ones that only touch the state of this server, like CONFIG SET
+    pub fn is_replicated(&self) -> bool {
+        !self.flags.contains(&NO_REPLICATE)
+    }
+
+    // Whether the command may wait for something else to happen before it replies
+    pub fn may_block(&self) -> bool {
+        self.flags.contains(&"blocking")
+    }
+
+    // Whether the command may grow the dataset, so room has to be made before it runs
+    pub fn denies_oom(&self) -> bool {
+        self.flags.contains(&"denyoom")
+    }
+}
+
+// Not a flag redis knows about, so COMMAND leaves it out
+pub const NO_REPLICATE: &str = "no_replicate";
+
+const NO_KEYS: (i64, i64, i64) = (0, 0, 0);
+const FIRST_KEY: (i64, i64, i64) = (1, 1, 1);
+
+// Every command `parse_arguments` knows, a new command needs an entry here as well
+pub const COMMAND_TABLE: &[CommandSpec] = &[
+    spec("ping", -1, &["fast", "stale"], NO_KEYS),
+    spec("quit", -1, &["fast", "stale", "loading"], NO_KEYS),
+    spec("echo", 2, &["fast"], NO_KEYS),
+    spec("set", -3, &["write", "denyoom"], FIRST_KEY),
+    spec("get", 2, &["readonly", "fast"], FIRST_KEY),
+    spec("getex", -2, &["write", "fast"], FIRST_KEY),
+    spec("getrange", 4, &["readonly"], FIRST_KEY),
+    spec("setrange", 4, &["write", "denyoom"], FIRST_KEY),
+    spec("append", 3, &["write", "denyoom", "fast"], FIRST_KEY),
+    spec("setbit", 4, &["write", "denyoom"], FIRST_KEY),
+    spec("getbit", 3, &["readonly", "fast"], FIRST_KEY),
+    spec("dump", 2, &["readonly"], FIRST_KEY),
+    spec("restore", -4, &["write", "denyoom"], FIRST_KEY),
+    spec("copy", -3, &["write", "denyoom"], (1, 2, 1)),
+    spec("expire", -3, &["write", "fast"], FIRST_KEY),
+    spec("pexpire", -3, &["write", "fast"], FIRST_KEY),
+    spec("expireat", -3, &["write", "fast"], FIRST_KEY),
+    spec("pexpireat", -3, &["write", "fast"], FIRST_KEY),
+    spec("persist", 2, &["write", "fast"], FIRST_KEY),
+    spec("incr", 2, &["write", "denyoom", "fast"], FIRST_KEY),
+    spec("decr", 2, &["write", "denyoom", "fast"], FIRST_KEY),
+    spec("incrby", 3, &["write", "denyoom", "fast"], FIRST_KEY),
+    spec("decrby", 3, &["write", "denyoom", "fast"], FIRST_KEY),
+    spec("del", -2, &["write"], (1, -1, 1)),
+    spec("flushall", -1, &["write"], NO_KEYS),
+    spec("flushdb", -1, &["write"], NO_KEYS),
+    spec("exists", -2, &["readonly", "fast"], (1, -1, 1)),
+    spec("randomkey", 1, &["readonly"], NO_KEYS),
+    spec("dbsize", 1, &["readonly", "fast"], NO_KEYS),
+    spec("info", -1, &["loading", "stale"], NO_KEYS),
+    spec(
+        "replconf",
+        -1,
+        &["admin", "noscript", "loading", "stale"],
+        NO_KEYS,
+    ),
+    spec("psync", -3, &["admin", "noscript"], NO_KEYS),
+    spec("wait", 3, &["noscript", "blocking"], NO_KEYS),
+    spec(
+        "config",
+        -2,
+        &["admin", "noscript", "loading", "stale", NO_REPLICATE],
+        NO_KEYS,
+    ),
+    spec("cluster", -2, &[], NO_KEYS),
+    spec(
+        "client",
+        -2,
+        &["admin", "noscript", "loading", "stale", NO_REPLICATE],
+        NO_KEYS,
+    ),
+    spec(
+        "debug",
+        -2,
+        &["admin", "noscript", "loading", "stale", NO_REPLICATE],
+        NO_KEYS,
+    ),
+    spec("command", -1, &["loading", "stale"], NO_KEYS),
+    spec("keys", 2, &["readonly"], NO_KEYS),
+    spec("object", -2, &["readonly"], (2, 2, 1)),
+    spec("memory", -2, &["readonly"], (2, 2, 1)),
+    spec("scan", -2, &["readonly"], NO_KEYS),
+    spec("type", 2, &["readonly", "fast"], FIRST_KEY),
+    spec("xadd", -5, &["write", "denyoom", "fast"], FIRST_KEY),
+    spec("xrange", -4, &["readonly"], FIRST_KEY),
+    // The keys come after STREAMS, so they have no fixed position
+    spec("xread", -4, &["readonly", "blocking"], NO_KEYS),
+    spec("xinfo", -2, &["readonly"], (2, 2, 1)),
+];
+
+pub fn all_command_names() -> impl Iterator<Item = &'static str> {
+    COMMAND_TABLE.iter().map(|spec| spec.name)
+}
+
+pub fn command_spec(name: &str) -> Option<&'static CommandSpec> {
+    COMMAND_TABLE
+        .iter()
+        .find(|spec| spec.name.eq_ignore_ascii_case(name))
+}
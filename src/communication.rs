@@ -1,86 +1,155 @@
-use std::collections::VecDeque;
+use std::{collections::VecDeque, mem::take};
 
 use anyhow::Result;
+use bytes::{Buf, BytesMut};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::TcpStream,
 };
 
-use crate::messages::Message;
+use crate::messages::{parse_rdb_file, Message, ParseError};
+
+// Any transport a client can talk to us over, TCP and unix sockets alike
+pub trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+// The size of a single read from the connection, like redis' PROTO_IOBUF_LEN
+const READ_BUFFER_SIZE: usize = 16 * 1024;
 
 pub struct MessageStream {
-    pub stream: TcpStream,
+    pub stream: Box<dyn Connection>,
     pub read_cache: VecDeque<Message>,
+    read_buffer: BytesMut,
+    protocol_error: Option<ParseError>,
+    // Queued replies, written to the connection all at once by `flush`
+    write_buffer: BytesMut,
+    // Reads from and writes to the connection since the last `take_io_counts`
+    reads: usize,
+    writes: usize,
 }
 
 impl MessageStream {
-    pub fn bind(stream: TcpStream) -> Self {
+    pub fn bind(stream: impl Connection + 'static) -> Self {
         Self {
-            stream,
+            stream: Box::new(stream),
             read_cache: VecDeque::new(),
+            read_buffer: BytesMut::new(),
+            protocol_error: None,
+            write_buffer: BytesMut::new(),
+            reads: 0,
+            writes: 0,
         }
     }
 
+    // Anything queued before goes out first, so the order of the replies is kept
     pub async fn write_raw(&mut self, data: &[u8]) -> Result<()> {
-        self.stream.write_all(data).await?;
-        self.stream.flush().await?;
+        self.write_buffer.extend_from_slice(data);
 
-        Ok(())
+        self.flush().await
     }
 
     pub async fn write(&mut self, message: Message) -> Result<()> {
-        let serialized = message.serialize()?;
-        self.write_raw(serialized.as_bytes()).await
+        self.queue(&message);
+
+        self.flush().await
     }
 
-    pub async fn read_message(&mut self) -> Option<Message> {
-        if self.read_cache.is_empty() {
-            self.read_stream().await;
+    // Nothing reaches the connection until the next flush
+    pub fn queue(&mut self, message: &Message) {
+        message.write_to(&mut self.write_buffer);
+    }
+
+    pub async fn flush(&mut self) -> Result<()> {
+        if self.write_buffer.is_empty() {
+            return Ok(());
         }
 
-        self.read_cache.pop_front()
+        self.stream.write_all(&self.write_buffer).await?;
+        self.stream.flush().await?;
+        self.write_buffer.clear();
+        self.writes += 1;
+
+        Ok(())
+    }
+
+    pub fn take_io_counts(&mut self) -> (usize, usize) {
+        (take(&mut self.reads), take(&mut self.writes))
+    }
+
+    // Closes our side of the connection, the client still gets everything written before
+    pub async fn shutdown(&mut self) -> Result<()> {
+        self.flush().await?;
+        self.stream.shutdown().await?;
+
+        Ok(())
     }
 
-    async fn read_stream(&mut self) {
-        let mut buffer = [0; 512];
+    // Messages parsed before a protocol error are still handed out, after those the error is
+    // returned and the stream should be considered unusable
+    pub async fn read_message(&mut self) -> Result<Option<Message>, ParseError> {
+        while self.read_cache.is_empty() {
+            if let Some(err) = self.protocol_error.take() {
+                return Err(err);
+            }
 
-        if let Ok(length) = self.stream.read(&mut buffer).await {
-            let mut index = 0;
+            if !self.read_stream().await {
+                return Ok(None);
+            }
+        }
 
-            while index < length {
-                let data = &buffer[index..length];
+        Ok(self.read_cache.pop_front())
+    }
 
-                if let Ok((message, offset)) = Message::parse(data) {
+    // Returns false once the connection is closed
+    async fn read_stream(&mut self) -> bool {
+        let mut buffer = [0; READ_BUFFER_SIZE];
+
+        let length = match self.stream.read(&mut buffer).await {
+            Ok(0) | Err(_) => return false,
+            Ok(length) => length,
+        };
+        self.reads += 1;
+
+        // Messages can be split over multiple reads, so keep what we can't parse yet around
+        self.read_buffer.extend_from_slice(&buffer[..length]);
+
+        loop {
+            match Message::parse_request(&self.read_buffer) {
+                // Empty requests, like a blank inline line, are skipped just like redis does
+                Ok((Message::Array(items), offset)) if items.is_empty() => {
+                    self.read_buffer.advance(offset);
+                }
+                Ok((message, offset)) => {
                     self.read_cache.push_back(message);
-                    index += offset;
-                } else {
-                    println!("Invalid data structure");
+                    self.read_buffer.advance(offset);
+                }
+                Err(ParseError::Incomplete) => break,
+                Err(err) => {
+                    self.read_buffer.clear();
+                    self.protocol_error = Some(err);
                     break;
                 }
             }
         }
+
+        true
     }
 }
 
 #[derive(Debug)]
 pub enum ReplicaMessage {
-    RdbFile(String),
+    RdbFile(Vec<u8>),
     Response(Message),
 }
 
 impl ReplicaMessage {
     pub fn is_rdb_file(&self) -> bool {
-        match self {
-            Self::RdbFile(_) => true,
-            _ => false,
-        }
+        matches!(self, Self::RdbFile(_))
     }
 
     pub fn is_response(&self) -> bool {
-        match self {
-            Self::Response(_) => true,
-            _ => false,
-        }
+        matches!(self, Self::Response(_))
     }
 }
 
@@ -88,6 +157,7 @@ impl ReplicaMessage {
 pub struct ReplicaStream {
     pub stream: TcpStream,
     pub read_cache: VecDeque<ReplicaMessage>,
+    read_buffer: BytesMut,
 }
 
 impl ReplicaStream {
@@ -95,6 +165,7 @@ impl ReplicaStream {
         Self {
             stream,
             read_cache: VecDeque::new(),
+            read_buffer: BytesMut::new(),
         }
     }
 
@@ -106,74 +177,75 @@ impl ReplicaStream {
     }
 
     pub async fn write(&mut self, message: Message) -> Result<()> {
-        let serialized = message.serialize()?;
-        self.write_raw(serialized.as_bytes()).await
+        let mut buffer = BytesMut::new();
+        message.write_to(&mut buffer);
+
+        self.write_raw(&buffer).await
     }
 
-    pub async fn get_rdb(&mut self) -> Option<String> {
-        if self.read_cache.is_empty() && !self.read_stream().await {
-            return None;
-        }
+    pub async fn get_rdb(&mut self) -> Option<Vec<u8>> {
+        loop {
+            let index = self.read_cache.iter().position(|x| x.is_rdb_file());
 
-        let index = self.read_cache.iter().position(|x| x.is_rdb_file());
+            if let Some(index) = index {
+                if let ReplicaMessage::RdbFile(rdb) = self.read_cache.remove(index).unwrap() {
+                    return Some(rdb);
+                }
+            }
 
-        if let Some(index) = index {
-            if let ReplicaMessage::RdbFile(rdb) = self.read_cache.remove(index).unwrap() {
-                return Some(rdb);
+            if !self.read_stream().await {
+                return None;
             }
         }
-
-        None
     }
 
     pub async fn get_response(&mut self) -> Option<Message> {
-        if self.read_cache.is_empty() && !self.read_stream().await {
-            return None;
-        }
+        loop {
+            let index = self.read_cache.iter().position(|x| x.is_response());
 
-        let index = self.read_cache.iter().position(|x| x.is_response());
+            if let Some(index) = index {
+                if let ReplicaMessage::Response(message) = self.read_cache.remove(index).unwrap() {
+                    return Some(message);
+                }
+            }
 
-        if let Some(index) = index {
-            if let ReplicaMessage::Response(message) = self.read_cache.remove(index).unwrap() {
-                return Some(message);
+            if !self.read_stream().await {
+                return None;
             }
         }
-
-        None
     }
 
+    // Returns false once the connection is closed, or the master sent something we can't parse
     async fn read_stream(&mut self) -> bool {
-        let mut buffer = [0; 512];
-
-        if let Ok(length) = self.stream.read(&mut buffer).await {
-            let mut index = 0;
-
-            while index < length {
-                let data: &[u8] = &buffer[index..length];
-
-                let message = match &data[0] {
-                    b'$' => {
-                        index += 93; // hardcoded the empty rdb file length
-                        ReplicaMessage::RdbFile(String::from("foobar"))
-                    }
-                    b'+' | b'*' => {
-                        let (message, offset) = Message::parse(data).unwrap();
-
-                        index += offset;
-
-                        ReplicaMessage::Response(message)
-                    }
-                    _ => {
-                        todo!("unsupported file format");
-                    }
-                };
-
-                self.read_cache.push_back(message);
+        let mut buffer = [0; READ_BUFFER_SIZE];
+
+        let length = match self.stream.read(&mut buffer).await {
+            Ok(0) | Err(_) => return false,
+            Ok(length) => length,
+        };
+
+        self.read_buffer.extend_from_slice(&buffer[..length]);
+
+        while !self.read_buffer.is_empty() {
+            // The master only sends a top level bulk string for the rdb file of a full resync
+            let result = if self.read_buffer[0] == b'$' {
+                parse_rdb_file(&self.read_buffer)
+                    .map(|(rdb, offset)| (ReplicaMessage::RdbFile(rdb), offset))
+            } else {
+                Message::parse(&self.read_buffer)
+                    .map(|(message, offset)| (ReplicaMessage::Response(message), offset))
+            };
+
+            match result {
+                Ok((message, offset)) => {
+                    self.read_cache.push_back(message);
+                    self.read_buffer.advance(offset);
+                }
+                Err(ParseError::Incomplete) => break,
+                Err(_) => return false,
             }
-
-            true
-        } else {
-            false
         }
+
+        true
     }
 }
@@ -1,8 +1,10 @@
-use std::{fmt, net::{SocketAddr, ToSocketAddrs}};
+use std::{env, fmt, fs, net::{IpAddr, SocketAddr, ToSocketAddrs}, path::{Path, PathBuf}, sync::atomic::{AtomicBool, AtomicUsize, Ordering}};
 
-use tokio::sync::Mutex;
+use anyhow::{bail, Context};
+use clap::ValueEnum;
+use tokio::sync::{Mutex, Notify};
 
-use crate::{replication::ReplicaHandle, CommandLineArgs};
+use crate::{client::ClientRegistry, commands::CommandRenames, errors::CommandError, logging::{self, LogLevel}, replication::{ReplicaHandle, ReplicationBacklog}, util::glob_match, CommandLineArgs};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum ReplicationRole {
@@ -21,61 +23,332 @@ impl fmt::Display for ReplicationRole {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ServerConfiguration {
     pub dir: Option<String>,
-    pub dbfilename: Option<String>
+    pub dbfilename: Option<String>,
+    pub bind: Vec<IpAddr>,
+    pub port: u16,
+    pub unixsocket: Option<PathBuf>,
+    pub replicaof: Option<Vec<String>>,
+    pub daemonize: bool,
+    pub pidfile: Option<PathBuf>,
+    pub tcp_nodelay: bool,
+    pub tcp_keepalive: u64,
+    pub loglevel: LogLevel,
+    // Only enforced with an evicting maxmemory-policy, nothing is ever snapshotted or appended to a log
+    pub maxmemory: u64,
+    pub maxmemory_policy: MaxmemoryPolicy,
+    // Only limits how far strings grow, the protocol parser always allows the 512MB default
+    pub proto_max_bulk_len: u64,
+    pub save: String,
+    pub appendonly: bool
 }
 
 impl ServerConfiguration {
-    pub fn new() -> Self {
+    pub fn new(args: &CommandLineArgs) -> Self {
         ServerConfiguration {
-            dir: None,
-            dbfilename: None
+            dir: args.dir.clone(),
+            dbfilename: args.dbfilename.clone(),
+            bind: args.address.clone(),
+            port: args.port,
+            unixsocket: args.unixsocket.clone(),
+            replicaof: args.replicaof.clone(),
+            daemonize: args.daemonize,
+            pidfile: args.pidfile.clone(),
+            tcp_nodelay: args.tcp_nodelay,
+            tcp_keepalive: args.tcp_keepalive,
+            loglevel: args.loglevel,
+            maxmemory: 0,
+            maxmemory_policy: MaxmemoryPolicy::Noeviction,
+            proto_max_bulk_len: DEFAULT_PROTO_MAX_BULK_LEN,
+            save: String::new(),
+            appendonly: false
+        }
+    }
+
+    // Like CONFIG GET, the values of every parameter matching one of the patterns, in registry order
+    pub fn get_values(&self, patterns: &[String]) -> Vec<(&'static str, String)> {
+        CONFIG_PARAMETERS.iter()
+            .filter(|param| patterns.iter().any(|pattern| glob_match(&pattern.to_lowercase(), param.name)))
+            .map(|param| (param.name, (param.get)(self)))
+            .collect()
+    }
+
+    // Like CONFIG SET, either every value is applied or none of them
+    pub fn set_values(&mut self, values: &[(String, String)]) -> Result<(), CommandError> {
+        let mut config = self.clone();
+
+        for (name, value) in values {
+            let Some(param) = CONFIG_PARAMETERS.iter().find(|param| param.name == name.to_lowercase()) else {
+                return Err(CommandError::Custom(format!("ERR Unknown option or number of arguments for CONFIG SET - '{}'", name)));
+            };
+
+            let Some(set) = param.set else {
+                return Err(set_failed(param, "can't set immutable config"));
+            };
+
+            let valid = match param.kind {
+                ConfigType::Integer => value.parse::<u64>().is_ok(),
+                ConfigType::Bool => value == "yes" || value == "no",
+                ConfigType::String | ConfigType::Enum => true
+            };
+
+            if !valid || !set(&mut config, value) {
+                return Err(set_failed(param, "argument couldn't be parsed"));
+            }
+        }
+
+        if config.loglevel != self.loglevel {
+            logging::set_level(config.loglevel);
         }
+
+        *self = config;
+        Ok(())
     }
+}
+
+// The policies that are supported, writes past maxmemory simply go through with noeviction
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum MaxmemoryPolicy {
+    Noeviction,
+    AllkeysLfu
+}
 
-    pub fn get_value(&self, key: &str) -> Option<String> {
-        match key.to_lowercase().as_str() {
-            "dir" => { self.dir.clone() },
-            "dbfilename" => { self.dbfilename.clone() },
-            _ => { None }
+impl MaxmemoryPolicy {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Noeviction => "noeviction",
+            Self::AllkeysLfu => "allkeys-lfu"
         }
     }
 }
 
+const DEFAULT_PROTO_MAX_BULK_LEN: u64 = 512 * 1024 * 1024;
+// Redis doesn't accept anything lower
+const MIN_PROTO_MAX_BULK_LEN: u64 = 1024 * 1024;
+
+fn set_failed(param: &ConfigParameter, reason: &str) -> CommandError {
+    CommandError::Custom(format!("ERR CONFIG SET failed (possibly related to argument '{}') - {}", param.name, reason))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConfigType {
+    String,
+    Integer,
+    Bool,
+    Enum
+}
+
+// Everything CONFIG knows about a single parameter
+pub struct ConfigParameter {
+    pub name: &'static str,
+    // What the parameter starts out as when it isn't on the command line
+    #[allow(dead_code)]
+    pub default: &'static str,
+    pub kind: ConfigType,
+    pub get: fn(&ServerConfiguration) -> String,
+    // None for the parameters that can only be given on the command line. Called with values that
+    // already match the kind, returns false for the ones it still rejects
+    pub set: Option<fn(&mut ServerConfiguration, &str) -> bool>
+}
+
+pub const CONFIG_PARAMETERS: &[ConfigParameter] = &[
+    ConfigParameter {
+        name: "dir", default: "", kind: ConfigType::String,
+        // Without a dir the rdb file is looked up in the working directory
+        get: |config| config.dir.clone().unwrap_or_else(|| env::current_dir().map(|dir| dir.display().to_string()).unwrap_or_default()),
+        set: Some(|config, value| {
+            config.dir = Some(value.to_string());
+            Path::new(value).is_dir()
+        })
+    },
+    ConfigParameter {
+        name: "dbfilename", default: "", kind: ConfigType::String,
+        get: |config| config.dbfilename.clone().unwrap_or_default(),
+        set: Some(|config, value| {
+            config.dbfilename = Some(value.to_string());
+            true
+        })
+    },
+    ConfigParameter {
+        name: "bind", default: "127.0.0.1", kind: ConfigType::String,
+        get: |config| config.bind.iter().map(|addr| addr.to_string()).collect::<Vec<_>>().join(" "),
+        set: None
+    },
+    ConfigParameter {
+        name: "port", default: "6379", kind: ConfigType::Integer,
+        get: |config| config.port.to_string(),
+        set: None
+    },
+    ConfigParameter {
+        name: "unixsocket", default: "", kind: ConfigType::String,
+        get: |config| display_path(&config.unixsocket),
+        set: None
+    },
+    ConfigParameter {
+        name: "replicaof", default: "", kind: ConfigType::String,
+        get: |config| config.replicaof.as_ref().map(|args| args.join(" ")).unwrap_or_default(),
+        set: None
+    },
+    ConfigParameter {
+        name: "daemonize", default: "no", kind: ConfigType::Bool,
+        get: |config| yes_no(config.daemonize),
+        set: None
+    },
+    ConfigParameter {
+        name: "pidfile", default: "", kind: ConfigType::String,
+        get: |config| display_path(&config.pidfile),
+        set: None
+    },
+    ConfigParameter {
+        name: "tcp-nodelay", default: "yes", kind: ConfigType::Bool,
+        get: |config| yes_no(config.tcp_nodelay),
+        set: None
+    },
+    ConfigParameter {
+        name: "tcp-keepalive", default: "0", kind: ConfigType::Integer,
+        get: |config| config.tcp_keepalive.to_string(),
+        set: None
+    },
+    ConfigParameter {
+        name: "loglevel", default: "info", kind: ConfigType::Enum,
+        get: |config| config.loglevel.name().to_string(),
+        set: Some(|config, value| match LogLevel::from_str(value, true) {
+            Ok(level) => { config.loglevel = level; true },
+            Err(_) => { false }
+        })
+    },
+    ConfigParameter {
+        name: "maxmemory", default: "0", kind: ConfigType::Integer,
+        get: |config| config.maxmemory.to_string(),
+        set: Some(|config, value| {
+            config.maxmemory = value.parse().unwrap_or_default();
+            true
+        })
+    },
+    ConfigParameter {
+        name: "maxmemory-policy", default: "noeviction", kind: ConfigType::Enum,
+        get: |config| config.maxmemory_policy.name().to_string(),
+        set: Some(|config, value| match MaxmemoryPolicy::from_str(value, true) {
+            Ok(policy) => { config.maxmemory_policy = policy; true },
+            Err(_) => { false }
+        })
+    },
+    ConfigParameter {
+        name: "proto-max-bulk-len", default: "536870912", kind: ConfigType::Integer,
+        get: |config| config.proto_max_bulk_len.to_string(),
+        set: Some(|config, value| {
+            config.proto_max_bulk_len = value.parse().unwrap_or_default();
+            config.proto_max_bulk_len >= MIN_PROTO_MAX_BULK_LEN
+        })
+    },
+    ConfigParameter {
+        name: "save", default: "", kind: ConfigType::String,
+        get: |config| config.save.clone(),
+        set: Some(|config, value| {
+            config.save = value.to_string();
+            true
+        })
+    },
+    ConfigParameter {
+        name: "appendonly", default: "no", kind: ConfigType::Bool,
+        get: |config| yes_no(config.appendonly),
+        set: Some(|config, value| {
+            config.appendonly = value == "yes";
+            true
+        })
+    },
+];
+
+fn display_path(path: &Option<PathBuf>) -> String {
+    path.as_ref().map(|path| path.display().to_string()).unwrap_or_default()
+}
+
+fn yes_no(value: bool) -> String {
+    if value { "yes" } else { "no" }.to_string()
+}
+
+#[derive(Debug, Default)]
+pub struct ServerStats {
+    pub keyspace_hits: AtomicUsize,
+    pub keyspace_misses: AtomicUsize,
+    pub expired_keys: AtomicUsize,
+    pub evicted_keys: AtomicUsize,
+    // Reads from and writes to client connections, a batch of pipelined replies is one write
+    pub total_reads_processed: AtomicUsize,
+    pub total_writes_processed: AtomicUsize,
+}
+
+impl ServerStats {
+    pub fn record_lookup(&self, hit: bool) {
+        let counter = if hit { &self.keyspace_hits } else { &self.keyspace_misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_expired(&self, count: usize) {
+        self.expired_keys.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn record_evicted(&self, count: usize) {
+        self.evicted_keys.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn record_io(&self, (reads, writes): (usize, usize)) {
+        self.total_reads_processed.fetch_add(reads, Ordering::Relaxed);
+        self.total_writes_processed.fetch_add(writes, Ordering::Relaxed);
+    }
+}
+
 #[derive(Debug)]
 pub struct ServerInformation {
     pub role: ReplicationRole,
+    // Reported by CLUSTER MYID, there is no cluster mode so it never has to be unique
+    pub node_id: String,
     pub repl_id: String,
-    pub repl_offset: usize,
+    pub repl_offset: AtomicUsize,
+    // Only ever updated together with the offset
+    pub repl_backlog: Mutex<ReplicationBacklog>,
 
     pub config: Mutex<ServerConfiguration>,
-    
-    pub socket_address: SocketAddr,
+    pub stats: ServerStats,
+    // Toggled by DEBUG SET-ACTIVE-EXPIRE, lazy expiry on reads happens either way
+    pub active_expire_enabled: AtomicBool,
+
+    // The port the tcp listeners ended up on, which is only known after binding when 0 was asked for
+    pub port: u16,
     pub replication_handles: Mutex<Vec<ReplicaHandle>>,
+    pub clients: ClientRegistry,
+    // Woken up whenever a replica acks an offset
+    pub replica_acks: Notify,
+    pub command_renames: CommandRenames,
 }
 
 impl ServerInformation {
-    pub fn new(args: &CommandLineArgs) -> Self {
+    pub fn new(args: &CommandLineArgs, port: u16) -> Self {
         let role = if let Some(addr) = parse_replication_addr(args) {
             ReplicationRole::Replication(addr)
         } else {
             ReplicationRole::Master
         };
 
-        let address = args.address;
-        let port = args.port;
-
-        let socket_address: SocketAddr = SocketAddr::new(address, port);
+        let mut config = ServerConfiguration::new(args);
+        config.port = port;
 
         Self {
             role,
+            node_id: String::from("e7d1eecce10fd6bb5eb35b9f99a514335d9ba9ca"),
             repl_id: String::from("8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb"),
-            repl_offset: 0,
-            config: Mutex::new(ServerConfiguration::new()),
-            socket_address,
-            replication_handles: Mutex::new(Vec::new())
+            repl_offset: AtomicUsize::new(0),
+            repl_backlog: Mutex::new(ReplicationBacklog::default()),
+            config: Mutex::new(config),
+            stats: ServerStats::default(),
+            active_expire_enabled: AtomicBool::new(true),
+            port,
+            replication_handles: Mutex::new(Vec::new()),
+            clients: ClientRegistry::default(),
+            replica_acks: Notify::new(),
+            command_renames: CommandRenames::new(&args.rename_command)
         }
     }
 }
@@ -101,4 +374,60 @@ fn parse_replication_addr(args: &CommandLineArgs) -> Option<SocketAddr> {
     };
 
     None
-}
\ No newline at end of file
+}
+// The directives of a redis.conf style file as command line options, `port 6380` becoming
+// `--port 6380`. Blank lines and comments are skipped.
+pub fn read_config_file(path: &Path) -> anyhow::Result<Vec<String>> {
+    let content = fs::read_to_string(path).with_context(|| format!("unable to read the config file {}", path.display()))?;
+    let mut options = Vec::new();
+
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let Some(mut args) = split_config_line(line) else {
+            bail!("unbalanced quotes in the config file {} on line {}", path.display(), number + 1);
+        };
+
+        options.push(format!("--{}", args.remove(0).to_lowercase()));
+        options.extend(args);
+    }
+
+    Ok(options)
+}
+
+// Splits on whitespace like sdssplitargs, where a double or single quoted argument may hold spaces
+// or be empty. Inside double quotes a backslash escapes the next character. None when a quote is
+// left open.
+fn split_config_line(line: &str) -> Option<Vec<String>> {
+    let mut args = Vec::new();
+    let mut chars = line.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+
+        let Some(&first) = chars.peek() else { return Some(args) };
+        let mut arg = String::new();
+
+        if first == '"' || first == '\'' {
+            chars.next();
+
+            loop {
+                match chars.next()? {
+                    '\\' if first == '"' => arg.push(chars.next()?),
+                    c if c == first => break,
+                    c => arg.push(c)
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                arg.push(c);
+            }
+        }
+
+        args.push(arg);
+    }
+}
@@ -0,0 +1,107 @@
+use thiserror::Error;
+
+use crate::messages::Message;
+
+// Every error a command can reply with, the texts are the ones redis uses
+#[derive(Debug, Error, PartialEq)]
+pub enum CommandError {
+    #[error("WRONGTYPE Operation against a key holding the wrong kind of value")]
+    WrongType,
+    #[error("ERR wrong number of arguments for '{cmd}' command")]
+    WrongArity { cmd: String },
+    #[error("ERR unknown command '{cmd}', with args beginning with: {args}")]
+    UnknownCommand { cmd: String, args: String },
+    #[error("ERR unknown subcommand '{subcommand}'. Try {cmd} HELP.")]
+    UnknownSubcommand { cmd: String, subcommand: String },
+    #[error("ERR invalid expire time in '{cmd}' command")]
+    InvalidExpireTime { cmd: String },
+    #[error("ERR value is not an integer or out of range")]
+    NotAnInteger,
+    #[error("ERR increment or decrement would overflow")]
+    Overflow,
+    #[error("ERR bit offset is not an integer or out of range")]
+    InvalidBitOffset,
+    #[error("ERR string exceeds maximum allowed size (proto-max-bulk-len)")]
+    StringTooLong,
+    #[error("ERR bit is not an integer or out of range")]
+    InvalidBit,
+    #[error("ERR no such key")]
+    NoSuchKey,
+    #[error("ERR syntax error")]
+    SyntaxError,
+    #[error("ERR invalid cursor")]
+    InvalidCursor,
+    #[error("ERR offset is out of range")]
+    OffsetOutOfRange,
+    #[error("ERR Invalid stream ID specified as stream command argument")]
+    InvalidStreamId,
+    #[error("ERR The ID specified in XADD must be greater than 0-0")]
+    StreamIdZero,
+    #[error("ERR The ID specified in XADD is equal or smaller than the target stream top item")]
+    StreamIdTooSmall,
+    #[error("BUSYKEY Target key name already exists.")]
+    BusyKey,
+    #[error("ERR DUMP payload version or checksum are wrong")]
+    BadDumpPayload,
+    #[error("ERR Invalid TTL value, must be >= 0")]
+    InvalidTtl,
+    #[error("ERR source and destination objects are the same")]
+    SameObject,
+    #[error("ERR DB index is out of range")]
+    DbIndexOutOfRange,
+    #[error("ERR An LFU maxmemory policy is not selected, access frequency not tracked. Please note that when switching between policies at runtime LRU and LFU data will take some time to adjust.")]
+    LfuNotSelected,
+    #[error("NOGROUP No such consumer group '{group}' for key name '{key}'")]
+    NoGroup { key: String, group: String },
+    #[error("{0}")]
+    Custom(String),
+}
+
+// How much of the command name and of its arguments an unknown command error shows, like redis
+const UNKNOWN_COMMAND_ECHO_LENGTH: usize = 128;
+
+impl CommandError {
+    pub fn to_message(&self) -> Message {
+        Message::Error(self.to_string())
+    }
+
+    // The arguments are quoted one after the other, until they take up the echo length
+    pub fn unknown_command(cmd: &[u8], args: &[Vec<u8>]) -> Self {
+        let mut quoted = Vec::new();
+
+        for arg in args {
+            if quoted.len() >= UNKNOWN_COMMAND_ECHO_LENGTH {
+                break;
+            }
+
+            let length = arg.len().min(UNKNOWN_COMMAND_ECHO_LENGTH - quoted.len());
+
+            quoted.push(b'\'');
+            quoted.extend(&arg[..length]);
+            quoted.extend(b"' ");
+        }
+
+        let cmd = &cmd[..cmd.len().min(UNKNOWN_COMMAND_ECHO_LENGTH)];
+
+        Self::UnknownCommand {
+            cmd: printable(cmd),
+            args: printable(&quoted),
+        }
+    }
+}
+
+// An error is a single line, so line breaks become spaces like redis does it, and other control
+// characters are escaped
+fn printable(bytes: &[u8]) -> String {
+    let mut escaped = Vec::with_capacity(bytes.len());
+
+    for &byte in bytes {
+        match byte {
+            b'\r' | b'\n' => escaped.push(b' '),
+            byte if byte.is_ascii_control() => escaped.extend(format!("\\x{:02x}", byte).bytes()),
+            byte => escaped.push(byte),
+        }
+    }
+
+    String::from_utf8_lossy(&escaped).into_owned()
+}
@@ -0,0 +1,1298 @@
+use std::{
+    future::{poll_fn, Future},
+    pin::Pin,
+    sync::{atomic::Ordering, Arc, MutexGuard},
+    task::Poll,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{
+    sync::{futures::Notified, RwLock},
+    task,
+    time::{sleep, timeout},
+};
+
+use crate::{
+    client::{ClientHandle, ClientState, ReplyMode},
+    command_name,
+    commands::{command_spec, CommandSpec, COMMAND_TABLE, NO_REPLICATE},
+    configuration::{MaxmemoryPolicy, ReplicationRole, ServerInformation},
+    errors::CommandError,
+    info::{
+        build_cluster_response, build_replication_response, build_server_response,
+        build_stats_response,
+    },
+    messages::{stream_entry_to_message, stream_to_message, Message},
+    replication::{
+        attach_replica, propagate_expired_key, propagate_to_replicas, wait_for_replica_acks,
+        ReplicaAddress,
+    },
+    store::{
+        get_end_of_xrange_id, get_start_of_xrange_id, EntryValue, ExpireCondition, Store,
+        StoreItem, Stream, StreamData, StreamId,
+    },
+    util::{glob_match, key_hash_slot},
+    Command, GetExExpiry, SETParams, SetCondition, XADDParams, XRANGEParams, XREADParams,
+};
+
+const KEYS_BATCH_SIZE: usize = 1000;
+const MEMORY_DEFAULT_SAMPLES: usize = 5;
+
+// Everything a command may touch, except the connection itself
+pub struct ExecutionContext {
+    pub store: Arc<RwLock<Store>>,
+    pub information: Arc<ServerInformation>,
+    pub client: ClientHandle,
+}
+
+impl ExecutionContext {
+    pub fn new(
+        store: Arc<RwLock<Store>>,
+        information: Arc<ServerInformation>,
+        client: ClientHandle,
+    ) -> Self {
+        Self {
+            store,
+            information,
+            client,
+        }
+    }
+
+    // Never hold on to this across an await, other connections may be listing the clients
+    pub fn client(&self) -> MutexGuard<'_, ClientState> {
+        self.client.lock().unwrap()
+    }
+}
+
+// Runs a single command and returns its reply, `message` is the original request
+// which gets forwarded to the replicas for the commands that modify the store
+pub async fn execute(command: Command, message: &Message, ctx: &mut ExecutionContext) -> Message {
+    if command_spec(command_name(message)).is_some_and(CommandSpec::denies_oom) {
+        evict_keys(ctx).await;
+    }
+
+    match run_command(command, message, ctx).await {
+        Ok(reply) => reply,
+        Err(err) => err.to_message(),
+    }
+}
+
+// Makes room for a command that may grow the dataset, when the maxmemory policy evicts keys. A
+// replica keeps whatever its master sends it, the DEL for every evicted key included.
+async fn evict_keys(ctx: &ExecutionContext) {
+    if ctx.information.role != ReplicationRole::Master {
+        return;
+    }
+
+    let (maxmemory, policy) = {
+        let config = ctx.information.config.lock().await;
+        (config.maxmemory, config.maxmemory_policy)
+    };
+
+    if maxmemory == 0 || policy != MaxmemoryPolicy::AllkeysLfu {
+        return;
+    }
+
+    // Most writes find the dataset fitting, and don't need to wait for the write lock
+    if ctx.store.read().await.dataset_memory_usage() <= maxmemory as usize {
+        return;
+    }
+
+    let mut store = ctx.store.write().await;
+    let evicted = store.evict_least_frequently_used(maxmemory as usize);
+
+    ctx.information.stats.record_evicted(evicted.len());
+
+    for key in evicted {
+        let message = Message::Array(vec![
+            Message::bulk_string("DEL".to_string()),
+            Message::bulk_string(key),
+        ]);
+
+        propagate_to_replicas(&ctx.information, &message).await;
+    }
+}
+
+// The proto-max-bulk-len the commands growing a string are held to
+async fn max_string_length(ctx: &ExecutionContext) -> usize {
+    ctx.information.config.lock().await.proto_max_bulk_len as usize
+}
+
+async fn run_command(
+    command: Command,
+    message: &Message,
+    ctx: &mut ExecutionContext,
+) -> Result<Message, CommandError> {
+    let reply = match command {
+        Command::Ping => Message::simple_string_from_str("PONG"),
+        Command::Echo(value) => Message::bulk_string(value),
+        Command::Quit => {
+            ctx.client().close_connection = true;
+
+            Message::simple_string_from_str("OK")
+        }
+        Command::Set(params) => execute_set(params, ctx).await?,
+        Command::Get(key) => {
+            let value = ctx
+                .store
+                .read()
+                .await
+                .get_kv_value(&key)?
+                .map(|entry| entry.value.to_bytes());
+
+            ctx.information.stats.record_lookup(value.is_some());
+
+            match value {
+                Some(value) => Message::bulk_bytes(value),
+                None => {
+                    // Like the active expiry, a replica leaves removing the key to the master
+                    let is_master = ctx.information.role == ReplicationRole::Master;
+
+                    if is_master {
+                        let mut store = ctx.store.write().await;
+
+                        if store.remove_if_expired(&key) {
+                            propagate_expired_key(&ctx.information, &key).await;
+                            ctx.information.stats.record_expired(1);
+                        }
+                    }
+
+                    Message::Null
+                }
+            }
+        }
+        Command::GetEx(key, expiry) => execute_getex(key, expiry, ctx).await?,
+        Command::GetRange(key, start, end) => {
+            let value = ctx.store.read().await.get_range(&key, start, end)?;
+
+            Message::bulk_bytes(value)
+        }
+        Command::SetRange(key, offset, value) => {
+            let max_length = max_string_length(ctx).await;
+            let mut store = ctx.store.write().await;
+            let length = store.set_range(&key, offset, &value, max_length)?;
+
+            // Like redis, a no-op doesn't reach the replicas
+            if !value.is_empty() {
+                propagate_to_replicas(&ctx.information, message).await;
+            }
+
+            Message::Integer(length as isize)
+        }
+        Command::Append(key, value) => {
+            let max_length = max_string_length(ctx).await;
+            let mut store = ctx.store.write().await;
+            let length = store.append(&key, &value, max_length)?;
+
+            propagate_to_replicas(&ctx.information, message).await;
+
+            Message::Integer(length as isize)
+        }
+        Command::SetBit(key, offset, bit) => {
+            let max_length = max_string_length(ctx).await;
+            let mut store = ctx.store.write().await;
+            let previous = store.set_bit(&key, offset, bit, max_length)?;
+            propagate_to_replicas(&ctx.information, message).await;
+
+            Message::Integer(previous as isize)
+        }
+        Command::GetBit(key, offset) => {
+            let bit = ctx.store.read().await.get_bit(&key, offset)?;
+
+            Message::Integer(bit as isize)
+        }
+        Command::Dump(key) => match ctx.store.read().await.dump(&key) {
+            Some(payload) => Message::BulkBytes(payload),
+            None => Message::Null,
+        },
+        Command::Copy(source, destination, replace) => {
+            let mut store = ctx.store.write().await;
+            let copied = store.copy(&source, destination, replace)?;
+
+            if copied {
+                propagate_to_replicas(&ctx.information, message).await;
+            }
+
+            Message::Integer(copied as isize)
+        }
+        Command::Restore(key, ttl, payload, replace) => {
+            // A ttl of 0 restores the key without an expiry
+            let ttl = (ttl > 0).then(|| Duration::from_millis(ttl));
+
+            let mut store = ctx.store.write().await;
+            store.restore(key, ttl, &payload, replace)?;
+            propagate_to_replicas(&ctx.information, message).await;
+
+            Message::simple_string_from_str("OK")
+        }
+        Command::Expire(key, expire_at, conditions) => {
+            execute_expire(key, expire_at, conditions, ctx).await?
+        }
+        Command::Del(keys) => {
+            let mut store = ctx.store.write().await;
+            let removed = keys.iter().filter(|key| store.delete(key)).count();
+
+            if removed > 0 {
+                propagate_to_replicas(&ctx.information, message).await;
+            }
+
+            Message::Integer(removed as isize)
+        }
+        Command::IncrBy(key, delta) => {
+            let mut store = ctx.store.write().await;
+            let value = store.incr_by(&key, delta)?;
+            propagate_to_replicas(&ctx.information, message).await;
+
+            Message::Integer(value as isize)
+        }
+        Command::Persist(key) => {
+            let mut store = ctx.store.write().await;
+            let updated = store.persist(&key);
+
+            if updated {
+                propagate_to_replicas(&ctx.information, message).await;
+            }
+
+            Message::Integer(updated as isize)
+        }
+        Command::FlushAll(lazy) => {
+            let mut store = ctx.store.write().await;
+
+            if lazy {
+                store.flush_async();
+            } else {
+                store.flush();
+            }
+
+            propagate_to_replicas(&ctx.information, message).await;
+
+            Message::simple_string_from_str("OK")
+        }
+        Command::DbSize => Message::Integer(ctx.store.read().await.len() as isize),
+        Command::Info(section) => execute_info(&section, ctx).await,
+        Command::Replconf(params) => {
+            if let [option, port] = params.as_slice() {
+                if option.eq_ignore_ascii_case("listening-port") {
+                    let port = port.parse().map_err(|_| CommandError::NotAnInteger)?;
+                    ctx.client().listening_port = Some(port);
+                }
+            }
+
+            Message::simple_string_from_str("OK")
+        }
+        Command::Psync(params) => {
+            // A replica that never announced its port is reported with port 0, like redis does
+            let address = {
+                let client = ctx.client();
+                ReplicaAddress::new(&client.addr, client.listening_port.unwrap_or(0))
+            };
+
+            let (reply, replica) =
+                attach_replica(&ctx.store, &ctx.information, &params, address).await;
+            ctx.client().pending_replica = Some(replica);
+
+            reply
+        }
+        Command::Wait(num_replicas, wait_time) => {
+            // Replicas that already acked everything we propagated count right away,
+            // which is all of them as long as nothing has been written
+            let count = wait_for_replica_acks(
+                &ctx.information,
+                num_replicas,
+                Duration::from_millis(wait_time),
+            )
+            .await;
+
+            Message::Integer(count as isize)
+        }
+        Command::Config(action, args) => match action.to_lowercase().as_str() {
+            "get" => {
+                if args.is_empty() {
+                    return Err(CommandError::WrongArity {
+                        cmd: "config|get".to_string(),
+                    });
+                }
+
+                let values = ctx.information.config.lock().await.get_values(&args);
+
+                Message::Array(
+                    values
+                        .into_iter()
+                        .flat_map(|(name, value)| {
+                            [
+                                Message::bulk_string(name.to_string()),
+                                Message::bulk_string(value),
+                            ]
+                        })
+                        .collect(),
+                )
+            }
+            "set" => {
+                if args.is_empty() || !args.len().is_multiple_of(2) {
+                    return Err(CommandError::WrongArity {
+                        cmd: "config|set".to_string(),
+                    });
+                }
+
+                let values: Vec<_> = args
+                    .chunks(2)
+                    .map(|pair| (pair[0].clone(), pair[1].clone()))
+                    .collect();
+
+                ctx.information.config.lock().await.set_values(&values)?;
+
+                Message::simple_string_from_str("OK")
+            }
+            _ => {
+                return Err(CommandError::UnknownSubcommand {
+                    cmd: "CONFIG".to_string(),
+                    subcommand: action,
+                })
+            }
+        },
+        Command::Cluster(subcommand, args) => execute_cluster(subcommand, &args, ctx)?,
+        Command::Client(subcommand, args) => execute_client(subcommand, &args, ctx)?,
+        Command::Memory(subcommand, args) => execute_memory(subcommand, &args, ctx).await?,
+        Command::Debug(subcommand, args) => execute_debug(subcommand, &args, ctx).await?,
+        Command::Command(subcommand, args) => execute_command(subcommand, &args)?,
+        Command::Exists(keys) => {
+            let store = ctx.store.read().await;
+
+            // Like redis, a key given twice is counted twice, and every key is a lookup
+            let found = keys
+                .iter()
+                .filter(|key| {
+                    let exists = store.exists(key);
+                    ctx.information.stats.record_lookup(exists);
+                    exists
+                })
+                .count();
+
+            Message::Integer(found as isize)
+        }
+        Command::RandomKey => match ctx.store.read().await.random_key() {
+            Some(key) => Message::bulk_string(key),
+            None => Message::Null,
+        },
+        Command::Keys(pattern) => execute_keys(&pattern, ctx).await,
+        Command::Object(subcommand, key) => match subcommand.to_lowercase().as_str() {
+            "idletime" => match ctx.store.read().await.idle_time(&key) {
+                Some(idle_time) => Message::Integer(idle_time.as_secs() as isize),
+                None => Message::Null,
+            },
+            "freq" => {
+                let policy = ctx.information.config.lock().await.maxmemory_policy;
+
+                if policy != MaxmemoryPolicy::AllkeysLfu {
+                    return Err(CommandError::LfuNotSelected);
+                }
+
+                match ctx.store.read().await.frequency(&key) {
+                    Some(frequency) => Message::Integer(frequency as isize),
+                    None => Message::Null,
+                }
+            }
+            "encoding" => match ctx.store.read().await.encoding(&key) {
+                Some(encoding) => Message::bulk_string(encoding.to_string()),
+                None => Message::Null,
+            },
+            _ => {
+                return Err(CommandError::UnknownSubcommand {
+                    cmd: "OBJECT".to_string(),
+                    subcommand,
+                })
+            }
+        },
+        Command::Scan(params) => {
+            // MATCH and TYPE only filter the batch, which can leave it empty while the cursor goes on
+            let matches = |key: &String, item: &StoreItem| {
+                let pattern = params.pattern.as_deref();
+                let value_type = params.value_type.as_deref();
+
+                pattern.is_none_or(|pattern| glob_match(pattern, key))
+                    && value_type.is_none_or(|value_type| value_type == item.value_type())
+            };
+
+            let store = ctx.store.read().await;
+            let (next_cursor, keys) = store.scan_filtered(params.cursor, params.count, matches);
+
+            Message::Array(vec![
+                Message::BulkString(next_cursor.to_string()),
+                Message::Array(keys.into_iter().map(Message::BulkString).collect()),
+            ])
+        }
+        Command::Type(key) => {
+            let value_type = match ctx.store.read().await.get_value(&key) {
+                Some(value) => value.value_type(),
+                None => String::from("none"),
+            };
+
+            Message::simple_string(value_type)
+        }
+        Command::XADD(params) => execute_xadd(params, ctx).await?,
+        Command::XRANGE(params) => execute_xrange(params, ctx).await?,
+        Command::XREAD(params) => execute_xread(params, ctx).await?,
+        Command::XInfo(subcommand, args) => execute_xinfo(subcommand, &args, ctx).await?,
+    };
+
+    Ok(reply)
+}
+
+fn unix_time_millis(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
+// `expire_at` is a unix time in milliseconds, every form of EXPIRE has been turned into one
+async fn execute_expire(
+    key: String,
+    expire_at: i64,
+    conditions: Vec<ExpireCondition>,
+    ctx: &ExecutionContext,
+) -> Result<Message, CommandError> {
+    let mut store = ctx.store.write().await;
+    let updated = update_expiry(&mut store, key, expire_at, &conditions, ctx).await?;
+
+    Ok(Message::Integer(updated as isize))
+}
+
+// Sets the expiry when the conditions allow it and propagates the change, returns whether it was
+// set. The caller holds on to the store until the change is propagated.
+async fn update_expiry(
+    store: &mut Store,
+    key: String,
+    expire_at: i64,
+    conditions: &[ExpireCondition],
+    ctx: &ExecutionContext,
+) -> Result<bool, CommandError> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64;
+
+    // A master deletes keys whose expiry is already behind it. Replicas leave that to the DEL
+    // the master sends, and apply whatever expiry they are given.
+    let is_master = ctx.information.role == ReplicationRole::Master;
+    let expiry_at = UNIX_EPOCH + Duration::from_millis(expire_at.max(0) as u64);
+
+    if is_master && expire_at <= now {
+        // The flags still decide, a key only goes when its expiry would have been set
+        let removed =
+            store.set_expiry(&key, expiry_at, conditions) && store.remove_if_expired(&key);
+
+        if removed {
+            propagate_expired_key(&ctx.information, &key).await;
+        }
+
+        return Ok(removed);
+    }
+
+    let updated = store.set_expiry(&key, expiry_at, conditions);
+
+    if updated {
+        // Always propagated as an absolute time without flags, whatever form the client used
+        let message = Message::Array(vec![
+            Message::bulk_string("PEXPIREAT".to_string()),
+            Message::bulk_string(key),
+            Message::bulk_string(expire_at.to_string()),
+        ]);
+
+        propagate_to_replicas(&ctx.information, &message).await;
+    }
+
+    Ok(updated)
+}
+
+async fn execute_getex(
+    key: String,
+    expiry: Option<GetExExpiry>,
+    ctx: &ExecutionContext,
+) -> Result<Message, CommandError> {
+    let mut store = ctx.store.write().await;
+
+    let value = store
+        .get_kv_value(&key)?
+        .map(|entry| entry.value.to_bytes());
+
+    ctx.information.stats.record_lookup(value.is_some());
+
+    let Some(value) = value else {
+        return Ok(Message::Null);
+    };
+
+    // Whether the expiry changes or not, the value is returned
+    match expiry {
+        Some(GetExExpiry::At(expire_at, conditions)) => {
+            update_expiry(&mut store, key, expire_at, &conditions, ctx).await?;
+        }
+        Some(GetExExpiry::Persist) if store.persist(&key) => {
+            let message = Message::Array(vec![
+                Message::bulk_string("PERSIST".to_string()),
+                Message::bulk_string(key),
+            ]);
+
+            propagate_to_replicas(&ctx.information, &message).await;
+        }
+        _ => {}
+    }
+
+    Ok(Message::bulk_bytes(value))
+}
+
+async fn execute_set(params: SETParams, ctx: &ExecutionContext) -> Result<Message, CommandError> {
+    let SETParams {
+        key,
+        mut entry,
+        condition,
+        keep_ttl,
+        get,
+    } = params;
+
+    let mut store = ctx.store.write().await;
+
+    // Only GET cares about the type of the old value, a plain SET overwrites anything
+    let previous = if get {
+        store
+            .get_kv_value(&key)?
+            .map(|entry| entry.value.to_bytes())
+    } else {
+        None
+    };
+    let exists = store.exists(&key);
+
+    let reply = |previous: Option<Vec<u8>>, ok: Message| match (get, previous) {
+        (true, Some(previous)) => Message::bulk_bytes(previous),
+        (true, None) => Message::Null,
+        (false, _) => ok,
+    };
+
+    match condition {
+        Some(SetCondition::NotExists) if exists => return Ok(reply(previous, Message::Null)),
+        Some(SetCondition::Exists) if !exists => return Ok(reply(previous, Message::Null)),
+        _ => {}
+    }
+
+    if keep_ttl {
+        entry.expiry_at = store.expiry_at(&key);
+    }
+
+    // Replicas get the outcome rather than the request, the master already checked NX and XX and
+    // a relative expiry would end up at a different moment on every replica
+    let mut propagated = vec![
+        Message::bulk_string("SET".to_string()),
+        Message::bulk_string(key.clone()),
+        Message::bulk_bytes(entry.value.to_bytes()),
+    ];
+
+    if let Some(expiry_at) = entry.expiry_at {
+        propagated.push(Message::bulk_string("PXAT".to_string()));
+        propagated.push(Message::bulk_string(
+            unix_time_millis(expiry_at).to_string(),
+        ));
+    }
+
+    store.set_kv_value(key, entry);
+    propagate_to_replicas(&ctx.information, &Message::Array(propagated)).await;
+
+    Ok(reply(previous, Message::simple_string_from_str("OK")))
+}
+
+fn execute_command(subcommand: String, args: &[String]) -> Result<Message, CommandError> {
+    let reply = match subcommand.to_lowercase().as_str() {
+        "" => Message::Array(COMMAND_TABLE.iter().map(command_info).collect()),
+        "count" => Message::Integer(COMMAND_TABLE.len() as isize),
+        "list" => {
+            let filter = match args {
+                [] => None,
+                [filterby, kind, value]
+                    if filterby.eq_ignore_ascii_case("filterby")
+                        && ["module", "aclcat", "pattern"]
+                            .contains(&kind.to_lowercase().as_str()) =>
+                {
+                    Some((kind.to_lowercase(), value.to_lowercase()))
+                }
+                _ => return Err(CommandError::SyntaxError),
+            };
+
+            let specs = COMMAND_TABLE.iter().filter(|spec| match &filter {
+                None => true,
+                Some((kind, pattern)) if kind == "pattern" => glob_match(pattern, spec.name),
+                Some((kind, category)) if kind == "aclcat" => in_acl_category(spec, category),
+                // There are no modules, so no command comes from one
+                Some(_) => false,
+            });
+
+            Message::Array(
+                specs
+                    .map(|spec| Message::bulk_string(spec.name.to_string()))
+                    .collect(),
+            )
+        }
+        "info" => Message::Array(
+            args.iter()
+                .map(|name| match command_spec(name) {
+                    Some(spec) => command_info(spec),
+                    None => Message::Null,
+                })
+                .collect(),
+        ),
+        // Clients only use the docs for help texts, having none is fine
+        "docs" => Message::Array(vec![]),
+        _ => {
+            return Err(CommandError::UnknownSubcommand {
+                cmd: "COMMAND".to_string(),
+                subcommand,
+            })
+        }
+    };
+
+    Ok(reply)
+}
+
+// Only the categories that follow from the flags of a command
+fn in_acl_category(spec: &CommandSpec, category: &str) -> bool {
+    match category {
+        "read" => spec.flags.contains(&"readonly"),
+        "write" => spec.flags.contains(&"write"),
+        "fast" => spec.flags.contains(&"fast"),
+        "slow" => !spec.flags.contains(&"fast"),
+        _ => false,
+    }
+}
+
+fn command_info(spec: &CommandSpec) -> Message {
+    Message::Array(vec![
+        Message::bulk_string(spec.name.to_string()),
+        Message::Integer(spec.arity as isize),
+        Message::Array(
+            spec.flags
+                .iter()
+                .filter(|flag| **flag != NO_REPLICATE)
+                .map(|flag| Message::simple_string_from_str(flag))
+                .collect(),
+        ),
+        Message::Integer(spec.first_key as isize),
+        Message::Integer(spec.last_key as isize),
+        Message::Integer(spec.key_step as isize),
+    ])
+}
+
+fn execute_client(
+    subcommand: String,
+    args: &[String],
+    ctx: &ExecutionContext,
+) -> Result<Message, CommandError> {
+    let wrong_arity = || CommandError::WrongArity {
+        cmd: format!("client|{}", subcommand.to_lowercase()),
+    };
+
+    let reply = match subcommand.to_lowercase().as_str() {
+        "id" => Message::Integer(ctx.client().id as isize),
+        "getname" => match &ctx.client().name {
+            Some(name) => Message::bulk_string(name.clone()),
+            None => Message::Null,
+        },
+        "setname" => {
+            let [name] = args else {
+                return Err(wrong_arity());
+            };
+
+            // The name ends up in CLIENT LIST, where spaces would break the format
+            if name.chars().any(|c| c == ' ' || !c.is_ascii_graphic()) {
+                return Err(CommandError::Custom(
+                    "ERR Client names cannot contain spaces, newlines or special characters."
+                        .to_string(),
+                ));
+            }
+
+            ctx.client().name = (!name.is_empty()).then(|| name.clone());
+
+            Message::simple_string_from_str("OK")
+        }
+        "list" => {
+            let lines: String = ctx
+                .information
+                .clients
+                .connected()
+                .iter()
+                .map(|client| format!("{}\n", client.lock().unwrap().describe()))
+                .collect();
+
+            Message::bulk_string(lines)
+        }
+        "reply" => {
+            let [mode] = args else {
+                return Err(wrong_arity());
+            };
+
+            let mut client = ctx.client();
+
+            client.reply_mode = match mode.to_lowercase().as_str() {
+                "on" => ReplyMode::On,
+                "off" => ReplyMode::Off,
+                // Like redis, a SKIP while replies are off changes nothing
+                "skip" if client.reply_mode == ReplyMode::Off => ReplyMode::Off,
+                "skip" => ReplyMode::Skip,
+                _ => return Err(CommandError::SyntaxError),
+            };
+
+            // Only ON gets to see this, OFF and SKIP are never answered
+            Message::simple_string_from_str("OK")
+        }
+        _ => {
+            return Err(CommandError::UnknownSubcommand {
+                cmd: "CLIENT".to_string(),
+                subcommand,
+            })
+        }
+    };
+
+    Ok(reply)
+}
+
+async fn execute_memory(
+    subcommand: String,
+    args: &[String],
+    ctx: &ExecutionContext,
+) -> Result<Message, CommandError> {
+    let reply = match subcommand.to_lowercase().as_str() {
+        "usage" => {
+            let (key, samples) = match args {
+                [key] => (key, MEMORY_DEFAULT_SAMPLES),
+                [key, option, samples] if option.eq_ignore_ascii_case("samples") => {
+                    let samples = samples
+                        .parse::<usize>()
+                        .map_err(|_| CommandError::NotAnInteger)?;
+
+                    (key, samples)
+                }
+                [] => {
+                    return Err(CommandError::WrongArity {
+                        cmd: "memory|usage".to_string(),
+                    })
+                }
+                _ => return Err(CommandError::SyntaxError),
+            };
+
+            match ctx.store.read().await.memory_usage(key, samples) {
+                Some(bytes) => Message::Integer(bytes as isize),
+                None => Message::Null,
+            }
+        }
+        "doctor" => Message::bulk_string(
+            "Hi Sam, I can't find any memory issue in your instance. \
+             I can only account for what occurs on this base."
+                .to_string(),
+        ),
+        "stats" => {
+            let (keys, bytes) = {
+                let store = ctx.store.read().await;
+                (store.len(), store.dataset_memory_usage())
+            };
+
+            Message::Array(vec![
+                Message::bulk_string("keys.count".to_string()),
+                Message::Integer(keys as isize),
+                Message::bulk_string("dataset.bytes".to_string()),
+                Message::Integer(bytes as isize),
+                Message::bulk_string("keys.bytes-per-key".to_string()),
+                Message::Integer(bytes.checked_div(keys).unwrap_or_default() as isize),
+            ])
+        }
+        _ => {
+            return Err(CommandError::UnknownSubcommand {
+                cmd: "MEMORY".to_string(),
+                subcommand,
+            })
+        }
+    };
+
+    Ok(reply)
+}
+
+async fn execute_debug(
+    subcommand: String,
+    args: &[String],
+    ctx: &ExecutionContext,
+) -> Result<Message, CommandError> {
+    match subcommand.to_lowercase().as_str() {
+        // There are no lists whose nodes it could affect, test suites only expect it to succeed
+        "quicklist-packed-threshold" => {
+            let [threshold] = args else {
+                return Err(CommandError::SyntaxError);
+            };
+
+            threshold
+                .parse::<u64>()
+                .map_err(|_| CommandError::NotAnInteger)?;
+
+            Ok(Message::simple_string_from_str("OK"))
+        }
+        // Nothing is saved to disk, the dataset makes the round trip through the rdb format in memory
+        "reload" => {
+            if !args.is_empty() {
+                return Err(CommandError::SyntaxError);
+            }
+
+            let mut store = ctx.store.write().await;
+            let data = store.export();
+            store.flush();
+            store.import(&data);
+
+            Ok(Message::simple_string_from_str("OK"))
+        }
+        // The hook the redis test suite checks its glob matcher with
+        "stringmatch-len" => {
+            let [pattern, string] = args else {
+                return Err(CommandError::SyntaxError);
+            };
+
+            Ok(Message::Integer(glob_match(pattern, string) as isize))
+        }
+        "set-active-expire" => {
+            let [enabled] = args else {
+                return Err(CommandError::SyntaxError);
+            };
+
+            let enabled = match enabled.as_str() {
+                "0" => false,
+                "1" => true,
+                _ => return Err(CommandError::NotAnInteger),
+            };
+
+            ctx.information
+                .active_expire_enabled
+                .store(enabled, Ordering::Relaxed);
+
+            Ok(Message::simple_string_from_str("OK"))
+        }
+        // Redis is single threaded and stalls every client while it sleeps. Holding the store
+        // for the duration does the same to any command touching the keyspace
+        "sleep" => {
+            let [seconds] = args else {
+                return Err(CommandError::SyntaxError);
+            };
+
+            // Like the strtod redis uses, anything that isn't a positive number doesn't sleep
+            let duration = seconds
+                .parse::<f64>()
+                .ok()
+                .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                .unwrap_or_default();
+
+            // The sleep owns the guard, the whole server stalls with it like a blocked redis would
+            let _store = ctx.store.clone().write_owned().await;
+            sleep(duration).await;
+
+            Ok(Message::simple_string_from_str("OK"))
+        }
+        _ => Err(CommandError::UnknownSubcommand {
+            cmd: "DEBUG".to_string(),
+            subcommand,
+        }),
+    }
+}
+
+// There is no cluster mode, this only answers the probes clients send when connecting
+fn execute_cluster(
+    subcommand: String,
+    args: &[String],
+    ctx: &ExecutionContext,
+) -> Result<Message, CommandError> {
+    let reply = match subcommand.to_lowercase().as_str() {
+        "info" => Message::bulk_string(build_cluster_response()),
+        "myid" => Message::bulk_string(ctx.information.node_id.clone()),
+        "slots" | "shards" => Message::Array(vec![]),
+        "keyslot" => {
+            let [key] = args else {
+                return Err(CommandError::WrongArity {
+                    cmd: "cluster|keyslot".to_string(),
+                });
+            };
+
+            Message::Integer(key_hash_slot(key.as_bytes()) as isize)
+        }
+        _ => {
+            return Err(CommandError::UnknownSubcommand {
+                cmd: "CLUSTER".to_string(),
+                subcommand,
+            })
+        }
+    };
+
+    Ok(reply)
+}
+
+async fn execute_info(section: &str, ctx: &ExecutionContext) -> Message {
+    match section.to_ascii_lowercase().as_str() {
+        "server" => Message::bulk_string(build_server_response(&ctx.information)),
+        "replication" => Message::bulk_string(build_replication_response(&ctx.information).await),
+        "stats" => Message::bulk_string(build_stats_response(&ctx.information)),
+        "" => {
+            let sections = [
+                build_server_response(&ctx.information),
+                build_replication_response(&ctx.information).await,
+                build_stats_response(&ctx.information),
+            ];
+
+            Message::bulk_string(sections.join("\n"))
+        }
+        _ => Message::bulk_string(String::new()),
+    }
+}
+
+async fn execute_keys(pattern: &str, ctx: &ExecutionContext) -> Message {
+    let mut keys = Vec::new();
+    let mut cursor = 0;
+
+    // Walk the keyspace in batches, so writers get the lock in between. Yielding lets them run even
+    // when they share the thread with us.
+    loop {
+        let (next_cursor, batch) = ctx.store.read().await.scan(cursor, KEYS_BATCH_SIZE);
+
+        keys.extend(
+            batch
+                .into_iter()
+                .filter(|key| pattern == "*" || glob_match(pattern, key))
+                .map(Message::BulkString),
+        );
+
+        if next_cursor == 0 {
+            break;
+        }
+
+        cursor = next_cursor;
+        task::yield_now().await;
+    }
+
+    Message::Array(keys)
+}
+
+async fn execute_xadd(params: XADDParams, ctx: &ExecutionContext) -> Result<Message, CommandError> {
+    let mut store = ctx.store.write().await;
+
+    let id = store
+        .auto_generate_stream_id(&params.key, &params.id)
+        .ok_or(CommandError::InvalidStreamId)?;
+
+    store.validate_stream_id(&params.key, &id)?;
+
+    // With the generated id in place of `*`, so the replicas end up with the same one
+    let mut propagated = vec![
+        Message::bulk_string("XADD".to_string()),
+        Message::bulk_string(params.key.clone()),
+        Message::bulk_string(id.clone()),
+    ];
+    propagated.extend(
+        params
+            .values
+            .flatten()
+            .into_iter()
+            .map(Message::bulk_string),
+    );
+
+    _ = store.append_stream_value(&params.key, &id, params.values);
+    propagate_to_replicas(&ctx.information, &Message::Array(propagated)).await;
+
+    Ok(Message::bulk_string(id))
+}
+
+async fn execute_xrange(
+    params: XRANGEParams,
+    ctx: &ExecutionContext,
+) -> Result<Message, CommandError> {
+    let store = ctx.store.read().await;
+
+    let start = get_start_of_xrange_id(&params.start)?;
+    let end = get_end_of_xrange_id(&params.end, &params.key, &store)?;
+
+    match store.get_value(&params.key) {
+        Some(StoreItem::Stream(_)) => {}
+        Some(_) => return Err(CommandError::WrongType),
+        None => return Ok(Message::Array(Vec::new())),
+    }
+
+    match store.get_stream_range(&params.key, start.as_ref(), end.as_ref()) {
+        Some(stream) => Ok(stream_to_message(&stream)),
+        None => Ok(Message::Array(Vec::new())),
+    }
+}
+
+async fn execute_xinfo(
+    subcommand: String,
+    args: &[String],
+    ctx: &ExecutionContext,
+) -> Result<Message, CommandError> {
+    match subcommand.to_lowercase().as_str() {
+        "stream" => {
+            let key = match args {
+                [key] => key,
+                [] => {
+                    return Err(CommandError::WrongArity {
+                        cmd: "xinfo|stream".to_string(),
+                    })
+                }
+                // FULL needs consumer groups to be worth anything
+                _ => return Err(CommandError::SyntaxError),
+            };
+
+            let store = ctx.store.read().await;
+            let stream = get_existing_stream(&store, key)?;
+
+            let zero = StreamId { ms: 0, seq: 0 };
+            let first = stream.entries.first();
+            let last = stream.entries.last();
+            let entry = |entry: Option<&(StreamId, StreamData)>| {
+                entry.map_or(Message::Null, |(id, data)| {
+                    stream_entry_to_message(id, data)
+                })
+            };
+            let field = |name: &str| Message::bulk_string(name.to_string());
+
+            // Entries are never deleted, so the length covers everything ever added
+            Ok(Message::Array(vec![
+                field("length"),
+                Message::Integer(stream.entries.len() as isize),
+                field("radix-tree-keys"),
+                Message::Integer(stream.radix_tree_keys() as isize),
+                field("radix-tree-nodes"),
+                Message::Integer(stream.radix_tree_nodes() as isize),
+                field("last-generated-id"),
+                field(&last.map_or(&zero, |(id, _)| id).to_string()),
+                field("max-deleted-entry-id"),
+                field(&zero.to_string()),
+                field("entries-added"),
+                Message::Integer(stream.entries.len() as isize),
+                field("recorded-first-entry-id"),
+                field(&first.map_or(&zero, |(id, _)| id).to_string()),
+                field("groups"),
+                Message::Integer(0),
+                field("first-entry"),
+                entry(first),
+                field("last-entry"),
+                entry(last),
+            ]))
+        }
+        // There are no consumer groups, so no stream has any
+        "groups" => {
+            let [key] = args else {
+                return Err(CommandError::WrongArity {
+                    cmd: "xinfo|groups".to_string(),
+                });
+            };
+
+            get_existing_stream(&*ctx.store.read().await, key)?;
+
+            Ok(Message::Array(Vec::new()))
+        }
+        "consumers" => {
+            let [key, group] = args else {
+                return Err(CommandError::WrongArity {
+                    cmd: "xinfo|consumers".to_string(),
+                });
+            };
+
+            get_existing_stream(&*ctx.store.read().await, key)?;
+
+            Err(CommandError::NoGroup {
+                key: key.clone(),
+                group: group.clone(),
+            })
+        }
+        _ => Err(CommandError::UnknownSubcommand {
+            cmd: "XINFO".to_string(),
+            subcommand,
+        }),
+    }
+}
+
+fn get_existing_stream<'a>(store: &'a Store, key: &String) -> Result<&'a Stream, CommandError> {
+    match store.get_value(key) {
+        Some(StoreItem::Stream(stream)) => Ok(stream),
+        Some(_) => Err(CommandError::WrongType),
+        None => Err(CommandError::NoSuchKey),
+    }
+}
+
+async fn execute_xread(
+    params: XREADParams,
+    ctx: &ExecutionContext,
+) -> Result<Message, CommandError> {
+    let mut requests: Vec<(String, StreamId)> = Vec::new();
+
+    for (key, id) in &params.requests {
+        let store = ctx.store.read().await;
+
+        if let Err(CommandError::WrongType) = get_existing_stream(&store, key) {
+            return Err(CommandError::WrongType);
+        }
+
+        let stream_id = if id == "$" {
+            match store.get_lastest_stream_id(key) {
+                Some(val) => val.clone(),
+                None => StreamId { ms: 0, seq: 0 },
+            }
+        } else {
+            id.parse()?
+        };
+
+        requests.push((key.clone(), stream_id));
+    }
+
+    let notifiers: Vec<_> = match params.block {
+        Some(_) => {
+            let mut store = ctx.store.write().await;
+            requests
+                .iter()
+                .map(|(key, _)| store.stream_notifier(key))
+                .collect()
+        }
+        None => Vec::new(),
+    };
+
+    loop {
+        // Registered before looking, so an entry added in between still wakes us up
+        let mut notified: Vec<_> = notifiers
+            .iter()
+            .map(|notify| Box::pin(notify.notified()))
+            .collect();
+        for notified in &mut notified {
+            notified.as_mut().enable();
+        }
+
+        let messages = read_streams(&requests, ctx).await;
+
+        if !messages.is_empty() {
+            return Ok(Message::Array(messages));
+        }
+
+        let Some(deadline) = params.block else {
+            return Ok(Message::NullArray);
+        };
+
+        // BLOCK 0 waits for as long as it takes
+        if params.wait {
+            any_notified(&mut notified).await;
+        } else {
+            let remaining = deadline
+                .duration_since(SystemTime::now())
+                .unwrap_or_default();
+
+            if timeout(remaining, any_notified(&mut notified))
+                .await
+                .is_err()
+            {
+                return Ok(Message::NullArray);
+            }
+        }
+    }
+}
+
+// The entries after the requested id, for the streams that have any
+async fn read_streams(requests: &[(String, StreamId)], ctx: &ExecutionContext) -> Vec<Message> {
+    let store = ctx.store.read().await;
+
+    requests
+        .iter()
+        .filter_map(|(key, id)| {
+            let stream = store.get_stream_read(key, id)?;
+
+            Some(Message::Array(vec![
+                Message::BulkString(key.clone()),
+                stream_to_message(&stream),
+            ]))
+        })
+        .collect()
+}
+
+// Resolves as soon as any of the streams gets a new entry
+async fn any_notified(notified: &mut [Pin<Box<Notified<'_>>>]) {
+    poll_fn(|cx| {
+        if notified
+            .iter_mut()
+            .any(|notified| notified.as_mut().poll(cx).is_ready())
+        {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::{parse_client_command, CommandLineArgs};
+
+    // A server of its own for every test, without any listener or connection
+    fn context() -> ExecutionContext {
+        let args = CommandLineArgs::parse_from(["redis"]);
+        let information = Arc::new(ServerInformation::new(&args, 0));
+        let client = information.clients.register("127.0.0.1:1");
+
+        ExecutionContext::new(Arc::new(RwLock::new(Store::new())), information, client)
+    }
+
+    async fn run(ctx: &mut ExecutionContext, args: &[&str]) -> Message {
+        let message = Message::Array(
+            args.iter()
+                .map(|arg| Message::BulkString(arg.to_string()))
+                .collect(),
+        );
+
+        match parse_client_command(&message) {
+            Ok(command) => execute(command, &message, ctx).await,
+            Err(err) => err.to_message(),
+        }
+    }
+
+    #[tokio::test]
+    async fn strings_are_set_and_read_back() {
+        let mut ctx = context();
+
+        assert_eq!(
+            run(&mut ctx, &["SET", "key", "value"]).await,
+            Message::simple_string_from_str("OK")
+        );
+        assert_eq!(
+            run(&mut ctx, &["GET", "key"]).await,
+            Message::BulkString("value".to_string())
+        );
+        assert_eq!(run(&mut ctx, &["GET", "missing"]).await, Message::Null);
+        assert_eq!(
+            run(&mut ctx, &["TYPE", "key"]).await,
+            Message::simple_string_from_str("string")
+        );
+        assert_eq!(
+            run(&mut ctx, &["TYPE", "missing"]).await,
+            Message::simple_string_from_str("none")
+        );
+    }
+
+    #[tokio::test]
+    async fn streams_are_added_to_and_typed() {
+        let mut ctx = context();
+
+        assert_eq!(
+            run(&mut ctx, &["XADD", "stream", "1-1", "field", "value"]).await,
+            Message::BulkString("1-1".to_string())
+        );
+        assert_eq!(
+            run(&mut ctx, &["TYPE", "stream"]).await,
+            Message::simple_string_from_str("stream")
+        );
+        assert_eq!(
+            run(&mut ctx, &["XADD", "stream", "1-1", "field", "value"]).await,
+            CommandError::StreamIdTooSmall.to_message()
+        );
+        assert_eq!(
+            run(&mut ctx, &["GET", "stream"]).await,
+            CommandError::WrongType.to_message()
+        );
+        assert_eq!(ctx.store.read().await.len(), 1);
+    }
+}
@@ -1,14 +1,68 @@
+use std::{process, sync::atomic::Ordering};
+
 use crate::configuration::ServerInformation;
 
+pub fn build_server_response(info: &ServerInformation) -> String {
+    format!("# Server\n\
+        redis_mode:standalone\n\
+        process_id:{}\n\
+        tcp_port:{}\n",
+        process::id(),
+        info.port
+    )
+}
+
 pub async fn build_replication_response(info: &ServerInformation) -> String {
-    format!("# Replication\n\
+    let replicas = info.replication_handles.lock().await;
+
+    let mut response = format!("# Replication\n\
         role:{}\n\
-        connected_clients:{}\n\
-        master_replid:{}\n\
-        master_repl_offset:{}\n",
+        connected_slaves:{}\n",
         info.role,
-        info.replication_handles.lock().await.len(),
+        replicas.len()
+    );
+
+    // The lag is the number of seconds since the replica last acked
+    for (index, replica) in replicas.iter().enumerate() {
+        response += &format!("slave{}:ip={},port={},state=online,offset={},lag={}\n",
+            index,
+            replica.address.ip,
+            replica.address.port,
+            replica.acked_offset.load(Ordering::Relaxed),
+            replica.last_ack.lock().unwrap().elapsed().as_secs()
+        );
+    }
+
+    response + &format!("master_replid:{}\n\
+        master_repl_offset:{}\n",
         info.repl_id,
-        info.repl_offset
+        info.repl_offset.load(Ordering::Relaxed)
+    )
+}
+
+pub fn build_stats_response(info: &ServerInformation) -> String {
+    format!("# Stats\n\
+        keyspace_hits:{}\n\
+        keyspace_misses:{}\n\
+        expired_keys:{}\n\
+        evicted_keys:{}\n\
+        total_reads_processed:{}\n\
+        total_writes_processed:{}\n",
+        info.stats.keyspace_hits.load(Ordering::Relaxed),
+        info.stats.keyspace_misses.load(Ordering::Relaxed),
+        info.stats.expired_keys.load(Ordering::Relaxed),
+        info.stats.evicted_keys.load(Ordering::Relaxed),
+        info.stats.total_reads_processed.load(Ordering::Relaxed),
+        info.stats.total_writes_processed.load(Ordering::Relaxed)
+    )
+}
+
+// What CLUSTER INFO reports on a server that isn't part of a cluster
+pub fn build_cluster_response() -> String {
+    String::from("cluster_enabled:0\r\n\
+        cluster_state:ok\r\n\
+        cluster_slots_assigned:0\r\n\
+        cluster_known_nodes:1\r\n\
+        cluster_size:0\r\n"
     )
 }
@@ -0,0 +1,670 @@
+use std::{
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    process,
+    sync::Arc,
+    time::{Instant, SystemTime},
+    vec,
+};
+
+#[macro_use]
+mod logging;
+
+mod client;
+mod commands;
+mod communication;
+mod configuration;
+mod errors;
+mod executor;
+mod info;
+mod listpack;
+mod messages;
+mod replication;
+mod store;
+mod util;
+
+pub use commands::{all_command_names, parse_client_command};
+pub use configuration::ServerInformation;
+pub use errors::CommandError;
+pub use logging::LogLevel;
+pub use messages::Message;
+pub use store::{Entry, ExpireCondition, Store, StreamData, StringValue};
+
+use anyhow::{bail, Result};
+use clap::{builder::BoolishValueParser, ArgAction, Parser};
+use client::ReplyMode;
+use commands::{command_spec, CommandSpec};
+use communication::{Connection, MessageStream, ReplicaStream};
+use configuration::read_config_file;
+use executor::{execute, ExecutionContext};
+use replication::replication_channel;
+use store::{active_expire, read_rdb_from_file};
+use tokio::{
+    net::{TcpListener, UnixListener},
+    sync::{Notify, RwLock},
+    task::JoinSet,
+    time::sleep,
+};
+
+use crate::replication::{
+    handle_handshake_with_master, needs_to_replicate, MasterLink, PsyncReply,
+    MASTER_RECONNECT_DELAY,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SetCondition {
+    NotExists,
+    Exists,
+}
+
+#[derive(Debug)]
+pub struct SETParams {
+    pub key: String,
+    pub entry: Entry,
+    pub condition: Option<SetCondition>,
+    pub keep_ttl: bool,
+    pub get: bool,
+}
+
+// What GETEX does to the expiry of the key it returns
+#[derive(Debug)]
+pub enum GetExExpiry {
+    // A unix time in milliseconds, set when the conditions allow it like with EXPIRE
+    At(i64, Vec<ExpireCondition>),
+    Persist,
+}
+
+#[derive(Debug)]
+pub struct XADDParams {
+    pub key: String,
+    pub id: String,
+    pub values: StreamData,
+}
+
+#[derive(Debug)]
+pub struct SCANParams {
+    pub cursor: u64,
+    pub pattern: Option<String>,
+    pub count: usize,
+    pub value_type: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct XRANGEParams {
+    pub key: String,
+    pub start: String,
+    pub end: String,
+}
+
+#[derive(Debug)]
+pub struct XREADParams {
+    pub block: Option<SystemTime>,
+    pub wait: bool,
+    pub requests: Vec<(String, String)>,
+}
+
+#[derive(Debug)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Command {
+    Echo(String),
+    Ping,
+    Quit,
+    Set(SETParams),
+    Get(String),
+    GetEx(String, Option<GetExExpiry>),
+    GetRange(String, i64, i64),
+    SetRange(String, i64, Vec<u8>),
+    Append(String, Vec<u8>),
+    SetBit(String, u64, bool),
+    GetBit(String, u64),
+    Dump(String),
+    Restore(String, u64, Vec<u8>, bool),
+    Copy(String, String, bool),
+    // The expiry as a unix time in milliseconds
+    Expire(String, i64, Vec<ExpireCondition>),
+    Persist(String),
+    Del(Vec<String>),
+    Exists(Vec<String>),
+    RandomKey,
+    // Whether the old keys are freed in the background
+    FlushAll(bool),
+    DbSize,
+    IncrBy(String, i64),
+    Info(String),
+    Replconf(Vec<String>),
+    Psync(Vec<String>),
+    Wait(usize, u64),
+    Config(String, Vec<String>),
+    Cluster(String, Vec<String>),
+    Debug(String, Vec<String>),
+    Client(String, Vec<String>),
+    Memory(String, Vec<String>),
+    Command(String, Vec<String>),
+    Keys(String),
+    Object(String, String),
+    Scan(SCANParams),
+    Type(String),
+    XADD(XADDParams),
+    XRANGE(XRANGEParams),
+    XREAD(XREADParams),
+    XInfo(String, Vec<String>),
+}
+
+#[derive(Parser, Debug, Clone)]
+#[clap(about, long_about = None, args_override_self = true)]
+pub struct CommandLineArgs {
+    // Like redis-server, a config file may be given. Its directives are read before the options on
+    // the command line, which win when both set the same thing.
+    config_file: Option<PathBuf>,
+
+    // Space separated, like the bind directive of redis
+    #[arg(default_value = "127.0.0.1", value_delimiter = ' ', num_args = 1..)]
+    #[clap(short, long, alias = "bind")]
+    address: Vec<IpAddr>,
+
+    // 0 binds an ephemeral port, see `Server::local_addrs` for the one that was picked
+    #[arg(default_value = "6379")]
+    #[clap(short, long)]
+    port: u16,
+
+    #[arg(value_delimiter = ' ', num_args = 2)]
+    #[clap(long)]
+    replicaof: Option<Vec<String>>,
+
+    #[clap(long)]
+    dir: Option<String>,
+
+    #[clap(long)]
+    dbfilename: Option<String>,
+
+    #[clap(long)]
+    unixsocket: Option<PathBuf>,
+
+    #[arg(value_enum, default_value = "info")]
+    #[clap(long)]
+    loglevel: LogLevel,
+
+    #[arg(default_value = "no", value_parser = BoolishValueParser::new(), action = ArgAction::Set)]
+    #[clap(long)]
+    daemonize: bool,
+
+    #[clap(long)]
+    pidfile: Option<PathBuf>,
+
+    // Disables Nagle on accepted tcp connections, so small replies aren't held back
+    #[arg(default_value = "yes", value_parser = BoolishValueParser::new(), action = ArgAction::Set)]
+    #[clap(long)]
+    tcp_nodelay: bool,
+
+    // Seconds like in redis, but only 0 (off) is supported
+    #[arg(default_value = "0")]
+    #[clap(long)]
+    tcp_keepalive: u64,
+
+    // Repeatable, a command and its new name, "" disables it
+    #[arg(num_args = 2, action = ArgAction::Append, value_names = ["COMMAND", "NEWNAME"])]
+    #[clap(long)]
+    rename_command: Vec<String>,
+}
+
+impl CommandLineArgs {
+    /// Parses `argv` like `Parser::parse_from`, along with the config file it names.
+    pub fn load(argv: Vec<String>) -> Result<Self> {
+        let args = Self::parse_from(&argv);
+
+        let Some(path) = &args.config_file else {
+            return Ok(args);
+        };
+
+        let mut merged = argv[..1].to_vec();
+        merged.extend(read_config_file(path)?);
+        merged.extend_from_slice(&argv[1..]);
+
+        Ok(Self::parse_from(merged))
+    }
+}
+
+// Stays attached to the master for as long as the server runs, reconnecting whenever the link is lost
+async fn replicate(store: Arc<RwLock<Store>>, information: Arc<ServerInformation>) {
+    // Kept across reconnects, so the master can continue where it left off
+    let mut link: Option<MasterLink> = None;
+
+    loop {
+        match handle_handshake_with_master(information.clone(), link.as_ref()).await {
+            Ok((mut replica_stream, reply)) => {
+                link = resync(&mut replica_stream, reply, link.take(), &store).await;
+
+                if let Some(link) = &mut link {
+                    link.offset = handle_master(
+                        replica_stream,
+                        link.offset,
+                        store.clone(),
+                        information.clone(),
+                    )
+                    .await;
+                }
+            }
+            Err(err) => {
+                error!("failed the handshake with the master"; error = err);
+            }
+        }
+
+        sleep(MASTER_RECONNECT_DELAY).await;
+    }
+}
+
+// Gets the replica to where the master continues from, None when the connection was lost before
+async fn resync(
+    replica_stream: &mut ReplicaStream,
+    reply: PsyncReply,
+    link: Option<MasterLink>,
+    store: &RwLock<Store>,
+) -> Option<MasterLink> {
+    match reply {
+        PsyncReply::FullResync(link) => {
+            let rdb = replica_stream.get_rdb().await?;
+            info!("full resync received from the master"; bytes = rdb.len());
+
+            let mut store = store.write().await;
+            store.flush();
+            store.import(&rdb);
+
+            Some(link)
+        }
+        // Nothing to load, the commands pick up right after our offset
+        PsyncReply::Continue(replid) => {
+            info!("partial resync accepted by the master");
+
+            // A CONTINUE is only accepted after offering a link
+            let mut link = link?;
+            if let Some(replid) = replid {
+                link.replid = replid;
+            }
+
+            Some(link)
+        }
+    }
+}
+
+// Applies what the master sends from `offset` on, until the connection is lost. Returns the offset
+// reached by then.
+async fn handle_master(
+    mut message_stream: ReplicaStream,
+    offset: usize,
+    store: Arc<RwLock<Store>>,
+    information: Arc<ServerInformation>,
+) -> usize {
+    let mut bytes_received = offset;
+    let client = information.clients.register(&information.role.to_string());
+    let mut ctx = ExecutionContext::new(store, information, client);
+
+    loop {
+        if let Some(message) = message_stream.get_response().await {
+            let command = if let Ok(command) = parse_client_command(&message) {
+                command
+            } else {
+                continue;
+            };
+
+            match command {
+                Command::Replconf(args) => match args.first() {
+                    Some(option) if option.eq_ignore_ascii_case("getack") => {
+                        let message = Message::Array(vec![
+                            Message::BulkString("REPLCONF".to_string()),
+                            Message::BulkString("ACK".to_string()),
+                            Message::BulkString(bytes_received.to_string()),
+                        ]);
+
+                        _ = message_stream.write(message).await;
+                    }
+                    // A misbehaving master shouldn't take the replica down
+                    option => {
+                        let option = option.map_or("", String::as_str);
+                        warn!("ignored a replconf from the master"; option = option);
+                    }
+                },
+                command => {
+                    // The master doesn't expect replies to anything it propagates
+                    _ = execute(command, &message, &mut ctx).await;
+                }
+            }
+
+            // Not the quickest way of doing things, but very easy and accurate
+            bytes_received += message.encoded_len();
+        } else {
+            info!("lost the connection with the master");
+            break;
+        }
+    }
+
+    bytes_received
+}
+
+async fn handle_client(
+    mut message_stream: MessageStream,
+    peer: &str,
+    store: Arc<RwLock<Store>>,
+    information: Arc<ServerInformation>,
+) {
+    let client = information.clients.register(peer);
+    let mut ctx = ExecutionContext::new(store, information, client);
+
+    loop {
+        ctx.information
+            .stats
+            .record_io(message_stream.take_io_counts());
+
+        let pending_replica = ctx.client().pending_replica.take();
+
+        if let Some(replica) = pending_replica {
+            info!("replica attached"; addr = peer);
+
+            _ = replication_channel(message_stream, replica, ctx.information.clone()).await;
+            return;
+        }
+
+        let message = match message_stream.read_message().await {
+            Ok(Some(message)) => message,
+            Ok(None) => break,
+            Err(err) => {
+                // There is no way to resynchronize a corrupted stream, so we bail on the client
+                _ = send_error_string(&mut message_stream, format!("ERR {}", err)).await;
+                _ = message_stream.shutdown().await;
+                break;
+            }
+        };
+
+        // A renamed command runs as the original one, which is also what the replicas get
+        let (message, renamed_away) = match ctx.information.command_renames.resolve(&message) {
+            Ok(Some(original)) => (original, None),
+            Ok(None) => (message, None),
+            Err(err) => (message, Some(err)),
+        };
+
+        let started = Instant::now();
+
+        let skipped = {
+            let mut client = ctx.client();
+            client.last_interaction = started;
+            client.last_command = command_name(&message).to_lowercase();
+
+            // The reply mode is consumed before running, so a SKIP only drops the reply after it
+            let skipped = client.reply_mode == ReplyMode::Skip;
+            if skipped {
+                client.reply_mode = ReplyMode::On;
+            }
+
+            skipped
+        };
+
+        // The replies to the commands before it shouldn't wait for a command that blocks
+        if command_spec(command_name(&message)).is_some_and(CommandSpec::may_block)
+            && message_stream.flush().await.is_err()
+        {
+            break;
+        }
+
+        let parsed = match renamed_away {
+            Some(err) => Err(err),
+            None => parse_client_command(&message),
+        };
+
+        let reply = match parsed {
+            Ok(command) => execute(command, &message, &mut ctx).await,
+            Err(err) => err.to_message(),
+        };
+
+        debug!("command executed";
+            addr = peer,
+            command = command_name(&message),
+            duration_us = started.elapsed().as_micros()
+        );
+
+        let (reply_mode, close_connection) = {
+            let client = ctx.client();
+            (client.reply_mode, client.close_connection)
+        };
+
+        if !skipped && reply_mode == ReplyMode::On {
+            message_stream.queue(&reply);
+        }
+
+        // The replies to a pipelined batch go out together, once every command read is answered
+        if message_stream.read_cache.is_empty() && message_stream.flush().await.is_err() {
+            break;
+        }
+
+        if close_connection {
+            // Anything pipelined after the command is dropped unanswered
+            _ = message_stream.shutdown().await;
+            break;
+        }
+    }
+
+    ctx.information
+        .stats
+        .record_io(message_stream.take_io_counts());
+}
+
+fn command_name(message: &Message) -> &str {
+    match message {
+        Message::Array(items) => match items.first() {
+            Some(Message::BulkString(name)) => name,
+            _ => "",
+        },
+        _ => "",
+    }
+}
+
+async fn send_error_string(message_stream: &mut MessageStream, error: String) -> Result<()> {
+    message_stream.write(Message::Error(error)).await
+}
+
+/// A redis server bound to its listeners, see `run` and `shutdown`.
+pub struct Server {
+    store: Arc<RwLock<Store>>,
+    information: Arc<ServerInformation>,
+    tcp_listeners: Vec<Arc<TcpListener>>,
+    unix_listener: Option<Arc<UnixListener>>,
+    unixsocket: Option<PathBuf>,
+    pidfile: Option<PathBuf>,
+    tcp_nodelay: bool,
+    shutdown: Notify,
+}
+
+impl Server {
+    /// Loads the rdb file and binds the listeners, no clients are served until `run` is called.
+    pub async fn bind(config: CommandLineArgs) -> Result<Self> {
+        logging::set_level(config.loglevel);
+
+        if config.tcp_keepalive > 0 {
+            // Neither std nor tokio can turn on keepalive for a socket, so it would silently stay off
+            bail!(
+                "tcp-keepalive {} is not supported, only 0 (off) is",
+                config.tcp_keepalive
+            );
+        }
+
+        let tcp_listeners = bind_tcp_listeners(&config.address, config.port).await?;
+        let port = match tcp_listeners.first() {
+            Some(listener) => listener.local_addr()?.port(),
+            None => config.port,
+        };
+
+        let store = Arc::new(RwLock::new(Store::new()));
+        let information = Arc::new(ServerInformation::new(&config, port));
+
+        {
+            let rdb_content = read_rdb_from_file(&information).await;
+
+            if let Some(data) = rdb_content {
+                store.write().await.import(&data);
+            }
+        }
+
+        let unix_listener = if let Some(path) = &config.unixsocket {
+            // A stale socket file from a previous run would make the bind fail
+            _ = fs::remove_file(path);
+            Some(Arc::new(UnixListener::bind(path)?))
+        } else {
+            None
+        };
+
+        if config.daemonize {
+            // Forking isn't portable, a service manager has to put us in the background
+            warn!("daemonize is accepted, but the server keeps running in the foreground");
+        }
+
+        if let Some(path) = &config.pidfile {
+            // Like redis, failing to write the pidfile isn't a reason to stop
+            if let Err(err) = fs::write(path, format!("{}\n", process::id())) {
+                warn!("unable to write the pidfile"; path = path.display(), error = err);
+            }
+        }
+
+        Ok(Self {
+            store,
+            information,
+            tcp_listeners,
+            unix_listener,
+            unixsocket: config.unixsocket,
+            pidfile: config.pidfile,
+            tcp_nodelay: config.tcp_nodelay,
+            shutdown: Notify::new(),
+        })
+    }
+
+    /// The addresses of the tcp listeners, one for every bind address.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.tcp_listeners
+            .iter()
+            .filter_map(|listener| listener.local_addr().ok())
+            .collect()
+    }
+
+    pub fn store(&self) -> &Arc<RwLock<Store>> {
+        &self.store
+    }
+
+    pub fn information(&self) -> &Arc<ServerInformation> {
+        &self.information
+    }
+
+    /// Serves clients until `shutdown` is called, the tasks owned by the server are stopped before returning.
+    pub async fn run(&self) -> Result<()> {
+        let mut background = JoinSet::new();
+        background.spawn(active_expire(self.store.clone(), self.information.clone()));
+
+        if needs_to_replicate(&self.information).await {
+            background.spawn(replicate(self.store.clone(), self.information.clone()));
+        }
+
+        // Every listener gets its own accept loop, they all hand their clients to the same handler
+        let mut listeners = JoinSet::new();
+
+        for listener in &self.tcp_listeners {
+            listeners.spawn(accept_tcp_clients(
+                listener.clone(),
+                self.tcp_nodelay,
+                self.store.clone(),
+                self.information.clone(),
+            ));
+        }
+        if let Some(listener) = &self.unix_listener {
+            listeners.spawn(accept_unix_clients(
+                listener.clone(),
+                self.store.clone(),
+                self.information.clone(),
+            ));
+        }
+
+        let result = tokio::select! {
+            Some(result) = listeners.join_next() => result.unwrap_or_else(|err| Err(err.into())),
+            _ = self.shutdown.notified() => Ok(()),
+        };
+
+        listeners.abort_all();
+        background.abort_all();
+
+        if let Some(path) = &self.unixsocket {
+            _ = fs::remove_file(path);
+        }
+        if let Some(path) = &self.pidfile {
+            _ = fs::remove_file(path);
+        }
+
+        result
+    }
+
+    /// Makes `run` return, also when it hasn't been called yet.
+    pub fn shutdown(&self) {
+        self.shutdown.notify_one();
+    }
+}
+
+// With port 0 the first listener picks an ephemeral port, and the others bind that same port
+async fn bind_tcp_listeners(addresses: &[IpAddr], port: u16) -> Result<Vec<Arc<TcpListener>>> {
+    let mut listeners = Vec::with_capacity(addresses.len());
+    let mut port = port;
+
+    for address in addresses {
+        let listener = TcpListener::bind(SocketAddr::new(*address, port)).await?;
+        let local_addr = listener.local_addr()?;
+
+        info!("listening"; addr = local_addr);
+
+        port = local_addr.port();
+        listeners.push(Arc::new(listener));
+    }
+
+    Ok(listeners)
+}
+
+async fn accept_tcp_clients(
+    listener: Arc<TcpListener>,
+    nodelay: bool,
+    store: Arc<RwLock<Store>>,
+    information: Arc<ServerInformation>,
+) -> Result<()> {
+    loop {
+        let (socket, addr) = listener.accept().await?;
+
+        if let Err(err) = socket.set_nodelay(nodelay) {
+            warn!("unable to set tcp-nodelay"; addr = addr, error = err);
+        }
+
+        spawn_client(socket, addr.to_string(), store.clone(), information.clone());
+    }
+}
+
+async fn accept_unix_clients(
+    listener: Arc<UnixListener>,
+    store: Arc<RwLock<Store>>,
+    information: Arc<ServerInformation>,
+) -> Result<()> {
+    // Like redis, unix socket clients go by the path of the socket and port 0
+    let local_addr = listener.local_addr()?;
+    let path = local_addr.as_pathname().unwrap_or(Path::new(""));
+    let peer = format!("{}:0", path.display());
+
+    loop {
+        let (socket, _) = listener.accept().await?;
+        spawn_client(socket, peer.clone(), store.clone(), information.clone());
+    }
+}
+
+fn spawn_client(
+    socket: impl Connection + 'static,
+    peer: String,
+    store: Arc<RwLock<Store>>,
+    information: Arc<ServerInformation>,
+) {
+    let message_stream = MessageStream::bind(socket);
+
+    tokio::spawn(async move {
+        info!("client connected"; addr = peer);
+        handle_client(message_stream, &peer, store, information).await;
+        info!("client disconnected"; addr = peer);
+    });
+}
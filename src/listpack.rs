@@ -0,0 +1,188 @@
+// The listpack format redis stores stream nodes in: a header with the total size and the number
+// of elements, the elements each followed by their own length, and a 0xFF terminator
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListpackElement {
+    Int(i64),
+    Bytes(Vec<u8>),
+}
+
+impl ListpackElement {
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Self::Int(value) => Some(*value),
+            Self::Bytes(bytes) => std::str::from_utf8(bytes).ok()?.parse().ok(),
+        }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        match self {
+            Self::Int(value) => value.to_string().into_bytes(),
+            Self::Bytes(bytes) => bytes,
+        }
+    }
+}
+
+const HEADER_SIZE: usize = 6;
+const TERMINATOR: u8 = 0xFF;
+// The element count in the header saturates, past it the elements have to be counted
+const UNKNOWN_COUNT: u16 = u16::MAX;
+
+pub fn encode(elements: &[ListpackElement]) -> Vec<u8> {
+    let mut data = vec![0; HEADER_SIZE];
+
+    for element in elements {
+        let start = data.len();
+
+        match element {
+            ListpackElement::Int(value) => encode_int(&mut data, *value),
+            ListpackElement::Bytes(bytes) => encode_bytes(&mut data, bytes),
+        }
+
+        let length = data.len() - start;
+        encode_backlen(&mut data, length);
+    }
+
+    data.push(TERMINATOR);
+
+    let total = data.len() as u32;
+    let count = u16::try_from(elements.len()).unwrap_or(UNKNOWN_COUNT);
+    data[0..4].copy_from_slice(&total.to_le_bytes());
+    data[4..6].copy_from_slice(&count.to_le_bytes());
+
+    data
+}
+
+fn encode_int(data: &mut Vec<u8>, value: i64) {
+    if (0..=127).contains(&value) {
+        data.push(value as u8);
+    } else if (-4096..=4095).contains(&value) {
+        let value = value as u16 & 0x1FFF;
+        data.extend([0xC0 | (value >> 8) as u8, value as u8]);
+    } else if let Ok(value) = i16::try_from(value) {
+        data.push(0xF1);
+        data.extend(value.to_le_bytes());
+    } else if (-(1 << 23)..1 << 23).contains(&value) {
+        data.push(0xF2);
+        data.extend(&value.to_le_bytes()[..3]);
+    } else if let Ok(value) = i32::try_from(value) {
+        data.push(0xF3);
+        data.extend(value.to_le_bytes());
+    } else {
+        data.push(0xF4);
+        data.extend(value.to_le_bytes());
+    }
+}
+
+fn encode_bytes(data: &mut Vec<u8>, bytes: &[u8]) {
+    let length = bytes.len();
+
+    if length < 1 << 6 {
+        data.push(0x80 | length as u8);
+    } else if length < 1 << 12 {
+        data.extend([0xE0 | (length >> 8) as u8, length as u8]);
+    } else {
+        data.push(0xF0);
+        data.extend((length as u32).to_le_bytes());
+    }
+
+    data.extend(bytes);
+}
+
+// The length of the element, written so it can be read from its end backwards: 7 bits per byte,
+// most significant first, with the high bit set on all but the first byte
+fn encode_backlen(data: &mut Vec<u8>, length: usize) {
+    let size = backlen_size(length);
+
+    for index in (0..size).rev() {
+        let bits = ((length >> (7 * index)) & 0x7F) as u8;
+        data.push(if index == size - 1 { bits } else { bits | 0x80 });
+    }
+}
+
+fn backlen_size(length: usize) -> usize {
+    match length {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    }
+}
+
+// None when the listpack is damaged
+pub fn decode(data: &[u8]) -> Option<Vec<ListpackElement>> {
+    let total = u32::from_le_bytes(data.get(0..4)?.try_into().ok()?) as usize;
+    if total != data.len() || data.last() != Some(&TERMINATOR) {
+        return None;
+    }
+
+    let mut elements = Vec::new();
+    let mut marker = HEADER_SIZE;
+
+    while data[marker] != TERMINATOR {
+        let start = marker;
+        let element = decode_element(data, &mut marker)?;
+
+        marker += backlen_size(marker - start);
+        if marker >= data.len() {
+            return None;
+        }
+
+        elements.push(element);
+    }
+
+    Some(elements)
+}
+
+fn decode_element(data: &[u8], marker: &mut usize) -> Option<ListpackElement> {
+    let first = *data.get(*marker)?;
+    *marker += 1;
+
+    let element = match first {
+        0x00..=0x7F => ListpackElement::Int(first as i64),
+        0x80..=0xBF => {
+            ListpackElement::Bytes(take(data, marker, (first & 0x3F) as usize)?.to_vec())
+        }
+        0xC0..=0xDF => {
+            let value = ((first as i64 & 0x1F) << 8) | *take(data, marker, 1)?.first()? as i64;
+            // Sign extend the 13 bits
+            ListpackElement::Int(if value >= 1 << 12 {
+                value - (1 << 13)
+            } else {
+                value
+            })
+        }
+        0xE0..=0xEF => {
+            let length = ((first as usize & 0x0F) << 8) | *take(data, marker, 1)?.first()? as usize;
+            ListpackElement::Bytes(take(data, marker, length)?.to_vec())
+        }
+        0xF0 => {
+            let length = u32::from_le_bytes(take(data, marker, 4)?.try_into().ok()?) as usize;
+            ListpackElement::Bytes(take(data, marker, length)?.to_vec())
+        }
+        0xF1 => {
+            ListpackElement::Int(i16::from_le_bytes(take(data, marker, 2)?.try_into().ok()?) as i64)
+        }
+        0xF2 => {
+            let bytes = take(data, marker, 3)?;
+            // Shifting into the top of an i32 and back sign extends the 24 bits
+            ListpackElement::Int(
+                (i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) >> 8) as i64,
+            )
+        }
+        0xF3 => {
+            ListpackElement::Int(i32::from_le_bytes(take(data, marker, 4)?.try_into().ok()?) as i64)
+        }
+        0xF4 => ListpackElement::Int(i64::from_le_bytes(take(data, marker, 8)?.try_into().ok()?)),
+        _ => return None,
+    };
+
+    Some(element)
+}
+
+fn take<'a>(data: &'a [u8], marker: &mut usize, length: usize) -> Option<&'a [u8]> {
+    let slice = data.get(*marker..*marker + length)?;
+    *marker += length;
+
+    Some(slice)
+}
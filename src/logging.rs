@@ -0,0 +1,98 @@
+use std::{
+    fmt::Arguments,
+    sync::atomic::{AtomicU8, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use clap::ValueEnum;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    // The name used on the command line and by CONFIG GET
+    pub fn name(self) -> &'static str {
+        match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+            LogLevel::Trace => "trace",
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            LogLevel::Error => "ERROR",
+            LogLevel::Warn => "WARN",
+            LogLevel::Info => "INFO",
+            LogLevel::Debug => "DEBUG",
+            LogLevel::Trace => "TRACE",
+        }
+    }
+}
+
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+pub fn set_level(level: LogLevel) {
+    MAX_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: LogLevel) -> bool {
+    level as u8 <= MAX_LEVEL.load(Ordering::Relaxed)
+}
+
+pub fn log(level: LogLevel, args: Arguments) {
+    if !enabled(level) {
+        return;
+    }
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+
+    eprintln!("{} {:<5} {}", timestamp, level.label(), args);
+}
+
+// Fields are appended as `key=value` pairs after the message, e.g.
+// `info!("client connected"; addr = peer)`
+macro_rules! log_event {
+    ($level:expr, $message:literal $(; $($field:ident = $value:expr),+ $(,)?)?) => {
+        if $crate::logging::enabled($level) {
+            $crate::logging::log(
+                $level,
+                format_args!(
+                    concat!($message $($(, " ", stringify!($field), "={}")+)?)
+                    $($(, $value)+)?
+                ),
+            );
+        }
+    };
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => { log_event!($crate::logging::LogLevel::Error, $($arg)*) };
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => { log_event!($crate::logging::LogLevel::Warn, $($arg)*) };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => { log_event!($crate::logging::LogLevel::Info, $($arg)*) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => { log_event!($crate::logging::LogLevel::Debug, $($arg)*) };
+}
+
+macro_rules! trace {
+    ($($arg:tt)*) => { log_event!($crate::logging::LogLevel::Trace, $($arg)*) };
+}
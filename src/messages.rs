@@ -1,43 +1,116 @@
 use anyhow::{anyhow, Result};
-use bytes::BytesMut;
-use std::vec;
+use bytes::{BufMut, BytesMut};
+use std::{fmt::Write, vec};
+use thiserror::Error;
 
-use crate::store::Stream;
+use crate::store::{Stream, StreamData, StreamId};
 
 pub const NULL_BULK_STRING: &str = "$-1\r\n";
+pub const NULL_ARRAY: &str = "*-1\r\n";
+
+// Same limits redis applies to incoming requests, anything above is treated as garbage
+const MAX_ARRAY_LENGTH: usize = 1024 * 1024;
+const MAX_BULK_LENGTH: usize = 512 * 1024 * 1024;
+const MAX_NESTING_DEPTH: usize = 32;
+const MAX_INLINE_LENGTH: usize = 64 * 1024;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ParseError {
+    #[error("incomplete message")]
+    Incomplete,
+    #[error("Protocol error: invalid multibulk length")]
+    InvalidMultibulkLength,
+    #[error("Protocol error: invalid bulk length")]
+    InvalidBulkLength,
+    #[error("Protocol error: expected '$', got '{0}'")]
+    UnexpectedType(char),
+    #[error("Protocol error: bulk string is not terminated by CRLF")]
+    UnterminatedBulkString,
+    #[error("Protocol error: invalid integer")]
+    InvalidInteger,
+    #[error("Protocol error: invalid utf-8 string")]
+    InvalidUtf8,
+    #[error("Protocol error: too deeply nested")]
+    TooDeeplyNested,
+    #[error("Protocol error: too big inline request")]
+    TooBigInlineRequest,
+    #[error("Protocol error: too big mbulk count string")]
+    TooBigMultibulkCount,
+    #[error("Protocol error: too big bulk count string")]
+    TooBigBulkCount,
+}
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Message {
     Error(String),
     SimpleString(String),
     BulkString(String),
+    // A bulk string that isn't valid utf-8, like the payloads of DUMP
+    BulkBytes(Vec<u8>),
     Array(Vec<Message>),
     Integer(isize),
     Null,
+    // What redis replies when there is no array to reply with, like XREAD timing out
+    NullArray,
 }
 
 impl Message {
     pub fn serialize(&self) -> Result<String> {
+        let mut buffer = BytesMut::new();
+        self.write_to(&mut buffer);
+
+        Ok(String::from_utf8(buffer.to_vec())?)
+    }
+
+    // Appends the RESP encoding in a single pass, without any intermediate strings
+    pub fn write_to(&self, buffer: &mut BytesMut) {
         match self {
-            Message::Error(s) => Ok(format!("-{}\r\n", s)),
-            Message::SimpleString(s) => Ok(format!("+{}\r\n", s)),
-            Message::BulkString(s) => Ok(format!("${}\r\n{}\r\n", s.chars().count(), s)),
+            Message::Error(s) => write_line(buffer, b'-', s.as_bytes()),
+            Message::SimpleString(s) => write_line(buffer, b'+', s.as_bytes()),
+            Message::BulkString(s) => {
+                write_length(buffer, b'$', s.len());
+                buffer.extend_from_slice(s.as_bytes());
+                buffer.extend_from_slice(b"\r\n");
+            }
+            Message::BulkBytes(bytes) => {
+                write_length(buffer, b'$', bytes.len());
+                buffer.extend_from_slice(bytes);
+                buffer.extend_from_slice(b"\r\n");
+            }
             Message::Array(items) => {
-                let mut parts = Vec::with_capacity(items.len());
+                write_length(buffer, b'*', items.len());
 
                 for item in items {
-                    parts.push(item.serialize()?);
+                    item.write_to(buffer);
                 }
-
-                Ok(format!("*{}\r\n{}", parts.len(), parts.join("")))
             }
-            Message::Integer(value) => Ok(format!(":{}\r\n", value)),
-            Message::Null => Ok(NULL_BULK_STRING.to_string()),
+            Message::Integer(value) => {
+                _ = write!(buffer, ":{}\r\n", value);
+            }
+            Message::Null => buffer.extend_from_slice(NULL_BULK_STRING.as_bytes()),
+            Message::NullArray => buffer.extend_from_slice(NULL_ARRAY.as_bytes()),
         }
     }
 
-    pub fn parse(bytes: &[u8]) -> Result<(Self, usize)> {
-        parse_message(bytes)
+    // The length of the RESP encoding
+    pub fn encoded_len(&self) -> usize {
+        let mut buffer = BytesMut::new();
+        self.write_to(&mut buffer);
+
+        buffer.len()
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<(Self, usize), ParseError> {
+        parse_message(bytes, 0)
+    }
+
+    // Clients may also send a command as a plain line of space separated words, like telnet does.
+    // An inline command comes out as the same array of bulk strings a regular request would be
+    pub fn parse_request(bytes: &[u8]) -> Result<(Self, usize), ParseError> {
+        match bytes.first() {
+            Some(b'*') | None => parse_message(bytes, 0),
+            Some(_) => parse_inline(bytes),
+        }
     }
 
     pub fn simple_string_from_str(value: &str) -> Self {
@@ -51,32 +124,100 @@ impl Message {
     pub fn bulk_string(value: String) -> Self {
         Self::BulkString(value)
     }
+
+    pub fn bulk_bytes(value: Vec<u8>) -> Self {
+        match String::from_utf8(value) {
+            Ok(string) => Self::BulkString(string),
+            Err(err) => Self::BulkBytes(err.into_bytes()),
+        }
+    }
 }
 
-fn parse_message(bytes: &[u8]) -> Result<(Message, usize)> {
-    match bytes[0] as char {
-        '*' => parse_array(bytes),
-        '+' => parse_simple_string(bytes),
-        ':' => parse_integer(bytes),
-        '$' => parse_bulk_string(bytes),
-        _ => Err(anyhow!("Unsupported message type. {:?}", bytes)),
+// The rdb file sent during a full resync is framed like a bulk string, but without a trailing CRLF
+pub fn parse_rdb_file(bytes: &[u8]) -> Result<(Vec<u8>, usize), ParseError> {
+    match bytes.first() {
+        Some(b'$') => {}
+        Some(tag) => return Err(ParseError::UnexpectedType(*tag as char)),
+        None => return Err(ParseError::Incomplete),
+    }
+
+    let (line, len) = read_until_crlf(&bytes[1..])?;
+    let length = parse_length(line, MAX_BULK_LENGTH)
+        .flatten()
+        .ok_or(ParseError::InvalidBulkLength)?;
+
+    let start = len + 1;
+    let end = start + length;
+
+    if bytes.len() < end {
+        return Err(ParseError::Incomplete);
+    }
+
+    Ok((bytes[start..end].to_vec(), end))
+}
+
+fn write_line(buffer: &mut BytesMut, tag: u8, line: &[u8]) {
+    buffer.put_u8(tag);
+    buffer.extend_from_slice(line);
+    buffer.extend_from_slice(b"\r\n");
+}
+
+fn write_length(buffer: &mut BytesMut, tag: u8, length: usize) {
+    _ = write!(buffer, "{}{}\r\n", tag as char, length);
+}
+
+fn parse_message(bytes: &[u8], depth: usize) -> Result<(Message, usize), ParseError> {
+    if depth > MAX_NESTING_DEPTH {
+        return Err(ParseError::TooDeeplyNested);
+    }
+
+    let Some(tag) = bytes.first() else {
+        return Err(ParseError::Incomplete);
+    };
+
+    match tag {
+        b'*' => parse_array(bytes, depth),
+        b'+' => parse_simple_string(bytes),
+        b'-' => parse_error(bytes),
+        b':' => parse_integer(bytes),
+        b'$' => parse_bulk_string(bytes),
+        _ => Err(ParseError::UnexpectedType(*tag as char)),
     }
 }
 
-fn parse_array(bytes: &[u8]) -> Result<(Message, usize)> {
-    let (array_items, mut bytes_consumed) = if let Some((line, len)) = read_until_crlf(&bytes[1..])
-    {
-        let array_items = parse_int(line).unwrap();
+fn parse_inline(bytes: &[u8]) -> Result<(Message, usize), ParseError> {
+    let Some(end) = bytes.iter().position(|&byte| byte == b'\n') else {
+        // Without a limit a client that never sends a newline could grow the buffer forever
+        if bytes.len() > MAX_INLINE_LENGTH {
+            return Err(ParseError::TooBigInlineRequest);
+        }
+
+        return Err(ParseError::Incomplete);
+    };
+
+    let items = bytes[..end]
+        .split(|byte| byte.is_ascii_whitespace())
+        .filter(|word| !word.is_empty())
+        .map(|word| Message::bulk_bytes(word.to_vec()))
+        .collect();
+
+    Ok((Message::Array(items), end + 1))
+}
+
+fn parse_array(bytes: &[u8], depth: usize) -> Result<(Message, usize), ParseError> {
+    let (line, len) = read_header(&bytes[1..], ParseError::TooBigMultibulkCount)?;
+    let array_items =
+        parse_length(line, MAX_ARRAY_LENGTH).ok_or(ParseError::InvalidMultibulkLength)?;
+    let mut bytes_consumed = len + 1;
 
-        (array_items, len + 1)
-    } else {
-        return Err(anyhow!("Invalid array, {:?}", bytes));
+    let Some(array_items) = array_items else {
+        return Ok((Message::NullArray, bytes_consumed));
     };
 
     let mut items = vec![];
 
     for _ in 0..array_items {
-        let (array_item, len) = parse_message(&BytesMut::from(&bytes[bytes_consumed..]))?;
+        let (array_item, len) = parse_message(&bytes[bytes_consumed..], depth + 1)?;
 
         items.push(array_item);
         bytes_consumed += len;
@@ -85,57 +226,97 @@ fn parse_array(bytes: &[u8]) -> Result<(Message, usize)> {
     Ok((Message::Array(items), bytes_consumed))
 }
 
-fn parse_simple_string(bytes: &[u8]) -> Result<(Message, usize)> {
-    if let Some((line, len)) = read_until_crlf(&bytes[1..]) {
-        let string = String::from_utf8(line.to_vec())?;
+fn parse_simple_string(bytes: &[u8]) -> Result<(Message, usize), ParseError> {
+    let (string, len) = parse_line(bytes)?;
 
-        return Ok((Message::SimpleString(string), len + 1));
-    }
+    Ok((Message::SimpleString(string), len))
+}
 
-    Err(anyhow!("Invalid simple string, {:?}", bytes))
+fn parse_error(bytes: &[u8]) -> Result<(Message, usize), ParseError> {
+    let (string, len) = parse_line(bytes)?;
+
+    Ok((Message::Error(string), len))
 }
 
-fn parse_integer(bytes: &[u8]) -> Result<(Message, usize)> {
-    if let Some((line, len)) = read_until_crlf(&bytes[1..]) {
-        let number = String::from_utf8(line.to_vec())?;
+fn parse_line(bytes: &[u8]) -> Result<(String, usize), ParseError> {
+    let (line, len) = read_until_crlf(&bytes[1..])?;
+    let string = String::from_utf8(line.to_vec()).map_err(|_| ParseError::InvalidUtf8)?;
 
-        let value = number.parse::<isize>()?;
+    Ok((string, len + 1))
+}
 
-        return Ok((Message::Integer(value), len + 1));
-    }
+fn parse_integer(bytes: &[u8]) -> Result<(Message, usize), ParseError> {
+    let (line, len) = read_until_crlf(&bytes[1..])?;
+    let value = parse_int(line)
+        .and_then(|x| isize::try_from(x).ok())
+        .ok_or(ParseError::InvalidInteger)?;
 
-    Err(anyhow!("Invalid simple string, {:?}", bytes))
+    Ok((Message::Integer(value), len + 1))
 }
 
-fn parse_bulk_string(bytes: &[u8]) -> Result<(Message, usize)> {
-    let (str_len, bytes_consumed) = if let Some((line, len)) = read_until_crlf(&bytes[1..]) {
-        let str_len = parse_int(line).unwrap();
+fn parse_bulk_string(bytes: &[u8]) -> Result<(Message, usize), ParseError> {
+    let (line, len) = read_header(&bytes[1..], ParseError::TooBigBulkCount)?;
+    let str_len = parse_length(line, MAX_BULK_LENGTH).ok_or(ParseError::InvalidBulkLength)?;
+    let bytes_consumed = len + 1;
 
-        (str_len, len + 1)
-    } else {
-        return Err(anyhow!("Invalid bulk string, {:?}", bytes));
+    let Some(str_len) = str_len else {
+        return Ok((Message::Null, bytes_consumed));
     };
 
-    let end_of_bulk_str = bytes_consumed + str_len as usize;
+    let end_of_bulk_str = bytes_consumed + str_len;
     let total_length = end_of_bulk_str + 2;
 
-    let string = String::from_utf8(bytes[bytes_consumed..end_of_bulk_str].to_vec())?;
+    if bytes.len() < total_length {
+        return Err(ParseError::Incomplete);
+    }
 
-    Ok((Message::BulkString(string), total_length))
+    if &bytes[end_of_bulk_str..total_length] != b"\r\n" {
+        return Err(ParseError::UnterminatedBulkString);
+    }
+
+    let message = match String::from_utf8(bytes[bytes_consumed..end_of_bulk_str].to_vec()) {
+        Ok(string) => Message::BulkString(string),
+        Err(err) => Message::BulkBytes(err.into_bytes()),
+    };
+
+    Ok((message, total_length))
 }
 
-fn parse_int(buffer: &[u8]) -> Result<i64> {
-    Ok(String::from_utf8(buffer.to_vec())?.parse::<i64>()?)
+// Parses the length header of an array or bulk string, where -1 encodes null
+fn parse_length(buffer: &[u8], max: usize) -> Option<Option<usize>> {
+    let length = parse_int(buffer)?;
+
+    if length == -1 {
+        return Some(None);
+    }
+
+    match usize::try_from(length) {
+        Ok(length) if length <= max => Some(Some(length)),
+        _ => None,
+    }
 }
 
-fn read_until_crlf(buffer: &[u8]) -> Option<(&[u8], usize)> {
+fn parse_int(buffer: &[u8]) -> Option<i64> {
+    std::str::from_utf8(buffer).ok()?.parse::<i64>().ok()
+}
+
+fn read_until_crlf(buffer: &[u8]) -> Result<(&[u8], usize), ParseError> {
     for i in 1..buffer.len() {
         if buffer[i - 1] == b'\r' && buffer[i] == b'\n' {
-            return Some((&buffer[0..(i - 1)], i + 1));
+            return Ok((&buffer[0..(i - 1)], i + 1));
         }
     }
 
-    None
+    Err(ParseError::Incomplete)
+}
+
+// The length line of an array or bulk string, which like an inline request can't grow the buffer
+// forever while waiting for its CRLF
+fn read_header(buffer: &[u8], too_big: ParseError) -> Result<(&[u8], usize), ParseError> {
+    match read_until_crlf(buffer) {
+        Err(ParseError::Incomplete) if buffer.len() > MAX_INLINE_LENGTH => Err(too_big),
+        result => result,
+    }
 }
 
 pub fn unpack_string(message: &Message) -> Result<String> {
@@ -150,18 +331,20 @@ pub fn stream_to_message(stream: &Stream) -> Message {
     let message_content: Vec<_> = stream
         .entries
         .iter()
-        .map(|(id, data)| {
-            Message::Array(vec![
-                Message::BulkString(id.to_string()),
-                Message::Array(
-                    data.flatten()
-                        .iter()
-                        .map(|x| Message::BulkString(x.clone()))
-                        .collect::<Vec<_>>(),
-                ),
-            ])
-        })
+        .map(|(id, data)| stream_entry_to_message(id, data))
         .collect();
 
     Message::Array(message_content)
 }
+
+pub fn stream_entry_to_message(id: &StreamId, data: &StreamData) -> Message {
+    Message::Array(vec![
+        Message::BulkString(id.to_string()),
+        Message::Array(
+            data.flatten()
+                .iter()
+                .map(|x| Message::BulkString(x.clone()))
+                .collect::<Vec<_>>(),
+        ),
+    ])
+}
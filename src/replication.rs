@@ -1,13 +1,12 @@
-use std::{net::SocketAddr, sync::Arc, time::Duration, vec};
+use std::{collections::VecDeque, net::SocketAddr, sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex}, time::Duration, vec};
 
 use anyhow::{bail, Result};
-use tokio::{io::AsyncWriteExt, net::TcpStream, sync::mpsc::{self, Receiver, Sender}, task::JoinHandle, time::timeout};
+use bytes::{Bytes, BytesMut};
+use tokio::{net::TcpStream, sync::{mpsc::{self, Receiver, Sender}, RwLock}, task::JoinHandle, time::{timeout_at, Instant}};
 
-use crate::{communication::{MessageStream, ReplicaStream}, configuration::{ReplicationRole, ServerInformation}, messages::Message};
+use crate::{command_name, commands::command_spec, communication::{MessageStream, ReplicaStream}, configuration::{ReplicationRole, ServerInformation}, messages::Message, store::{full_resync_rdb, Store}};
 
 pub async fn needs_to_replicate(info: &Arc<ServerInformation>) -> bool {
-    let info = info;
-
     match info.role {
         ReplicationRole::Master => false,
         ReplicationRole::Replication(_) => true
@@ -21,7 +20,28 @@ fn get_master_socket_addr(info: &ServerInformation) -> Option<SocketAddr> {
     }
 }
 
-pub async fn handle_handshake_with_master(info: Arc<ServerInformation>) -> Result<ReplicaStream> {   
+// What a replica remembers of its master across reconnects, offered with PSYNC so the master can
+// continue the stream of commands instead of sending the whole dataset again
+#[derive(Debug, Clone)]
+pub struct MasterLink {
+    pub replid: String,
+    // The replication offset up to which every command was processed
+    pub offset: usize
+}
+
+// How the master answered PSYNC
+#[derive(Debug)]
+pub enum PsyncReply {
+    // An rdb follows, and the commands after it start at the offset of the link
+    FullResync(MasterLink),
+    // The commands continue right after our offset, with the new replid if the master changed it
+    Continue(Option<String>)
+}
+
+// Redis tries to reconnect to its master once a second
+pub const MASTER_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+pub async fn handle_handshake_with_master(info: Arc<ServerInformation>, link: Option<&MasterLink>) -> Result<(ReplicaStream, PsyncReply)> {
     let info = info;
 
     let socket_addr = get_master_socket_addr(&info);
@@ -33,7 +53,7 @@ pub async fn handle_handshake_with_master(info: Arc<ServerInformation>) -> Resul
     let mut replica_stream = ReplicaStream::bind(stream);
 
     { // 1. Ping
-        println!("Replication: ping");
+        info!("replication handshake"; step = "ping", master = socket_addr);
 
         let ping_command = Message::Array(vec![Message::BulkString("ping".to_string())]);
         _ = replica_stream.write(ping_command).await;
@@ -41,12 +61,12 @@ pub async fn handle_handshake_with_master(info: Arc<ServerInformation>) -> Resul
     }
 
     { // 2.1 REPLCONF listening port
-        println!("Replication: replconf port");
+        info!("replication handshake"; step = "listening-port", master = socket_addr);
 
         let listening_port_command = Message::Array(vec![
             Message::BulkString("REPLCONF".to_string()),
             Message::BulkString("listening-port".to_string()),
-            Message::BulkString(info.socket_address.port().to_string())
+            Message::BulkString(info.port.to_string())
         ]);
 
         _ = replica_stream.write(listening_port_command).await;
@@ -54,7 +74,7 @@ pub async fn handle_handshake_with_master(info: Arc<ServerInformation>) -> Resul
     }
 
     { // 2.2 REPLCONF capabilities
-        println!("Replication: replconf capa");
+        info!("replication handshake"; step = "capa", master = socket_addr);
 
         let capability_command = Message::Array(vec![
             Message::BulkString("REPLCONF".to_string()),
@@ -67,88 +87,286 @@ pub async fn handle_handshake_with_master(info: Arc<ServerInformation>) -> Resul
     }
 
     { // 3. PSYNC
-        println!("Replication: psync");
+        info!("replication handshake"; step = "psync", master = socket_addr);
+
+        // Without a link we know nothing of the master yet, otherwise we ask for the first byte we miss
+        let (replid, offset) = match link {
+            Some(link) => (link.replid.clone(), (link.offset + 1).to_string()),
+            None => ("?".to_string(), "-1".to_string())
+        };
 
         let psync_command = Message::Array(vec![
             Message::BulkString("PSYNC".to_string()),
-            Message::BulkString("?".to_string()), // replication id
-            Message::BulkString("-1".to_string()) // replication offset
-        ]);   
+            Message::BulkString(replid), // replication id
+            Message::BulkString(offset) // replication offset
+        ]);
 
         _ = replica_stream.write(psync_command).await;
-        _ = replica_stream.get_response().await;
+
+        let reply = match replica_stream.get_response().await {
+            Some(Message::SimpleString(reply)) => parse_psync_reply(&reply, link.is_some()),
+            _ => None
+        };
+
+        let Some(reply) = reply else { bail!("unexpected reply to PSYNC") };
+
+        Ok((replica_stream, reply))
     }
+}
+
+// +FULLRESYNC <replid> <offset> or +CONTINUE [replid], a CONTINUE we didn't offer a link for is bogus
+fn parse_psync_reply(reply: &str, has_link: bool) -> Option<PsyncReply> {
+    let mut parts = reply.split_whitespace();
 
-    Ok(replica_stream)
+    match parts.next()?.to_uppercase().as_str() {
+        "FULLRESYNC" => {
+            let replid = parts.next()?.to_string();
+            let offset = parts.next()?.parse().ok()?;
+
+            Some(PsyncReply::FullResync(MasterLink { replid, offset }))
+        },
+        "CONTINUE" if has_link => Some(PsyncReply::Continue(parts.next().map(str::to_string))),
+        _ => None
+    }
 }
 
 #[derive(Debug)]
-pub struct ReplicaCommand {
-    pub message: Message,
-    pub timeout: Option<Duration>
+pub struct ReplicaHandle {
+    // Already serialized messages, so they can be shared between all replicas
+    pub tx: Sender<Bytes>,
+    // The replication offset up to which the replica confirmed to have processed everything
+    pub acked_offset: Arc<AtomicUsize>,
+    pub last_ack: Arc<Mutex<Instant>>,
+    pub address: ReplicaAddress,
 }
 
-impl ReplicaCommand {
-    pub fn new(message: Message) -> Self {
-        Self { message, timeout: None }
-    }
+// Where the replica can be reached, the port being the one it announced with REPLCONF
+// listening-port rather than the one it connected from
+#[derive(Debug, Clone)]
+pub struct ReplicaAddress {
+    pub ip: String,
+    pub port: u16,
+}
+
+impl ReplicaAddress {
+    // The peer is the ip:port the replica connected from, with an ipv6 address in brackets
+    pub fn new(peer: &str, port: u16) -> Self {
+        let ip = peer.rsplit_once(':').map_or(peer, |(ip, _)| ip);
 
-    pub fn with_timeout(message: Message, duration : Duration) -> Self {
-        Self { message, timeout: Some(duration) }
+        Self { ip: ip.trim_start_matches('[').trim_end_matches(']').to_string(), port }
     }
 }
 
-#[derive(Debug)]
-pub struct ReplicaResponse {
-    pub expired: bool
+// Commands a replica may lag behind before we give up on it
+const REPLICA_CHANNEL_CAPACITY: usize = 16 * 1024;
+
+// Same as the default repl-backlog-size of redis
+const REPL_BACKLOG_SIZE: usize = 1024 * 1024;
+
+// The most recently propagated bytes, so a replica that lost its connection can continue from its
+// offset as long as it didn't fall too far behind
+#[derive(Debug, Default)]
+pub struct ReplicationBacklog {
+    data: VecDeque<u8>,
+    // The replication offset of the first byte in data
+    start_offset: usize
 }
 
-impl ReplicaResponse {
-    pub fn received() -> Self {
-        Self { expired: false }
+impl ReplicationBacklog {
+    fn append(&mut self, data: &[u8]) {
+        self.data.extend(data);
+
+        let dropped = self.data.len().saturating_sub(REPL_BACKLOG_SIZE);
+        self.data.drain(..dropped);
+        self.start_offset += dropped;
     }
 
-    pub fn expired() -> Self {
-        Self { expired: true }
+    // Everything propagated after `offset`, None once that was dropped or when it is beyond what we have
+    fn since(&self, offset: usize) -> Option<Vec<u8>> {
+        let skipped = offset.checked_sub(self.start_offset)?;
+
+        (skipped <= self.data.len()).then(|| self.data.range(skipped..).copied().collect())
     }
 }
 
+// A replica that got its reply to PSYNC, but whose connection isn't handed over to replication yet.
+// Whatever is propagated in between already waits in the channel.
 #[derive(Debug)]
-pub struct ReplicaHandle {
-    pub tx: Sender<ReplicaCommand>,
-    pub rx: Receiver<ReplicaResponse>,
+pub struct PendingReplica {
+    rx: Receiver<Bytes>,
+    // Goes out before anything in the channel, the rdb of a full resync or what a partial one missed
+    payload: Vec<u8>,
+    acked_offset: Arc<AtomicUsize>,
+    last_ack: Arc<Mutex<Instant>>
 }
 
-pub fn replication_channel(mut message_stream: MessageStream) -> (ReplicaHandle, JoinHandle<()>) {
-    let (tx_res, mut rx) = mpsc::channel::<ReplicaCommand>(32);
-    let (tx, rx_res) = mpsc::channel::<ReplicaResponse>(32);
+// Answers PSYNC <replid> <offset>, continuing from the offset when it is ours and still in the backlog.
+// The replica is registered with the store read locked, and writers propagate before releasing the
+// store, so the snapshot holds exactly the writes up to the offset in the reply and everything after
+// it reaches the channel.
+pub async fn attach_replica(store: &RwLock<Store>, info: &ServerInformation, params: &[String], address: ReplicaAddress) -> (Message, PendingReplica) {
+    // The replica asks for the first byte it misses, so it processed everything before it
+    let requested = match params {
+        [replid, offset] if *replid == info.repl_id => offset.parse::<usize>().ok().and_then(|offset| offset.checked_sub(1)),
+        _ => None
+    };
+
+    let store = store.read().await;
+    let mut handles = info.replication_handles.lock().await;
+
+    let missed = match requested {
+        Some(offset) => info.repl_backlog.lock().await.since(offset).map(|missed| (offset, missed)),
+        None => None
+    };
+
+    let (reply, payload, offset) = match missed {
+        Some((offset, missed)) => (format!("CONTINUE {}", info.repl_id), missed, offset),
+        None => {
+            let offset = info.repl_offset.load(Ordering::Acquire);
+            (format!("FULLRESYNC {} {}", info.repl_id, offset), full_resync_rdb(&store), offset)
+        }
+    };
 
-    let handle = tokio::spawn(async move {
-        loop {
-            while let Some(replica_command) = rx.recv().await {
-                write_message(&mut message_stream.stream, &replica_command.message).await;
+    let (tx, rx) = mpsc::channel::<Bytes>(REPLICA_CHANNEL_CAPACITY);
+    let acked_offset = Arc::new(AtomicUsize::new(offset));
+    let last_ack = Arc::new(Mutex::new(Instant::now()));
+
+    handles.push(ReplicaHandle { tx, acked_offset: acked_offset.clone(), last_ack: last_ack.clone(), address });
+
+    (Message::simple_string(reply), PendingReplica { rx, payload, acked_offset, last_ack })
+}
+
+// The returned task is the only writer to the replica, so everything sent to it arrives in the
+// order it was queued. It also reads the acks the replica sends back, which are replication offsets
+// just like ours. The payload goes out first, whatever is propagated while it is being sent waits in the channel.
+pub fn replication_channel(mut message_stream: MessageStream, replica: PendingReplica, info: Arc<ServerInformation>) -> JoinHandle<()> {
+    let PendingReplica { mut rx, payload, acked_offset, last_ack } = replica;
+
+    // Runs until the handle is dropped or the replica goes away
+    tokio::spawn(async move {
+        if message_stream.write_raw(&payload).await.is_err() {
+            info!("replica disconnected");
+            return;
+        }
 
-                if let Some(duration) = replica_command.timeout {
-                    let timed_out = timeout(duration, message_stream.read_message()).await.is_err();
-                    let response = if timed_out { ReplicaResponse::expired() } else { ReplicaResponse::received() };
+        loop {
+            tokio::select! {
+                data = rx.recv() => {
+                    let Some(data) = data else { break };
 
-                    _ = tx.send(response).await;
+                    if message_stream.write_raw(&data).await.is_err() {
+                        break;
+                    }
+                }
+                message = message_stream.read_message() => {
+                    let Ok(Some(message)) = message else { break };
+
+                    if let Some(offset) = parse_ack_offset(&message) {
+                        acked_offset.store(offset, Ordering::Release);
+                        *last_ack.lock().unwrap() = Instant::now();
+                        info.replica_acks.notify_waiters();
+                    }
                 }
             }
         }
-    });
 
-    (
-        ReplicaHandle {
-            tx: tx_res,
-            rx: rx_res
-        },
-        handle
-    )
+        info!("replica disconnected");
+    })
+}
+
+// REPLCONF ACK <offset>
+fn parse_ack_offset(message: &Message) -> Option<usize> {
+    let Message::Array(items) = message else { return None };
+
+    match items.as_slice() {
+        [Message::BulkString(command), Message::BulkString(ack), Message::BulkString(offset)]
+            if command.eq_ignore_ascii_case("replconf") && ack.eq_ignore_ascii_case("ack") => offset.parse().ok(),
+        _ => None
+    }
+}
+
+// Waits until `needed` replicas have acked everything propagated before the call, or the wait time
+// is up. Replicas that are behind are asked for an ack, the count at that moment is returned.
+pub async fn wait_for_replica_acks(info: &ServerInformation, needed: usize, wait_time: Duration) -> usize {
+    let deadline = Instant::now() + wait_time;
+    let target = info.repl_offset.load(Ordering::Acquire);
+
+    let mut acked = count_acked_replicas(info, target).await;
+
+    if acked >= needed {
+        return acked;
+    }
+
+    let ack_message = Message::Array(vec![
+        Message::BulkString("REPLCONF".to_string()),
+        Message::BulkString("GETACK".to_string()),
+        Message::BulkString("*".to_string()),
+    ]);
+
+    propagate_to_replicas(info, &ack_message).await;
+
+    loop {
+        // Registered before counting, so an ack arriving in between isn't missed
+        let notified = info.replica_acks.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+
+        acked = count_acked_replicas(info, target).await;
+
+        if acked >= needed || timeout_at(deadline, notified).await.is_err() {
+            return acked;
+        }
+    }
 }
 
-async fn write_message(socket: &mut TcpStream, message: &Message) {
-    if let Ok(serialized) = message.serialize() {
-        socket.write(serialized.as_bytes()).await.expect("Unable to write to socket");
+async fn count_acked_replicas(info: &ServerInformation, target: usize) -> usize {
+    info.replication_handles.lock().await.iter()
+        .filter(|replication| replication.acked_offset.load(Ordering::Acquire) >= target)
+        .count()
+}
+
+// Never waits on a replica while holding the handles lock, a replica that can't keep up gets
+// disconnected instead of stalling every client writing to us. Writers call this before letting go
+// of the store, so a snapshot taken under the store lock matches the replication offset.
+pub async fn propagate_to_replicas(info: &ServerInformation, message: &Message) {
+    // Local only commands never reach the replicas, whoever asks for them to be propagated
+    if command_spec(command_name(message)).is_some_and(|spec| !spec.is_replicated()) {
+        return;
     }
+
+    let data = serialize_message(message);
+
+    // The offset is moved while holding the lock, so replicas attaching concurrently get a
+    // base offset that matches what they will receive
+    let mut handles = info.replication_handles.lock().await;
+
+    info.repl_offset.fetch_add(data.len(), Ordering::AcqRel);
+    info.repl_backlog.lock().await.append(&data);
+
+    handles.retain(|replication| {
+        let sent = replication.tx.try_send(data.clone());
+
+        if sent.is_err() {
+            warn!("dropping replica that is lagging behind");
+        }
+
+        sent.is_ok()
+    });
+}
+
+// A key the master removed because its expiry passed, which replicas only hide until then
+pub async fn propagate_expired_key(info: &ServerInformation, key: &str) {
+    let message = Message::Array(vec![
+        Message::BulkString("DEL".to_string()),
+        Message::BulkString(key.to_string())
+    ]);
+
+    propagate_to_replicas(info, &message).await;
+}
+
+fn serialize_message(message: &Message) -> Bytes {
+    let mut buffer = BytesMut::new();
+    message.write_to(&mut buffer);
+
+    buffer.freeze()
 }
@@ -1,9 +1,15 @@
 use std::{
-    collections::HashMap,
+    cmp::Reverse,
+    collections::{BTreeSet, BinaryHeap, HashMap},
     env,
     fmt::Display,
+    hash::{BuildHasher, DefaultHasher, Hash, Hasher, RandomState},
     path::Path,
-    sync::Arc,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, AtomicU8, Ordering},
+        Arc,
+    },
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
@@ -11,25 +17,120 @@ use anyhow::{bail, Result};
 use tokio::{
     fs::{metadata, File},
     io::AsyncReadExt,
+    sync::{Notify, RwLock},
+    time::interval,
 };
 
-use crate::{configuration::ServerInformation, util::decode_hex};
+use crate::{
+    configuration::{ReplicationRole, ServerInformation},
+    errors::CommandError,
+    listpack::{self, ListpackElement},
+    replication::propagate_expired_key,
+    util::{crc64, lzf_decompress},
+};
 
 pub trait EntryValue {
     fn value_type(&self) -> String;
 }
 
+// Above this length redis no longer embeds a string in its object, which OBJECT ENCODING reports
+const EMBSTR_MAX_LENGTH: usize = 44;
+
+// A string value, kept as an integer when it is exactly how that integer would be printed, like
+// the int encoding of redis
+#[derive(Debug, Clone, PartialEq)]
+pub enum StringValue {
+    Int(i64),
+    Bytes(Vec<u8>),
+    // Written to in place, by SETRANGE or SETBIT, which redis never embeds or turns back into an int
+    Raw(Vec<u8>),
+}
+
+impl StringValue {
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        match parse_canonical_int(&bytes) {
+            Some(value) => Self::Int(value),
+            None => Self::Bytes(bytes),
+        }
+    }
+
+    // Strings holding digits count as integers too, they just weren't stored as one
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Self::Int(value) => Some(*value),
+            Self::Bytes(bytes) | Self::Raw(bytes) => parse_canonical_int(bytes),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Self::Int(value) => value.to_string().into_bytes(),
+            Self::Bytes(bytes) | Self::Raw(bytes) => bytes.clone(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Int(value) => value.to_string().len(),
+            Self::Bytes(bytes) | Self::Raw(bytes) => bytes.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Self::Int(_) => "int",
+            Self::Bytes(bytes) if bytes.len() <= EMBSTR_MAX_LENGTH => "embstr",
+            Self::Bytes(_) | Self::Raw(_) => "raw",
+        }
+    }
+}
+
+impl From<String> for StringValue {
+    fn from(value: String) -> Self {
+        Self::from_bytes(value.into_bytes())
+    }
+}
+
+impl From<Vec<u8>> for StringValue {
+    fn from(value: Vec<u8>) -> Self {
+        Self::from_bytes(value)
+    }
+}
+
+fn bit_position(offset: u64) -> (usize, u8) {
+    ((offset / 8) as usize, 0x80 >> (offset % 8))
+}
+
+// Leading zeroes, a plus sign or whitespace make it a plain string
+fn parse_canonical_int(bytes: &[u8]) -> Option<i64> {
+    let value = std::str::from_utf8(bytes).ok()?.parse::<i64>().ok()?;
+
+    (value.to_string().as_bytes() == bytes).then_some(value)
+}
+
+// Whether a deadline has passed, the same for reads and for the active expiry. Like redis, a key
+// lives through its very deadline.
+fn is_expired_at(expiry_at: SystemTime, now: SystemTime) -> bool {
+    expiry_at < now
+}
+
 #[derive(Debug, Clone)]
 pub struct Entry {
-    pub value: String,
+    pub value: StringValue,
     pub expiry_at: Option<SystemTime>,
 }
 
 impl Entry {
-    pub fn new(value: String, expiry: Option<Duration>) -> Self {
-        if expiry.is_some() {
+    pub fn new(value: impl Into<StringValue>, expiry: Option<Duration>) -> Self {
+        let value = value.into();
+
+        if let Some(expiry) = expiry {
             let current_time = SystemTime::now();
-            let expiry_time = current_time + expiry.unwrap();
+            let expiry_time = current_time + expiry;
 
             Self {
                 value,
@@ -42,6 +143,11 @@ impl Entry {
             }
         }
     }
+
+    pub fn is_expired(&self) -> bool {
+        self.expiry_at
+            .is_some_and(|expiry_at| is_expired_at(expiry_at, SystemTime::now()))
+    }
 }
 
 impl EntryValue for Entry {
@@ -50,9 +156,33 @@ impl EntryValue for Entry {
     }
 }
 
+// The NX, XX, GT and LT flags of EXPIRE
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExpireCondition {
+    NotExists,
+    Exists,
+    GreaterThan,
+    LessThan,
+}
+
+impl ExpireCondition {
+    // A key without an expiry counts as expiring infinitely far away
+    pub fn holds(self, current: Option<SystemTime>, expiry_at: SystemTime) -> bool {
+        match (self, current) {
+            (Self::NotExists, current) => current.is_none(),
+            (Self::Exists, current) => current.is_some(),
+            (Self::GreaterThan, Some(current)) => expiry_at > current,
+            (Self::GreaterThan, None) => false,
+            (Self::LessThan, Some(current)) => expiry_at < current,
+            (Self::LessThan, None) => true,
+        }
+    }
+}
+
+// The fields of a stream entry, in the order XADD got them. Like redis, a field may repeat
 #[derive(Debug, Clone)]
 pub struct StreamData {
-    pub data: HashMap<String, String>,
+    pub data: Vec<(String, String)>,
 }
 
 impl StreamData {
@@ -74,20 +204,16 @@ pub struct StreamId {
     pub seq: u64,
 }
 
-impl From<&String> for StreamId {
-    fn from(value: &String) -> Self {
-        let (ms, seq) = value
-            .split_once('-')
-            .expect("Unable to parse the string into two parts");
+impl FromStr for StreamId {
+    type Err = CommandError;
 
-        let ms = ms
-            .parse::<u64>()
-            .expect("Unable to parse ms value from string");
-        let seq = seq
-            .parse::<u64>()
-            .expect("Unable to parse seq value from string");
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (ms, seq) = value.split_once('-').ok_or(CommandError::InvalidStreamId)?;
 
-        Self { ms, seq }
+        let ms = ms.parse().map_err(|_| CommandError::InvalidStreamId)?;
+        let seq = seq.parse().map_err(|_| CommandError::InvalidStreamId)?;
+
+        Ok(Self { ms, seq })
     }
 }
 
@@ -99,29 +225,224 @@ impl Display for StreamId {
 
 // stream_key   | 1526919030474-0   | temperature 36 humidity 95
 // store key    | id                | stream data
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Stream {
     pub entries: Vec<(StreamId, StreamData)>,
+    pub expiry_at: Option<SystemTime>,
 }
 
+// redis' default stream-node-max-entries
+const STREAM_NODE_MAX_ENTRIES: usize = 100;
+
 impl Stream {
     pub fn empty() -> Self {
+        Self::new(Vec::new())
+    }
+
+    pub fn new(entries: Vec<(StreamId, StreamData)>) -> Self {
         Self {
-            entries: Vec::new(),
+            entries,
+            expiry_at: None,
         }
     }
 
-    pub fn new(entries: Vec<(StreamId, StreamData)>) -> Self {
-        Self { entries }
+    // There is no radix tree of listpacks, these are the sizes it would have with the entries
+    // split into nodes like the rdb writer does, plus the root
+    pub fn radix_tree_keys(&self) -> usize {
+        self.entries.len().div_ceil(STREAM_NODE_MAX_ENTRIES)
+    }
+
+    pub fn radix_tree_nodes(&self) -> usize {
+        self.radix_tree_keys() + 1
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum StoreItem {
     KeyValueEntry(Entry),
     Stream(Stream),
 }
 
+// Rough sizes of the allocations redis makes around the data itself, MEMORY USAGE is an estimate
+const OBJECT_OVERHEAD: usize = 16;
+const DICT_ENTRY_OVERHEAD: usize = 24;
+// How many keys are compared for every eviction, the maxmemory-samples default of redis
+const EVICTION_SAMPLES: usize = 5;
+const STREAM_ENTRY_OVERHEAD: usize = 16;
+
+impl StoreItem {
+    // Bytes taken by the value, with `samples` entries of a stream measured and the rest assumed
+    // to be alike. 0 measures all of them
+    pub fn memory_usage(&self, samples: usize) -> usize {
+        match self {
+            Self::KeyValueEntry(entry) => string_memory_usage(&entry.value),
+            Self::Stream(stream) => {
+                let count = stream.entries.len();
+                let sampled = if samples == 0 {
+                    count
+                } else {
+                    samples.min(count)
+                };
+
+                let sampled_bytes: usize = stream.entries[..sampled]
+                    .iter()
+                    .map(|(_, data)| stream_entry_memory_usage(data))
+                    .sum();
+
+                let entries_bytes = match sampled {
+                    0 => 0,
+                    _ => sampled_bytes * count / sampled,
+                };
+
+                OBJECT_OVERHEAD + entries_bytes
+            }
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expiry_at()
+            .is_some_and(|expiry_at| is_expired_at(expiry_at, SystemTime::now()))
+    }
+
+    pub fn expiry_at(&self) -> Option<SystemTime> {
+        match self {
+            Self::KeyValueEntry(x) => x.expiry_at,
+            Self::Stream(x) => x.expiry_at,
+        }
+    }
+
+    fn expiry_at_mut(&mut self) -> &mut Option<SystemTime> {
+        match self {
+            Self::KeyValueEntry(x) => &mut x.expiry_at,
+            Self::Stream(x) => &mut x.expiry_at,
+        }
+    }
+}
+
+// A stored item together with the metadata every type shares
+#[derive(Debug)]
+pub struct StoredValue {
+    pub item: StoreItem,
+    // Milliseconds since the unix epoch, atomic so reads can refresh it under a shared lock
+    last_accessed: AtomicU64,
+    // The access counter of the LFU policies, as of the last access
+    frequency: AtomicU8,
+}
+
+// The logarithmic access counter of redis. A new key starts at LFU_INIT_VAL so it isn't the first
+// to be evicted, every access is less likely to increment the counter the higher it already is,
+// and it loses a point for every LFU_DECAY_MINUTES the key isn't accessed.
+const LFU_INIT_VAL: u8 = 5;
+const LFU_LOG_FACTOR: f64 = 10.0;
+const LFU_DECAY_MINUTES: u64 = 1;
+
+impl StoredValue {
+    fn new(item: StoreItem, frequency: u8) -> Self {
+        Self {
+            item,
+            last_accessed: AtomicU64::new(unix_millis()),
+            frequency: AtomicU8::new(frequency),
+        }
+    }
+
+    // The access hook of the read paths, for both the idle time and the access counter
+    fn touch(&self) {
+        let frequency = lfu_log_increment(self.frequency());
+
+        self.frequency.store(frequency, Ordering::Relaxed);
+        self.last_accessed.store(unix_millis(), Ordering::Relaxed);
+    }
+
+    // The access counter with the decay since the last access applied
+    pub fn frequency(&self) -> u8 {
+        let periods = self.idle_time().as_secs() / 60 / LFU_DECAY_MINUTES;
+        let frequency = self.frequency.load(Ordering::Relaxed);
+
+        frequency.saturating_sub(periods.min(u8::MAX as u64) as u8)
+    }
+
+    pub fn idle_time(&self) -> Duration {
+        let last_accessed = self.last_accessed.load(Ordering::Relaxed);
+
+        Duration::from_millis(unix_millis().saturating_sub(last_accessed))
+    }
+}
+
+fn lfu_log_increment(frequency: u8) -> u8 {
+    if frequency == u8::MAX {
+        return frequency;
+    }
+
+    let base = frequency.saturating_sub(LFU_INIT_VAL) as f64;
+    let probability = 1.0 / (base * LFU_LOG_FACTOR + 1.0);
+
+    // A fresh RandomState is seeded differently every time, which is random enough here
+    let random = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+
+    if random < probability {
+        frequency + 1
+    } else {
+        frequency
+    }
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+// Upcoming expirations, soonest first. Overwriting or removing a key doesn't touch the queue,
+// instead every queued expiry remembers the generation of the key it was made for, and is
+// skipped once the key has moved on to a newer generation.
+#[derive(Debug, Default)]
+struct ExpiryIndex {
+    queue: BinaryHeap<Reverse<(SystemTime, u64, String)>>,
+    generations: HashMap<String, u64>,
+    next_generation: u64,
+}
+
+impl ExpiryIndex {
+    fn track(&mut self, key: &str, expiry_at: Option<SystemTime>) {
+        let Some(expiry_at) = expiry_at else {
+            self.generations.remove(key);
+            return;
+        };
+
+        self.next_generation += 1;
+        let generation = self.next_generation;
+
+        self.generations.insert(key.to_string(), generation);
+        self.queue
+            .push(Reverse((expiry_at, generation, key.to_string())));
+    }
+
+    fn forget(&mut self, key: &str) {
+        self.generations.remove(key);
+    }
+
+    fn pop_expired(&mut self, now: SystemTime, limit: usize) -> Vec<String> {
+        let mut keys = Vec::new();
+
+        while keys.len() < limit {
+            match self.queue.peek() {
+                Some(Reverse((expiry_at, _, _))) if is_expired_at(*expiry_at, now) => {}
+                _ => break,
+            }
+
+            let Reverse((_, generation, key)) = self.queue.pop().unwrap();
+
+            if self.generations.get(&key) == Some(&generation) {
+                self.generations.remove(&key);
+                keys.push(key);
+            }
+        }
+
+        keys
+    }
+}
+
 impl EntryValue for StoreItem {
     fn value_type(&self) -> String {
         match self {
@@ -131,25 +452,121 @@ impl EntryValue for StoreItem {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Store {
-    data: HashMap<String, StoreItem>,
+    data: HashMap<String, StoredValue>,
+    // Every key ordered by its hash, so iteration can resume from a cursor while keys come and go
+    key_index: BTreeSet<(u64, String)>,
+    expiries: ExpiryIndex,
+    // Woken up by every entry added to the stream, for the XREADs blocking on it. Only kept for
+    // as long as someone is waiting.
+    stream_notifiers: HashMap<String, Arc<Notify>>,
+    // The estimate of `dataset_memory_usage`, kept up to date by every write so maxmemory can be
+    // checked without going over the keyspace
+    used_memory: usize,
 }
 
 impl Store {
     pub fn new() -> Self {
-        Self {
-            data: HashMap::new(),
-        }
+        Self::default()
     }
 
-    pub fn keys(&self) -> Vec<String> {
-        self.data.keys().cloned().collect()
+    // Visits up to `count` keys starting at `cursor`, skipping expired ones. Returns the cursor to
+    // continue from, which is 0 once every key has been visited.
+    //
+    // The cursor is a position in the key index rather than in the map, so the guarantees of redis
+    // hold however the map grows in between: a key that exists for the whole iteration is returned
+    // at least once, a key added or removed meanwhile may or may not be. Only keys sharing a hash
+    // across a batch boundary can be returned twice.
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<String>) {
+        self.scan_filtered(cursor, count, |_, _| true)
+    }
+
+    // Like `scan`, only returning the keys `filter` accepts. They still count towards `count`
+    pub fn scan_filtered(
+        &self,
+        cursor: u64,
+        count: usize,
+        filter: impl Fn(&String, &StoreItem) -> bool,
+    ) -> (u64, Vec<String>) {
+        let mut keys = Vec::new();
+        let entries = self.key_index.range((cursor, String::new())..);
+
+        for (visited, (hash, key)) in entries.enumerate() {
+            if visited >= count {
+                return (*hash, keys);
+            }
+
+            if self.get_value(key).is_some_and(|item| filter(key, item)) {
+                keys.push(key.clone());
+            }
+        }
+
+        (0, keys)
     }
 
     pub fn set_kv_value(&mut self, key: String, value: Entry) {
         let entry = StoreItem::KeyValueEntry(value);
-        self.data.insert(key, entry);
+        self.insert_item(key, entry);
+    }
+
+    // Like redis, a key that gets overwritten keeps its access counter
+    fn insert_item(&mut self, key: String, item: StoreItem) {
+        let frequency = self
+            .data
+            .get(&key)
+            .map_or(LFU_INIT_VAL, StoredValue::frequency);
+
+        self.used_memory += key_memory_usage(&key, &item);
+        self.key_index.insert((key_hash(&key), key.clone()));
+        self.expiries.track(&key, item.expiry_at());
+
+        if let Some(old) = self
+            .data
+            .insert(key.clone(), StoredValue::new(item, frequency))
+        {
+            self.used_memory -= key_memory_usage(&key, &old.item);
+        }
+    }
+
+    // Returns whether there was a key to delete, one that already expired doesn't count
+    pub fn delete(&mut self, key: &String) -> bool {
+        self.remove_item(key).is_some_and(|item| !item.is_expired())
+    }
+
+    fn remove_item(&mut self, key: &String) -> Option<StoreItem> {
+        let value = self.data.remove(key)?;
+
+        self.used_memory -= key_memory_usage(key, &value.item);
+        self.key_index.remove(&(key_hash(key), key.clone()));
+        self.expiries.forget(key);
+
+        Some(value.item)
+    }
+
+    // Lazy expiry for reads, which only hide an expired key until it is removed here
+    pub fn remove_if_expired(&mut self, key: &String) -> bool {
+        let expired = self
+            .data
+            .get(key)
+            .is_some_and(|value| value.item.is_expired());
+
+        if expired {
+            self.remove_item(key);
+        }
+
+        expired
+    }
+
+    // Removes up to `limit` keys whose expiry has passed, returning the removed keys
+    pub fn remove_expired_keys(&mut self, limit: usize) -> Vec<String> {
+        let keys = self.expiries.pop_expired(SystemTime::now(), limit);
+
+        for key in &keys {
+            self.remove_item(key);
+        }
+
+        keys
     }
 
     pub fn auto_generate_stream_id(&self, key: &String, id_pattern: &String) -> Option<String> {
@@ -175,93 +592,459 @@ impl Store {
         Some(last_id)
     }
 
-    pub fn validate_stream_id(&self, key: &String, id: &String) -> Result<()> {
-        let stream = if let Some(stream) = self.get_stream(key) {
+    pub fn validate_stream_id(&self, key: &String, id: &str) -> Result<(), CommandError> {
+        let id: StreamId = id.parse()?;
+
+        if id.ms == 0 && id.seq == 0 {
+            return Err(CommandError::StreamIdZero);
+        }
+
+        match self.get_value(key) {
+            Some(StoreItem::Stream(stream)) => match stream.entries.last() {
+                Some((last_id, _)) if &id <= last_id => Err(CommandError::StreamIdTooSmall),
+                _ => Ok(()),
+            },
+            Some(_) => Err(CommandError::WrongType),
+            None => Ok(()),
+        }
+    }
+
+    pub fn append_stream_value(
+        &mut self,
+        key: &String,
+        id: &str,
+        stream_data: StreamData,
+    ) -> Result<()> {
+        let stream = if let Some(stream) = self.get_mut_stream(key) {
             stream
         } else {
-            return Ok(());
+            // Create new stream
+            self.insert_item(key.clone(), StoreItem::Stream(Stream::empty()));
+
+            self.get_mut_stream(key).unwrap()
         };
 
-        if stream.entries.len() == 0 {
-            return Ok(());
+        let stream_id = id.parse()?;
+        let added = stream_entry_memory_usage(&stream_data);
+
+        stream.entries.push((stream_id, stream_data));
+        self.used_memory += added;
+
+        if let Some(notify) = self.stream_notifiers.get(key) {
+            notify.notify_waiters();
         }
 
-        let (last_id, _) = stream.entries.last().unwrap();
+        Ok(())
+    }
+
+    // What to wait on for the next entry of a stream, which doesn't have to exist yet
+    pub fn stream_notifier(&mut self, key: &str) -> Arc<Notify> {
+        // Forget the streams nobody is waiting on anymore, their readers dropped their handle
+        self.stream_notifiers
+            .retain(|_, notify| Arc::strong_count(notify) > 1);
 
-        let (last_id_ms, last_id_seq) = (last_id.ms, last_id.seq);
-        let (cur_id_ms, cur_id_seq) = id.split_once('-').unwrap();
+        self.stream_notifiers
+            .entry(key.to_string())
+            .or_default()
+            .clone()
+    }
 
-        let cur_id_ms = cur_id_ms.parse::<u64>().unwrap();
-        let cur_id_seq = cur_id_seq.parse::<u64>().unwrap();
+    // The type is checked before the expiry, so a key holding anything but a string is an error.
+    // Counts as an access of the key.
+    pub fn get_kv_value(&self, key: &String) -> Result<Option<&Entry>, CommandError> {
+        let Some(value) = self.data.get(key) else {
+            return Ok(None);
+        };
 
-        if cur_id_ms == 0 && cur_id_seq == 0 {
-            bail!("ERR The ID specified in XADD must be greater than 0-0");
+        let StoreItem::KeyValueEntry(key_val_entry) = &value.item else {
+            return Err(CommandError::WrongType);
+        };
+
+        if key_val_entry.is_expired() {
+            return Ok(None);
         }
 
-        if cur_id_ms < last_id_ms {
-            bail!(
-                "ERR The ID specified in XADD is equal or smaller than the target stream top item"
-            );
+        value.touch();
+
+        Ok(Some(key_val_entry))
+    }
+
+    // Indices are inclusive, negative ones count from the end of the string
+    pub fn get_range(&self, key: &String, start: i64, end: i64) -> Result<Vec<u8>, CommandError> {
+        let Some(entry) = self.get_kv_value(key)? else {
+            return Ok(Vec::new());
+        };
+
+        let bytes = entry.value.to_bytes();
+        let length = bytes.len() as i64;
+
+        if length == 0 || (start < 0 && end < 0 && start > end) {
+            return Ok(Vec::new());
         }
 
-        if cur_id_ms == last_id_ms && cur_id_seq <= last_id_seq {
-            bail!(
-                "ERR The ID specified in XADD is equal or smaller than the target stream top item"
-            );
+        let start = if start < 0 { length + start } else { start }.max(0);
+        let end = if end < 0 { length + end } else { end }
+            .max(0)
+            .min(length - 1);
+
+        if start > end {
+            return Ok(Vec::new());
         }
 
-        Ok(())
+        Ok(bytes[start as usize..=end as usize].to_vec())
     }
 
-    pub fn append_stream_value(
+    // Overwrites part of the string at `offset`, zero padding it when it is too short.
+    // Returns the length of the string afterwards.
+    pub fn set_range(
         &mut self,
         key: &String,
-        id: &String,
-        stream_data: StreamData,
-    ) -> Result<()> {
-        let stream = if let Some(stream) = self.get_mut_stream(key) {
-            stream
+        offset: i64,
+        value: &[u8],
+        max_length: usize,
+    ) -> Result<usize, CommandError> {
+        let Ok(offset) = usize::try_from(offset) else {
+            return Err(CommandError::OffsetOutOfRange);
+        };
+
+        let current = self.get_kv_value(key)?;
+
+        if value.is_empty() {
+            // Writing nothing never creates the key
+            return Ok(current.map(|entry| entry.value.len()).unwrap_or(0));
+        }
+
+        if offset + value.len() > max_length {
+            return Err(CommandError::StringTooLong);
+        }
+
+        let mut bytes = current
+            .map(|entry| entry.value.to_bytes())
+            .unwrap_or_default();
+
+        if bytes.len() < offset + value.len() {
+            bytes.resize(offset + value.len(), 0);
+        }
+
+        bytes[offset..offset + value.len()].copy_from_slice(value);
+
+        let length = bytes.len();
+        self.write_in_place(key, bytes);
+
+        Ok(length)
+    }
+
+    // Creates the key when it doesn't exist yet. Returns the new length.
+    pub fn append(
+        &mut self,
+        key: &String,
+        value: &[u8],
+        max_length: usize,
+    ) -> Result<usize, CommandError> {
+        let current = self.get_kv_value(key)?;
+
+        if current.map_or(0, |entry| entry.value.len()) + value.len() > max_length {
+            return Err(CommandError::StringTooLong);
+        }
+
+        let mut bytes = current
+            .map(|entry| entry.value.to_bytes())
+            .unwrap_or_default();
+
+        bytes.extend_from_slice(value);
+
+        let length = bytes.len();
+        self.write_in_place(key, bytes);
+
+        Ok(length)
+    }
+
+    // Bits count from the most significant bit of the first byte. Returns the previous bit.
+    pub fn set_bit(
+        &mut self,
+        key: &String,
+        offset: u64,
+        bit: bool,
+        max_length: usize,
+    ) -> Result<bool, CommandError> {
+        let (byte, mask) = bit_position(offset);
+
+        if byte >= max_length {
+            return Err(CommandError::InvalidBitOffset);
+        }
+
+        let mut bytes = self
+            .get_kv_value(key)?
+            .map(|entry| entry.value.to_bytes())
+            .unwrap_or_default();
+
+        if bytes.len() <= byte {
+            bytes.resize(byte + 1, 0);
+        }
+
+        let previous = bytes[byte] & mask != 0;
+
+        if bit {
+            bytes[byte] |= mask;
         } else {
-            // Create new stream
-            self.data
-                .insert(key.clone(), StoreItem::Stream(Stream::empty()));
+            bytes[byte] &= !mask;
+        }
+
+        self.write_in_place(key, bytes);
+
+        Ok(previous)
+    }
+
+    pub fn get_bit(&self, key: &String, offset: u64) -> Result<bool, CommandError> {
+        let (byte, mask) = bit_position(offset);
+
+        let Some(entry) = self.get_kv_value(key)? else {
+            return Ok(false);
+        };
+
+        Ok(entry
+            .value
+            .to_bytes()
+            .get(byte)
+            .is_some_and(|value| value & mask != 0))
+    }
+
+    // Replaces the string at the key keeping its expiry, or creates it without one
+    fn write_in_place(&mut self, key: &String, bytes: Vec<u8>) {
+        let value = StringValue::Raw(bytes);
+
+        if let Err(value) = self.replace_live_value(key, value) {
+            self.set_kv_value(key.clone(), Entry::new(value, None));
+        }
+    }
+
+    // Swaps the value of a live string in place, keeping the memory estimate right. Hands the
+    // value back when there is no such string.
+    fn replace_live_value(&mut self, key: &String, value: StringValue) -> Result<(), StringValue> {
+        let Ok(Some(entry)) = self.get_mut_live_entry(key) else {
+            return Err(value);
+        };
+
+        let added = string_memory_usage(&value);
+        let removed = string_memory_usage(&std::mem::replace(&mut entry.value, value));
+        self.used_memory = self.used_memory + added - removed;
+
+        Ok(())
+    }
+
+    // Adds to the integer stored at the key, keeping its expiry. A missing key counts as 0.
+    pub fn incr_by(&mut self, key: &String, delta: i64) -> Result<i64, CommandError> {
+        let current = match self.get_kv_value(key)? {
+            Some(entry) => entry.value.as_int().ok_or(CommandError::NotAnInteger)?,
+            None => 0,
+        };
+
+        let value = current.checked_add(delta).ok_or(CommandError::Overflow)?;
+
+        if let Err(new_value) = self.replace_live_value(key, StringValue::Int(value)) {
+            self.set_kv_value(key.clone(), Entry::new(new_value, None));
+        }
+
+        Ok(value)
+    }
+
+    // What a key and its value take up, like MEMORY USAGE
+    pub fn memory_usage(&self, key: &String, samples: usize) -> Option<usize> {
+        let item = self.get_value(key)?;
+
+        Some(DICT_ENTRY_OVERHEAD + OBJECT_OVERHEAD + key.len() + item.memory_usage(samples))
+    }
+
+    // The estimate for every key, expired ones included since they are still held on to
+    pub fn dataset_memory_usage(&self) -> usize {
+        self.used_memory
+    }
+
+    pub fn encoding(&self, key: &String) -> Option<&'static str> {
+        match self.get_value(key)? {
+            StoreItem::KeyValueEntry(entry) => Some(entry.value.encoding()),
+            StoreItem::Stream(_) => Some("stream"),
+        }
+    }
+
+    // Returns whether the expiry was set, which needs the key to exist and every condition to hold
+    pub fn set_expiry(
+        &mut self,
+        key: &String,
+        expiry_at: SystemTime,
+        conditions: &[ExpireCondition],
+    ) -> bool {
+        let Some(item) = self.get_mut_live_item(key) else {
+            return false;
+        };
+
+        let current = item.expiry_at_mut();
+        if !conditions
+            .iter()
+            .all(|condition| condition.holds(*current, expiry_at))
+        {
+            return false;
+        }
+
+        *current = Some(expiry_at);
+        self.expiries.track(key, Some(expiry_at));
+
+        true
+    }
+
+    // Returns whether there was an expiry to remove
+    pub fn persist(&mut self, key: &String) -> bool {
+        let Some(item) = self.get_mut_live_item(key) else {
+            return false;
+        };
+
+        if item.expiry_at_mut().take().is_none() {
+            return false;
+        }
+
+        self.expiries.forget(key);
+
+        true
+    }
+
+    pub fn expiry_at(&self, key: &String) -> Option<SystemTime> {
+        self.get_value(key).and_then(|item| item.expiry_at())
+    }
+
+    fn get_mut_live_item(&mut self, key: &String) -> Option<&mut StoreItem> {
+        self.data
+            .get_mut(key)
+            .map(|value| &mut value.item)
+            .filter(|item| !item.is_expired())
+    }
+
+    fn get_mut_live_entry(&mut self, key: &String) -> Result<Option<&mut Entry>, CommandError> {
+        match self.get_mut_live_item(key) {
+            Some(StoreItem::KeyValueEntry(entry)) => Ok(Some(entry)),
+            Some(StoreItem::Stream(_)) => Err(CommandError::WrongType),
+            None => Ok(None),
+        }
+    }
 
-            self.get_mut_stream(key).unwrap()
-        };
+    // Serializes the value for RESTORE, None when the key doesn't exist
+    pub fn dump(&self, key: &String) -> Option<Vec<u8>> {
+        self.get_value(key).map(write_dump_payload)
+    }
 
-        let stream_id = StreamId::from(id);
+    pub fn restore(
+        &mut self,
+        key: String,
+        ttl: Option<Duration>,
+        payload: &[u8],
+        replace: bool,
+    ) -> Result<(), CommandError> {
+        if !replace && self.exists(&key) {
+            return Err(CommandError::BusyKey);
+        }
 
-        stream.entries.push((stream_id, stream_data));
+        let mut item = read_dump_payload(payload).ok_or(CommandError::BadDumpPayload)?;
+        *item.expiry_at_mut() = ttl.map(|ttl| SystemTime::now() + ttl);
+        self.insert_item(key, item);
 
         Ok(())
     }
 
-    pub fn get_kv_value(&self, key: &String) -> Option<&Entry> {
-        let store_entry = self.data.get(key)?;
+    // The copy is independent of the source and expires at the very same moment. Returns whether
+    // the key was copied, which needs the source to exist and the destination not to, unless
+    // it is replaced
+    pub fn copy(
+        &mut self,
+        source: &String,
+        destination: String,
+        replace: bool,
+    ) -> Result<bool, CommandError> {
+        if *source == destination {
+            return Err(CommandError::SameObject);
+        }
 
-        let key_val_entry = if let StoreItem::KeyValueEntry(key_val_entry) = store_entry {
-            key_val_entry
-        } else {
-            return None;
+        let Some(item) = self.get_value(source).cloned() else {
+            return Ok(false);
         };
 
-        if let Some(expiry_date_time) = key_val_entry.expiry_at {
-            if SystemTime::now() > expiry_date_time {
-                return None;
-            }
+        if !replace && self.exists(&destination) {
+            return Ok(false);
         }
 
-        Some(key_val_entry)
+        self.insert_item(destination, item);
+
+        Ok(true)
     }
 
+    // Keys past their expiry are gone as far as any reader is concerned, even while they wait
+    // for the active expiry to remove them
+    fn get_live(&self, key: &String) -> Option<&StoredValue> {
+        self.data.get(key).filter(|value| !value.item.is_expired())
+    }
+
+    // Looking at a value this way doesn't count as an access
     pub fn get_value(&self, key: &String) -> Option<&StoreItem> {
-        self.data.get(key)
+        self.get_live(key).map(|value| &value.item)
+    }
+
+    pub fn exists(&self, key: &String) -> bool {
+        self.get_live(key).is_some()
+    }
+
+    // Starts looking at a random spot of the key index, so any key can come up, though not all
+    // with the same chance
+    pub fn random_key(&self) -> Option<String> {
+        let start = RandomState::new().build_hasher().finish();
+
+        self.key_index
+            .range((start, String::new())..)
+            .chain(self.key_index.iter())
+            .find(|(_, key)| self.exists(key))
+            .map(|(_, key)| key.clone())
+    }
+
+    // How long ago the key was last accessed, None when it doesn't exist
+    pub fn idle_time(&self, key: &String) -> Option<Duration> {
+        self.get_live(key).map(StoredValue::idle_time)
+    }
+
+    pub fn frequency(&self, key: &String) -> Option<u8> {
+        self.get_live(key).map(StoredValue::frequency)
+    }
+
+    // The allkeys-lfu policy: removes keys until the estimated dataset fits in `maxmemory`, each
+    // time the least frequently used of a few sampled ones. Returns the evicted keys.
+    pub fn evict_least_frequently_used(&mut self, maxmemory: usize) -> Vec<String> {
+        let mut evicted = Vec::new();
+
+        while self.used_memory > maxmemory {
+            // Between equally frequent keys the one idle for longest goes first
+            let candidate = (0..EVICTION_SAMPLES)
+                .filter_map(|_| self.random_key())
+                .min_by_key(|key| {
+                    let value = &self.data[key];
+                    (value.frequency(), Reverse(value.idle_time()))
+                });
+
+            // Only expired keys are left, the active expiry takes care of those
+            let Some(key) = candidate else {
+                break;
+            };
+
+            self.remove_item(&key);
+            evicted.push(key);
+        }
+
+        evicted
     }
 
     pub fn get_mut_stream(&mut self, key: &String) -> Option<&mut Stream> {
-        let store_entry = self.data.get_mut(key)?;
+        let value = self
+            .data
+            .get_mut(key)
+            .filter(|value| !value.item.is_expired())?;
+        value.touch();
 
-        if let StoreItem::Stream(stream) = store_entry {
+        if let StoreItem::Stream(stream) = &mut value.item {
             Some(stream)
         } else {
             None
@@ -269,9 +1052,10 @@ impl Store {
     }
 
     pub fn get_stream(&self, key: &String) -> Option<&Stream> {
-        let store_entry = self.data.get(key)?;
+        let value = self.get_live(key)?;
 
-        if let StoreItem::Stream(stream) = store_entry {
+        if let StoreItem::Stream(stream) = &value.item {
+            value.touch();
             Some(stream)
         } else {
             None
@@ -291,7 +1075,7 @@ impl Store {
             read_entries.push((entry_id.clone(), entry_data.clone()));
         }
 
-        if read_entries.len() == 0 {
+        if read_entries.is_empty() {
             return None;
         }
 
@@ -330,18 +1114,125 @@ impl Store {
         Some(Stream::new(range_entries))
     }
 
+    // Removes every key, expiring or not
+    // The readers blocking on a stream keep waiting, for a stream that may come back
+    pub fn flush(&mut self) {
+        let stream_notifiers = std::mem::take(&mut self.stream_notifiers);
+
+        *self = Self {
+            stream_notifiers,
+            ..Self::default()
+        };
+    }
+
+    // Like FLUSHALL ASYNC, only swaps in an empty store and frees the old keys on a blocking task,
+    // so the lock isn't held while a large dataset is dropped
+    pub fn flush_async(&mut self) {
+        let stream_notifiers = std::mem::take(&mut self.stream_notifiers);
+        let old = std::mem::replace(
+            self,
+            Self {
+                stream_notifiers,
+                ..Self::default()
+            },
+        );
+
+        tokio::task::spawn_blocking(move || drop(old));
+    }
+
     pub fn len(&self) -> usize {
         self.data.len()
     }
 
-    pub fn import(&mut self, data: &Vec<u8>) {
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    // Makes room up front for the keys an rdb file announces
+    fn reserve(&mut self, sizes: &ResizeDb) {
+        self.data.reserve(sizes.keys);
+        self.expiries.generations.reserve(sizes.expires);
+    }
+
+    pub fn import(&mut self, data: &[u8]) {
         parse_rdb(self, data)
     }
+
+    pub fn export(&self) -> Vec<u8> {
+        write_rdb(self)
+    }
+}
+
+const ACTIVE_EXPIRE_INTERVAL: Duration = Duration::from_millis(100);
+const ACTIVE_EXPIRE_BATCH: usize = 1000;
+
+// Lazy expiry only hides keys, this sweep is what actually frees them and so where they are counted
+pub async fn active_expire(store: Arc<RwLock<Store>>, information: Arc<ServerInformation>) {
+    let mut ticker = interval(ACTIVE_EXPIRE_INTERVAL);
+
+    loop {
+        ticker.tick().await;
+
+        if !information.active_expire_enabled.load(Ordering::Relaxed) {
+            continue;
+        }
+
+        // Replicas only hide expired keys, they are removed by the DEL the master sends
+        if information.role != ReplicationRole::Master {
+            continue;
+        }
+
+        // Release the lock between batches, so a burst of expirations doesn't starve clients
+        loop {
+            let mut store = store.write().await;
+            let keys = store.remove_expired_keys(ACTIVE_EXPIRE_BATCH);
+
+            // Propagated before letting go of the store, like any other write
+            for key in &keys {
+                propagate_expired_key(&information, key).await;
+            }
+
+            drop(store);
+
+            let expired = keys.len();
+            information.stats.record_expired(expired);
+
+            if expired < ACTIVE_EXPIRE_BATCH {
+                break;
+            }
+
+            tokio::task::yield_now().await;
+        }
+    }
+}
+
+// Cursors start at 0, and 0 also marks the end of an iteration, so a key never hashes to 0
+fn key_hash(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+
+    hasher.finish().max(1)
+}
+
+// What a key adds to the dataset, like MEMORY USAGE measuring every entry
+fn key_memory_usage(key: &str, item: &StoreItem) -> usize {
+    DICT_ENTRY_OVERHEAD + OBJECT_OVERHEAD + key.len() + item.memory_usage(0)
+}
+
+fn string_memory_usage(value: &StringValue) -> usize {
+    match value {
+        // Small integers are shared objects in redis, but every int here is stored on its own
+        StringValue::Int(_) => OBJECT_OVERHEAD,
+        StringValue::Bytes(bytes) | StringValue::Raw(bytes) => OBJECT_OVERHEAD + bytes.len(),
+    }
+}
+
+fn stream_entry_memory_usage(data: &StreamData) -> usize {
+    STREAM_ENTRY_OVERHEAD + data.flatten().iter().map(String::len).sum::<usize>()
 }
 
-pub fn full_resync_rdb() -> Vec<u8> {
-    const EMPTY_RDB: &str = include_str!("empty_rdb.hex");
-    let content = decode_hex(EMPTY_RDB).unwrap_or_default();
+pub fn full_resync_rdb(store: &Store) -> Vec<u8> {
+    let content = store.export();
     let header = format!("${}\r\n", content.len()).as_bytes().to_vec();
 
     [header, content].concat()
@@ -353,11 +1244,7 @@ pub async fn read_rdb_from_file(information: &Arc<ServerInformation>) -> Option<
     let directory = config.dir.clone();
     let filename = config.dbfilename.clone();
 
-    let file_name = if let Some(filename) = filename {
-        filename
-    } else {
-        return None;
-    };
+    let file_name = filename?;
 
     let current_dir = env::current_dir()
         .unwrap()
@@ -388,155 +1275,644 @@ pub async fn read_rdb_from_file(information: &Arc<ServerInformation>) -> Option<
     Some(buffer)
 }
 
-fn parse_magic_number(data: &Vec<u8>, marker: &mut usize) -> bool {
+fn parse_magic_number(data: &[u8], marker: &mut usize) -> bool {
     let magic_number = b"REDIS";
 
     *marker += magic_number.len();
 
-    magic_number == &data[0..magic_number.len()]
+    data.get(..magic_number.len()) == Some(magic_number)
 }
 
-fn find_database_selector(data: &Vec<u8>, database: u8, marker: &mut usize) -> bool {
-    let start = *marker + 1;
+// Skips the version and the aux fields, each a 0xFA followed by a length prefixed name and value,
+// leaving the marker on the first opcode after them
+fn skip_aux_fields(data: &[u8], marker: &mut usize) -> bool {
+    // The 4 digits of the rdb version
+    *marker += 4;
 
-    for i in start..data.len() {
-        if data[i - 1] == 0xFE && data[i] == database {
-            *marker = i + 1;
-            return true;
+    while data.get(*marker) == Some(&0xFA) {
+        *marker += 1;
+
+        if read_length_prefixed_value(data, marker).is_none()
+            || read_length_prefixed_value(data, marker).is_none()
+        {
+            return false;
         }
     }
 
-    false
+    true
 }
 
-fn read_resizedb_field(data: &Vec<u8>, marker: &mut usize) -> bool {
-    if data[*marker] != 0xFB {
-        return false;
-    }
-
+// The 0xFE opcode and the database number it selects, followed by the optional resizedb hints
+fn read_database_selector(data: &[u8], marker: &mut usize) -> Option<(usize, ResizeDb)> {
     *marker += 1;
+    let database = read_length(data, marker)?;
+
+    let sizes = if data.get(*marker) == Some(&0xFB) {
+        read_resizedb_field(data, marker)?
+    } else {
+        ResizeDb::default()
+    };
 
-    // TODO: I don't know how to really parse the length of the hash table, so we hardcode it for now
-    *marker += 1; // Skip the 0 - 1 - 2
-    *marker += 1; // Skip the 0
+    Some((database, sizes))
+}
 
-    true
+// How many keys, and how many expiries, the database about to be read holds
+#[derive(Debug, Default)]
+struct ResizeDb {
+    keys: usize,
+    expires: usize,
 }
 
-fn read_length_prefixed_string(data: &Vec<u8>, marker: &mut usize) -> Option<String> {
-    let length = data[*marker] as usize;
+// Both sizes are full lengths, anything from 64 keys on takes more than a byte
+fn read_resizedb_field(data: &[u8], marker: &mut usize) -> Option<ResizeDb> {
     *marker += 1;
 
-    let start = *marker;
-    let end = start + length;
+    let keys = read_length(data, marker)?;
+    let expires = read_length(data, marker)?;
+
+    Some(ResizeDb { keys, expires })
+}
+
+fn read_bytes<const N: usize>(data: &[u8], marker: &mut usize) -> Option<[u8; N]> {
+    let bytes = data.get(*marker..*marker + N)?.try_into().ok()?;
+    *marker += N;
+
+    Some(bytes)
+}
+
+enum RdbLength {
+    Length(usize),
+    // The lower 6 bits of a length byte starting with 0b11, describing how a string is stored
+    Encoded(u8),
+}
+
+fn read_length_encoding(data: &[u8], marker: &mut usize) -> Option<RdbLength> {
+    let [first] = read_bytes::<1>(data, marker)?;
+
+    match first >> 6 {
+        0b00 => Some(RdbLength::Length((first & 0x3F) as usize)),
+        0b01 => {
+            let [next] = read_bytes::<1>(data, marker)?;
+            Some(RdbLength::Length(
+                (((first & 0x3F) as usize) << 8) | next as usize,
+            ))
+        }
+        0b10 => match first {
+            0x80 => Some(RdbLength::Length(
+                u32::from_be_bytes(read_bytes(data, marker)?) as usize,
+            )),
+            0x81 => Some(RdbLength::Length(
+                u64::from_be_bytes(read_bytes(data, marker)?) as usize,
+            )),
+            _ => None,
+        },
+        _ => Some(RdbLength::Encoded(first & 0x3F)),
+    }
+}
+
+fn read_length(data: &[u8], marker: &mut usize) -> Option<usize> {
+    match read_length_encoding(data, marker)? {
+        RdbLength::Length(length) => Some(length),
+        RdbLength::Encoded(_) => None,
+    }
+}
 
-    let slice: &[u8] = &data[start..end];
-    let value = std::str::from_utf8(slice).unwrap().to_string();
+fn read_length_prefixed_value(data: &[u8], marker: &mut usize) -> Option<StringValue> {
+    let value = match read_length_encoding(data, marker)? {
+        RdbLength::Length(length) => {
+            let slice = data.get(*marker..*marker + length)?;
+            *marker += length;
+
+            StringValue::from_bytes(slice.to_vec())
+        }
+        RdbLength::Encoded(0) => {
+            StringValue::Int(i8::from_le_bytes(read_bytes(data, marker)?) as i64)
+        }
+        RdbLength::Encoded(1) => {
+            StringValue::Int(i16::from_le_bytes(read_bytes(data, marker)?) as i64)
+        }
+        RdbLength::Encoded(2) => {
+            StringValue::Int(i32::from_le_bytes(read_bytes(data, marker)?) as i64)
+        }
+        RdbLength::Encoded(3) => {
+            let compressed_length = read_length(data, marker)?;
+            let length = read_length(data, marker)?;
+            let compressed = data.get(*marker..*marker + compressed_length)?;
+            *marker += compressed_length;
 
-    *marker = end;
+            StringValue::from_bytes(lzf_decompress(compressed, length)?)
+        }
+        RdbLength::Encoded(_) => return None,
+    };
 
     Some(value)
 }
 
-fn read_normal_string_value(data: &Vec<u8>, marker: &mut usize) -> Result<(String, String)> {
+fn read_length_prefixed_string(data: &[u8], marker: &mut usize) -> Option<String> {
+    String::from_utf8(read_length_prefixed_value(data, marker)?.to_bytes()).ok()
+}
+
+fn read_key_and_item(
+    data: &[u8],
+    marker: &mut usize,
+    value_type: u8,
+) -> Result<(String, StoreItem)> {
     let key = if let Some(key) = read_length_prefixed_string(data, marker) {
         key
     } else {
         bail!("Unable to read key from the entry");
     };
 
-    let value = if let Some(value) = read_length_prefixed_string(data, marker) {
-        value
+    Ok((key, read_item(data, marker, value_type)?))
+}
+
+// The value half of an entry, shared by the rdb files and the DUMP payloads
+fn read_item(data: &[u8], marker: &mut usize, value_type: u8) -> Result<StoreItem> {
+    let item = match value_type {
+        RDB_TYPE_STREAM_LISTPACKS | RDB_TYPE_STREAM_LISTPACKS_2 | RDB_TYPE_STREAM_LISTPACKS_3 => {
+            StoreItem::Stream(read_stream(data, marker, value_type)?)
+        }
+        _ => StoreItem::KeyValueEntry(Entry::new(read_object(data, marker, value_type)?, None)),
+    };
+
+    Ok(item)
+}
+
+fn read_object(data: &[u8], marker: &mut usize, value_type: u8) -> Result<StringValue> {
+    if value_type != RDB_TYPE_STRING {
+        bail!("Unsupported value");
+    }
+
+    if let Some(value) = read_length_prefixed_value(data, marker) {
+        Ok(value)
     } else {
         bail!("Unable to read value from the entry");
+    }
+}
+
+// A stream is a rax of listpack nodes, keyed by the id every entry of the node is relative to,
+// followed by its metadata and consumer groups
+fn read_stream(data: &[u8], marker: &mut usize, value_type: u8) -> Result<Stream> {
+    let Some(entries) = read_stream_nodes(data, marker) else {
+        bail!("Unable to read the entries of a stream");
     };
 
-    Ok((key, value))
-}
+    // The length, last id and, from version 2, the first id, max deleted id and entries added.
+    // All of them follow from the entries here
+    let metadata = if value_type == RDB_TYPE_STREAM_LISTPACKS {
+        3
+    } else {
+        8
+    };
 
-fn read_entry(data: &Vec<u8>, marker: &mut usize) -> Result<(String, Entry)> {
-    let mut offset = *marker;
+    for _ in 0..metadata {
+        if read_length(data, marker).is_none() {
+            bail!("Unable to read the metadata of a stream");
+        }
+    }
 
-    match data[offset] {
-        0xFC => {
-            offset += 1; // Skip the tag
-
-            let expiry_time = u64::from_le_bytes([
-                data[offset + 0],
-                data[offset + 1],
-                data[offset + 2],
-                data[offset + 3],
-                data[offset + 4],
-                data[offset + 5],
-                data[offset + 6],
-                data[offset + 7],
-            ]);
-
-            offset += 8;
-
-            let value_type = data[offset];
-            offset += 1;
-            if value_type != 0x00 {
-                bail!("Unsupported value");
-            }
+    let Some(groups) = skip_stream_consumer_groups(data, marker, value_type) else {
+        bail!("Unable to read the consumer groups of a stream");
+    };
+
+    if groups > 0 {
+        warn!("dropped the consumer groups of a stream"; groups = groups);
+    }
 
-            let (key, value) = read_normal_string_value(data, &mut offset)?;
+    Ok(Stream::new(entries))
+}
 
-            let expiry = UNIX_EPOCH + Duration::from_millis(expiry_time);
-            let current = SystemTime::now();
+// There are no consumer groups here, so they are read past to load the stream without them.
+// Returns how many there were.
+fn skip_stream_consumer_groups(data: &[u8], marker: &mut usize, value_type: u8) -> Option<usize> {
+    let groups = read_length(data, marker)?;
 
-            let duration = expiry
-                .duration_since(current)
-                .unwrap_or_else(|_| Duration::from_secs(0));
+    for _ in 0..groups {
+        // The name, the last delivered id and, from version 2, the number of entries read
+        read_length_prefixed_value(data, marker)?;
+        read_length(data, marker)?;
+        read_length(data, marker)?;
 
-            *marker = offset;
+        if value_type != RDB_TYPE_STREAM_LISTPACKS {
+            read_length(data, marker)?;
+        }
 
-            Ok((key, Entry::new(value, Some(duration))))
+        // The pending entries: a raw id, the delivery time and the delivery count
+        for _ in 0..read_length(data, marker)? {
+            read_bytes::<16>(data, marker)?;
+            read_bytes::<8>(data, marker)?;
+            read_length(data, marker)?;
         }
-        _ => {
-            let value_type = data[offset];
-            offset += 1;
 
-            if value_type != 0x00 {
-                bail!("Unsupported value");
+        // The consumers: the name, when they were last seen and, from version 3, last active,
+        // then the raw ids of the pending entries they own
+        for _ in 0..read_length(data, marker)? {
+            read_length_prefixed_value(data, marker)?;
+            read_bytes::<8>(data, marker)?;
+
+            if value_type == RDB_TYPE_STREAM_LISTPACKS_3 {
+                read_bytes::<8>(data, marker)?;
             }
 
-            let (key, value) = read_normal_string_value(data, &mut offset)?;
+            for _ in 0..read_length(data, marker)? {
+                read_bytes::<16>(data, marker)?;
+            }
+        }
+    }
+
+    Some(groups)
+}
+
+fn read_stream_nodes(data: &[u8], marker: &mut usize) -> Option<Vec<(StreamId, StreamData)>> {
+    let nodes = read_length(data, marker)?;
+    let mut entries = Vec::new();
+
+    for _ in 0..nodes {
+        // The master id as two big endian integers, so the rax sorts its keys by id
+        let key = read_length_prefixed_value(data, marker)?.to_bytes();
+        let mut offset = 0;
+        let ms = u64::from_be_bytes(read_bytes(&key, &mut offset)?);
+        let seq = u64::from_be_bytes(read_bytes(&key, &mut offset)?);
+
+        let node = read_length_prefixed_value(data, marker)?.to_bytes();
+        entries.extend(read_stream_node(
+            StreamId { ms, seq },
+            listpack::decode(&node)?,
+        )?);
+    }
+
+    Some(entries)
+}
+
+const STREAM_ITEM_FLAG_DELETED: i64 = 1 << 0;
+const STREAM_ITEM_FLAG_SAMEFIELDS: i64 = 1 << 1;
+
+// A node starts with a master entry: the number of live and deleted entries and the fields of
+// its first entry. Each entry then has its flags, its id relative to the master id, its fields
+// unless they are the master fields, its values and the number of elements before it
+fn read_stream_node(
+    master_id: StreamId,
+    elements: Vec<ListpackElement>,
+) -> Option<Vec<(StreamId, StreamData)>> {
+    let mut elements = elements.into_iter();
+    let next_int = |elements: &mut std::vec::IntoIter<ListpackElement>| elements.next()?.as_int();
+
+    let _count = next_int(&mut elements)?;
+    let _deleted = next_int(&mut elements)?;
+    let master_fields = (0..next_int(&mut elements)?)
+        .map(|_| elements.next().map(ListpackElement::into_bytes))
+        .collect::<Option<Vec<_>>>()?;
+
+    if next_int(&mut elements)? != 0 {
+        return None;
+    }
+
+    let mut entries = Vec::new();
+
+    while let Some(flags) = elements.next() {
+        let flags = flags.as_int()?;
+        let ms = master_id.ms.checked_add_signed(next_int(&mut elements)?)?;
+        let seq = master_id.seq.checked_add_signed(next_int(&mut elements)?)?;
+
+        let fields = if flags & STREAM_ITEM_FLAG_SAMEFIELDS != 0 {
+            master_fields.clone()
+        } else {
+            (0..next_int(&mut elements)?)
+                .map(|_| elements.next().map(ListpackElement::into_bytes))
+                .collect::<Option<Vec<_>>>()?
+        };
+
+        let mut data = Vec::with_capacity(fields.len());
+        for field in fields {
+            let value = elements.next()?.into_bytes();
+            data.push((
+                String::from_utf8(field).ok()?,
+                String::from_utf8(value).ok()?,
+            ));
+        }
 
-            *marker = offset;
+        next_int(&mut elements)?; // The element count, only needed to walk the node backwards
 
-            Ok((key, Entry::new(value, None)))
+        if flags & STREAM_ITEM_FLAG_DELETED == 0 {
+            entries.push((StreamId { ms, seq }, StreamData { data }));
         }
     }
+
+    Some(entries)
+}
+
+fn read_entry(data: &[u8], marker: &mut usize) -> Result<(String, StoreItem)> {
+    let mut offset = *marker;
+
+    let expiry_time = if data.get(offset) == Some(&0xFC) {
+        offset += 1; // Skip the tag
+
+        let Some(expiry_time) = read_bytes(data, &mut offset) else {
+            bail!("Unable to read the expiry of the entry");
+        };
+
+        Some(u64::from_le_bytes(expiry_time))
+    } else {
+        None
+    };
+
+    let Some([value_type]) = read_bytes::<1>(data, &mut offset) else {
+        bail!("Unable to read the type of the entry");
+    };
+
+    let (key, mut item) = read_key_and_item(data, &mut offset, value_type)?;
+
+    if let Some(expiry_time) = expiry_time {
+        let expiry = UNIX_EPOCH + Duration::from_millis(expiry_time);
+        let current = SystemTime::now();
+
+        let duration = expiry
+            .duration_since(current)
+            .unwrap_or_else(|_| Duration::from_secs(0));
+
+        *item.expiry_at_mut() = Some(current + duration);
+    }
+
+    *marker = offset;
+
+    Ok((key, item))
 }
 
-fn parse_rdb(store: &mut Store, data: &Vec<u8>) {
+fn parse_rdb(store: &mut Store, data: &[u8]) {
     let mut marker = 0;
 
     if !parse_magic_number(data, &mut marker) {
         return;
     }
-    if !find_database_selector(data, 0x00, &mut marker) {
-        return;
-    }
-    if !read_resizedb_field(data, &mut marker) {
+    if !skip_aux_fields(data, &mut marker) {
+        warn!("unable to read the aux fields of the rdb file"; offset = marker);
         return;
     }
 
-    while data[marker] != 0xFF {
+    let mut database = 0;
+    let mut loaded = 0;
+    let mut skipped = 0;
+
+    while data.get(marker).is_some_and(|x| *x != 0xFF) {
+        if data[marker] == 0xFE {
+            match read_database_selector(data, &mut marker) {
+                Some((selected, sizes)) => {
+                    database = selected;
+
+                    if database == 0 {
+                        store.reserve(&sizes);
+                    } else {
+                        warn!("skipping a database other than 0"; database = database, keys = sizes.keys);
+                    }
+                }
+                None => {
+                    warn!("unable to read a database selector of the rdb file"; offset = marker);
+                    break;
+                }
+            }
+
+            continue;
+        }
+
         let result = read_entry(data, &mut marker);
 
-        if let Ok((key, entry)) = result {
-            println!("loaded {}", &key);
-            store.set_kv_value(key, entry);
+        if let Ok((key, item)) = result {
+            // There is only a single database, the keys of the others are read past but dropped
+            if database != 0 {
+                skipped += 1;
+                continue;
+            }
+
+            trace!("loaded key"; key = key);
+            store.insert_item(key, item);
+            loaded += 1;
+        } else {
+            // We can't tell where the unreadable entry ends, so the rest of the file is lost
+            warn!("unable to read the rest of the rdb file"; offset = marker);
+            break;
+        }
+    }
+
+    if skipped > 0 {
+        warn!("dropped the keys of databases other than 0"; keys = skipped);
+    }
+
+    info!("rdb loaded"; keys = loaded, bytes = data.len());
+}
+
+const RDB_HEADER: &[u8] = b"REDIS0011";
+const RDB_VERSION: u16 = 11;
+const RDB_TYPE_STRING: u8 = 0x00;
+const RDB_TYPE_STREAM_LISTPACKS: u8 = 0x0F;
+const RDB_TYPE_STREAM_LISTPACKS_2: u8 = 0x13;
+const RDB_TYPE_STREAM_LISTPACKS_3: u8 = 0x15;
+
+fn write_rdb(store: &Store) -> Vec<u8> {
+    let mut data = RDB_HEADER.to_vec();
+
+    let items: Vec<(&String, &StoreItem)> = store
+        .data
+        .iter()
+        .filter(|(_, value)| !value.item.is_expired())
+        .map(|(key, value)| (key, &value.item))
+        .collect();
+
+    let expires = items
+        .iter()
+        .filter(|(_, item)| item.expiry_at().is_some())
+        .count();
+
+    data.extend([0xFE, 0x00, 0xFB]);
+    write_length(&mut data, items.len());
+    write_length(&mut data, expires);
+
+    for (key, item) in items {
+        if let Some(expiry_at) = item.expiry_at() {
+            let expiry_time = expiry_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64;
+
+            data.push(0xFC);
+            data.extend(expiry_time.to_le_bytes());
+        }
+
+        data.push(rdb_type(item));
+        write_string(&mut data, key);
+        write_item(&mut data, item);
+    }
+
+    data.push(0xFF);
+    data.extend([0; 8]); // A zeroed checksum tells the reader checksums are disabled
+
+    data
+}
+
+fn rdb_type(item: &StoreItem) -> u8 {
+    match item {
+        StoreItem::KeyValueEntry(_) => RDB_TYPE_STRING,
+        StoreItem::Stream(_) => RDB_TYPE_STREAM_LISTPACKS_3,
+    }
+}
+
+fn write_item(data: &mut Vec<u8>, item: &StoreItem) {
+    match item {
+        StoreItem::KeyValueEntry(entry) => write_object(data, entry),
+        StoreItem::Stream(stream) => write_stream(data, stream),
+    }
+}
+
+fn write_object(data: &mut Vec<u8>, entry: &Entry) {
+    match entry.value {
+        // The integer encodings, a length byte of 0b11 followed by the size of the integer
+        StringValue::Int(value) if i8::try_from(value).is_ok() => {
+            data.push(0xC0);
+            data.extend((value as i8).to_le_bytes());
+        }
+        StringValue::Int(value) if i16::try_from(value).is_ok() => {
+            data.push(0xC1);
+            data.extend((value as i16).to_le_bytes());
+        }
+        StringValue::Int(value) if i32::try_from(value).is_ok() => {
+            data.push(0xC2);
+            data.extend((value as i32).to_le_bytes());
+        }
+        _ => write_bytes(data, &entry.value.to_bytes()),
+    }
+}
+
+fn write_stream(data: &mut Vec<u8>, stream: &Stream) {
+    let nodes: Vec<_> = stream.entries.chunks(STREAM_NODE_MAX_ENTRIES).collect();
+
+    write_length(data, nodes.len());
+
+    for node in nodes {
+        let master_id = &node[0].0;
+        let mut key = master_id.ms.to_be_bytes().to_vec();
+        key.extend(master_id.seq.to_be_bytes());
+
+        write_bytes(data, &key);
+        write_bytes(data, &listpack::encode(&stream_node_elements(node)));
+    }
+
+    let zero = StreamId { ms: 0, seq: 0 };
+    let first_id = stream.entries.first().map_or(&zero, |(id, _)| id);
+    let last_id = stream.entries.last().map_or(&zero, |(id, _)| id);
+
+    write_length(data, stream.entries.len());
+    for id in [last_id, first_id, &zero] {
+        write_length(data, id.ms as usize);
+        write_length(data, id.seq as usize);
+    }
+    // Entries are never deleted, so every one added is still there
+    write_length(data, stream.entries.len());
+    write_length(data, 0); // No consumer groups
+}
+
+// The inverse of read_stream_node, the fields of the first entry become the master fields
+fn stream_node_elements(node: &[(StreamId, StreamData)]) -> Vec<ListpackElement> {
+    let (master_id, master_entry) = &node[0];
+    let bytes = |value: &String| ListpackElement::Bytes(value.as_bytes().to_vec());
+
+    let mut elements = vec![
+        ListpackElement::Int(node.len() as i64),
+        ListpackElement::Int(0),
+        ListpackElement::Int(master_entry.data.len() as i64),
+    ];
+    elements.extend(master_entry.data.iter().map(|(field, _)| bytes(field)));
+    elements.push(ListpackElement::Int(0));
+
+    for (id, entry) in node {
+        let same_fields = entry.data.len() == master_entry.data.len()
+            && entry
+                .data
+                .iter()
+                .zip(&master_entry.data)
+                .all(|((field, _), (master_field, _))| field == master_field);
+        let fields = entry.data.len() as i64;
+
+        elements.push(ListpackElement::Int(if same_fields {
+            STREAM_ITEM_FLAG_SAMEFIELDS
+        } else {
+            0
+        }));
+        elements.push(ListpackElement::Int(id.ms.wrapping_sub(master_id.ms) as i64));
+        elements.push(ListpackElement::Int(
+            id.seq.wrapping_sub(master_id.seq) as i64
+        ));
+
+        if same_fields {
+            elements.extend(entry.data.iter().map(|(_, value)| bytes(value)));
+            elements.push(ListpackElement::Int(fields + 3));
+        } else {
+            elements.push(ListpackElement::Int(fields));
+            for (field, value) in &entry.data {
+                elements.push(bytes(field));
+                elements.push(bytes(value));
+            }
+            elements.push(ListpackElement::Int(2 * fields + 4));
         }
     }
+
+    elements
+}
+
+// A DUMP payload is the type and value of an entry, followed by the rdb version and a crc64 of
+// everything before it, both little endian
+fn write_dump_payload(item: &StoreItem) -> Vec<u8> {
+    let mut data = vec![rdb_type(item)];
+
+    write_item(&mut data, item);
+    data.extend(RDB_VERSION.to_le_bytes());
+    data.extend(crc64(&data).to_le_bytes());
+
+    data
+}
+
+// None when the payload is damaged or was made by a newer rdb version
+fn read_dump_payload(payload: &[u8]) -> Option<StoreItem> {
+    let (body, checksum) = payload.split_last_chunk::<8>()?;
+    let (object, version) = body.split_last_chunk::<2>()?;
+
+    if u16::from_le_bytes(*version) > RDB_VERSION || u64::from_le_bytes(*checksum) != crc64(body) {
+        return None;
+    }
+
+    let (&value_type, object) = object.split_first()?;
+    let mut marker = 0;
+    let item = read_item(object, &mut marker, value_type).ok()?;
+
+    // Trailing garbage means the payload wasn't made by DUMP
+    (marker == object.len()).then_some(item)
+}
+
+fn write_length(data: &mut Vec<u8>, length: usize) {
+    if length < 1 << 6 {
+        data.push(length as u8);
+    } else if length < 1 << 14 {
+        data.extend([0x40 | (length >> 8) as u8, length as u8]);
+    } else if length <= u32::MAX as usize {
+        data.push(0x80);
+        data.extend((length as u32).to_be_bytes());
+    } else {
+        data.push(0x81);
+        data.extend((length as u64).to_be_bytes());
+    }
+}
+
+fn write_string(data: &mut Vec<u8>, value: &str) {
+    write_bytes(data, value.as_bytes());
+}
+
+fn write_bytes(data: &mut Vec<u8>, value: &[u8]) {
+    write_length(data, value.len());
+    data.extend(value);
 }
 
 fn build_stream_id(pattern: &String, last_stream_entry: Option<&StreamId>) -> Option<String> {
     let pattern = if pattern.len() < 3 { "*-*" } else { pattern };
 
-    let (cur_id_ms, cur_id_seq) = pattern.split_once('-').unwrap();
+    let (cur_id_ms, cur_id_seq) = pattern.split_once('-')?;
 
     let mut id_ms: String = cur_id_ms.to_string();
     let mut id_seq: String = cur_id_seq.to_string();
@@ -550,15 +1926,7 @@ fn build_stream_id(pattern: &String, last_stream_entry: Option<&StreamId>) -> Op
         id_ms = time_since_unix_time.as_millis().to_string();
     }
 
-    let relevant_stream_entry = if let Some(last_id) = last_stream_entry {
-        if last_id.ms.to_string() == id_ms {
-            Some(last_id)
-        } else {
-            None
-        }
-    } else {
-        None
-    };
+    let relevant_stream_entry = last_stream_entry.filter(|last_id| last_id.ms.to_string() == id_ms);
 
     let auto_generate_seq = cur_id_seq == "*";
 
@@ -577,29 +1945,32 @@ fn build_stream_id(pattern: &String, last_stream_entry: Option<&StreamId>) -> Op
         }
     }
 
+    // Anything that isn't a number by now was never a valid id
+    let id_ms = id_ms.parse::<u64>().ok()?;
+    let id_seq = id_seq.parse::<u64>().ok()?;
+
     Some(format!("{}-{}", id_ms, id_seq))
 }
 
-pub fn get_start_of_xrange_id(id: &String) -> Option<StreamId> {
+pub fn get_start_of_xrange_id(id: &String) -> Result<Option<StreamId>, CommandError> {
     if id == "-" {
-        return None;
+        return Ok(None);
     }
 
-    let (ms, seq) = if let Some(split) = id.split_once('-') {
-        split
+    if id.contains('-') {
+        id.parse().map(Some)
     } else {
-        (id.as_str(), "0")
-    };
-
-    let ms = ms.parse::<u64>().expect("Unable to parse ms");
-    let seq = seq.parse::<u64>().expect("Unable to parse seq");
-
-    Some(StreamId { ms, seq })
+        format!("{}-0", id).parse().map(Some)
+    }
 }
 
-pub fn get_end_of_xrange_id(id: &String, key: &String, store: &Store) -> Option<StreamId> {
+pub fn get_end_of_xrange_id(
+    id: &String,
+    key: &String,
+    store: &Store,
+) -> Result<Option<StreamId>, CommandError> {
     if id == "+" {
-        return None;
+        return Ok(None);
     }
 
     let template = if id.contains("-") {
@@ -608,7 +1979,9 @@ pub fn get_end_of_xrange_id(id: &String, key: &String, store: &Store) -> Option<
         format!("{}-*", id)
     };
 
-    let id = store.auto_generate_stream_id(key, &template)?;
+    let id = store
+        .auto_generate_stream_id(key, &template)
+        .ok_or(CommandError::InvalidStreamId)?;
 
-    Some(StreamId::from(&id))
+    id.parse().map(Some)
 }
@@ -1,8 +1,325 @@
-use std::num::ParseIntError;
+// Port of redis' stringmatchlen, supporting *, ?, [...] classes with ranges and negation, and
+// backslash escapes
+pub fn glob_match(pattern: &str, string: &str) -> bool {
+    let mut skip_longer_matches = false;
 
-pub fn decode_hex(s: &str) -> Result<Vec<u8>, ParseIntError> {
-    (0..s.len())
-        .step_by(2)
-        .map(|i| u8::from_str_radix(&s[i..i + 2], 16))
-        .collect()
+    glob_match_bytes(
+        pattern.as_bytes(),
+        string.as_bytes(),
+        &mut skip_longer_matches,
+        0,
+    )
+}
+
+// Deeper than this a pattern is taken as abusive and doesn't match, like in redis
+const MAX_GLOB_NESTING: usize = 1000;
+
+// Once the rest of the pattern after a * matched nowhere in the rest of the string, no earlier *
+// can help by matching more, so `skip_longer_matches` stops every level from trying (CVE-2022-36021)
+fn glob_match_bytes(
+    pattern: &[u8],
+    string: &[u8],
+    skip_longer_matches: &mut bool,
+    nesting: usize,
+) -> bool {
+    if nesting > MAX_GLOB_NESTING {
+        return false;
+    }
+
+    let mut p = 0;
+    let mut s = 0;
+
+    while p < pattern.len() && s < string.len() {
+        match pattern[p] {
+            b'*' => {
+                while p + 1 < pattern.len() && pattern[p + 1] == b'*' {
+                    p += 1;
+                }
+
+                if p + 1 == pattern.len() {
+                    return true;
+                }
+
+                while s < string.len() {
+                    let rest = &pattern[p + 1..];
+
+                    if glob_match_bytes(rest, &string[s..], skip_longer_matches, nesting + 1) {
+                        return true;
+                    }
+
+                    if *skip_longer_matches {
+                        return false;
+                    }
+
+                    s += 1;
+                }
+
+                *skip_longer_matches = true;
+                return false;
+            }
+            b'?' => s += 1,
+            b'[' => {
+                p += 1;
+
+                let negate = pattern.get(p) == Some(&b'^');
+                if negate {
+                    p += 1;
+                }
+
+                let mut matched = false;
+
+                loop {
+                    if p >= pattern.len() {
+                        // Unterminated class, like redis we treat the end of the pattern as its end
+                        p -= 1;
+                        break;
+                    }
+
+                    match pattern[p] {
+                        b'\\' if p + 1 < pattern.len() => {
+                            p += 1;
+                            matched |= pattern[p] == string[s];
+                        }
+                        b']' => break,
+                        start if p + 2 < pattern.len() && pattern[p + 1] == b'-' => {
+                            let end = pattern[p + 2];
+                            let (low, high) = if start > end {
+                                (end, start)
+                            } else {
+                                (start, end)
+                            };
+
+                            p += 2;
+                            matched |= (low..=high).contains(&string[s]);
+                        }
+                        c => matched |= c == string[s],
+                    }
+
+                    p += 1;
+                }
+
+                if negate {
+                    matched = !matched;
+                }
+
+                if !matched {
+                    return false;
+                }
+
+                s += 1;
+            }
+            b'\\' if p + 1 < pattern.len() => {
+                p += 1;
+
+                if pattern[p] != string[s] {
+                    return false;
+                }
+
+                s += 1;
+            }
+            c => {
+                if c != string[s] {
+                    return false;
+                }
+
+                s += 1;
+            }
+        }
+
+        p += 1;
+
+        if s == string.len() {
+            while p < pattern.len() && pattern[p] == b'*' {
+                p += 1;
+            }
+
+            break;
+        }
+    }
+
+    p == pattern.len() && s == string.len()
+}
+
+// Reflected form of the Jones polynomial redis uses for its crc64
+const CRC64_POLY: u64 = 0x95ac_9329_ac4b_c9b5;
+
+// Bit at a time rather than table driven, the payloads it checks are small
+pub fn crc64(data: &[u8]) -> u64 {
+    let mut crc = 0;
+
+    for &byte in data {
+        crc ^= byte as u64;
+
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ CRC64_POLY
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    crc
+}
+
+// CRC16-CCITT (XMODEM), the checksum redis cluster maps keys to slots with
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
+
+pub const CLUSTER_SLOTS: u16 = 16384;
+
+// The part of the key between the first '{' and the next '}', when it isn't empty. Keys sharing
+// a hash tag always end up in the same slot.
+pub fn hash_tag(key: &[u8]) -> &[u8] {
+    let Some(start) = key.iter().position(|&byte| byte == b'{') else {
+        return key;
+    };
+
+    match key[start + 1..].iter().position(|&byte| byte == b'}') {
+        Some(0) | None => key,
+        Some(length) => &key[start + 1..start + 1 + length],
+    }
+}
+
+pub fn key_hash_slot(key: &[u8]) -> u16 {
+    crc16(hash_tag(key)) % CLUSTER_SLOTS
+}
+
+// Decompresses the LZF strings redis writes into rdb files when they're worth compressing. None
+// when the data is damaged or doesn't decompress to `length` bytes
+pub fn lzf_decompress(data: &[u8], length: usize) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(length);
+    let mut marker = 0;
+
+    while marker < data.len() {
+        let control = data[marker] as usize;
+        marker += 1;
+
+        if control < 1 << 5 {
+            // A run of control + 1 literal bytes
+            let literal = data.get(marker..marker + control + 1)?;
+            output.extend(literal);
+            marker += control + 1;
+        } else {
+            // A back reference, its length in the top 3 bits and possibly an extra byte
+            let mut run = control >> 5;
+            if run == 7 {
+                run += *data.get(marker)? as usize;
+                marker += 1;
+            }
+
+            let offset = ((control & 0x1F) << 8) + *data.get(marker)? as usize + 1;
+            marker += 1;
+
+            let start = output.len().checked_sub(offset)?;
+
+            // Byte by byte, the reference may overlap what it is copying
+            for index in start..start + run + 2 {
+                output.push(output[index]);
+            }
+        }
+    }
+
+    (output.len() == length).then_some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::glob_match;
+
+    // Mirrors the cases redis checks stringmatchlen with
+    #[test]
+    fn glob_match_follows_stringmatchlen() {
+        let cases = [
+            ("", "", true),
+            ("", "a", false),
+            ("*", "anything", true),
+            ("**a", "a", true),
+            ("a*", "abc", true),
+            ("a*", "bac", false),
+            ("*c", "abc", true),
+            ("a*b*c", "axxbyyc", true),
+            ("a*b*c", "axxbyy", false),
+            ("a?c", "abc", true),
+            ("a?c", "ac", false),
+            ("h[ae]llo", "hello", true),
+            ("h[ae]llo", "hallo", true),
+            ("h[ae]llo", "hillo", false),
+            ("h[^e]llo", "hallo", true),
+            ("h[^e]llo", "hello", false),
+            ("h[a-c]llo", "hbllo", true),
+            ("h[a-c]llo", "hdllo", false),
+            ("h[c-a]llo", "hbllo", true),
+            ("h[^a-c]llo", "hdllo", true),
+            ("h[^a-c]llo", "hbllo", false),
+            ("h\\*llo", "h*llo", true),
+            ("h\\*llo", "hello", false),
+            ("h\\?llo", "hello", false),
+            ("[\\]]", "]", true),
+            ("[\\-]", "-", true),
+            ("[\\-]", "a", false),
+            // Without a closing bracket the class runs to the end of the pattern
+            ("[abc", "a", true),
+            ("[abc", "c", true),
+            ("[abc", "d", false),
+            ("[abc", "ab", false),
+            ("x[^ab", "xc", true),
+            // A trailing backslash has nothing to escape and is matched literally
+            ("a\\", "a\\", true),
+            ("a\\", "a", false),
+            ("\\", "\\", true),
+        ];
+
+        for (pattern, string, expected) in cases {
+            assert_eq!(
+                glob_match(pattern, string),
+                expected,
+                "{:?} against {:?}",
+                pattern,
+                string
+            );
+        }
+    }
+
+    // Patterns that took exponential time before CVE-2022-36021 was fixed, and ones nested deeper
+    // than redis allows
+    #[test]
+    fn glob_match_gives_up_on_abusive_patterns() {
+        let long = "a".repeat(50_000);
+        let cases = [
+            ("*a*a*a*a*a*a*a*a*a*a*a*b".to_string(), long.clone(), false),
+            ("*a".repeat(12) + "*", long.clone(), true),
+            ("*a".repeat(1000), "a".repeat(1000), true),
+            ("*a".repeat(1001), "a".repeat(1001), false),
+        ];
+
+        let started = Instant::now();
+
+        for (pattern, string, expected) in &cases {
+            assert_eq!(glob_match(pattern, string), *expected, "{:?}", pattern);
+        }
+
+        assert!(
+            started.elapsed() < Duration::from_secs(1),
+            "{:?}",
+            started.elapsed()
+        );
+    }
 }
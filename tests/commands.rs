@@ -0,0 +1,235 @@
+mod common;
+
+use bytes::BytesMut;
+use common::{request, TestServer};
+use redis_starter_rust::{
+    all_command_names, parse_client_command, Command, CommandError, ExpireCondition, Message,
+    SETParams, SetCondition, StringValue,
+};
+
+fn parse_set(args: &[&str]) -> SETParams {
+    match parse_client_command(&request(args)) {
+        Ok(Command::Set(params)) => params,
+        result => panic!("{:?} didn't parse as SET: {:?}", args, result),
+    }
+}
+
+#[test]
+fn set_options_as_clients_send_them() {
+    // redis-py: set("key", "value", px=100, nx=True, get=True)
+    let params = parse_set(&["SET", "key", "value", "PX", "100", "NX", "GET"]);
+    assert_eq!(params.key, "key");
+    assert_eq!(params.entry.value, StringValue::Bytes(b"value".to_vec()));
+    assert!(params.entry.expiry_at.is_some());
+    assert_eq!(params.condition, Some(SetCondition::NotExists));
+    assert!(params.get);
+
+    // lettuce: set("key", "value", SetArgs.Builder.ex(10).xx())
+    let params = parse_set(&["SET", "key", "value", "EX", "10", "XX"]);
+    assert!(params.entry.expiry_at.is_some());
+    assert_eq!(params.condition, Some(SetCondition::Exists));
+
+    // lettuce: set("key", "value", SetArgs.Builder.nx().keepttl())
+    let params = parse_set(&["SET", "key", "value", "NX", "KEEPTTL"]);
+    assert!(params.keep_ttl);
+    assert!(params.entry.expiry_at.is_none());
+
+    // Order and case don't matter
+    let params = parse_set(&["set", "key", "value", "nx", "px", "100"]);
+    assert!(params.entry.expiry_at.is_some());
+    assert_eq!(params.condition, Some(SetCondition::NotExists));
+
+    let params = parse_set(&["SET", "key", "value", "PXAT", "1700000000000"]);
+    assert!(params.entry.expiry_at.is_some());
+}
+
+#[test]
+fn set_rejects_duplicate_and_conflicting_options() {
+    for args in [
+        &["SET", "key", "value", "NX", "XX"][..],
+        &["SET", "key", "value", "NX", "NX"],
+        &["SET", "key", "value", "EX", "10", "PX", "100"],
+        &["SET", "key", "value", "EX", "10", "KEEPTTL"],
+        &["SET", "key", "value", "GET", "GET"],
+        &["SET", "key", "value", "PX"],
+        &["SET", "key", "value", "NOPE"],
+    ] {
+        assert_eq!(
+            parse_client_command(&request(args)).unwrap_err(),
+            CommandError::SyntaxError,
+            "{:?}",
+            args
+        );
+    }
+}
+
+#[test]
+fn expire_flags_parse_and_conflict_like_redis() {
+    let condition = |args: &[&str]| match parse_client_command(&request(args)) {
+        Ok(Command::Expire(_, _, condition)) => Ok(condition),
+        Ok(command) => panic!("{:?} didn't parse as EXPIRE: {:?}", args, command),
+        Err(err) => Err(err.to_string()),
+    };
+
+    assert_eq!(condition(&["EXPIRE", "key", "10"]), Ok(vec![]));
+    assert_eq!(
+        condition(&["PEXPIRE", "key", "10", "nx"]),
+        Ok(vec![ExpireCondition::NotExists])
+    );
+    assert_eq!(
+        condition(&["EXPIREAT", "key", "10", "XX", "GT"]),
+        Ok(vec![ExpireCondition::Exists, ExpireCondition::GreaterThan])
+    );
+    assert_eq!(
+        condition(&["EXPIRE", "key", "10", "LT", "XX"]),
+        Ok(vec![ExpireCondition::Exists, ExpireCondition::LessThan])
+    );
+    assert_eq!(
+        condition(&["EXPIRE", "key", "10", "NX", "GT"]),
+        Err("ERR NX and XX, GT or LT options at the same time are not compatible".to_string())
+    );
+    assert_eq!(
+        condition(&["EXPIRE", "key", "10", "GT", "LT"]),
+        Err("ERR GT and LT options at the same time are not compatible".to_string())
+    );
+    assert_eq!(
+        condition(&["EXPIRE", "key", "10", "SOON"]),
+        Err("ERR Unsupported option SOON".to_string())
+    );
+}
+
+#[test]
+fn every_command_in_the_table_parses() {
+    for name in all_command_names() {
+        // Enough dummy arguments to get past any arity check
+        let mut args = vec![name];
+        args.extend(["0"; 6]);
+
+        if let Err(CommandError::UnknownCommand { .. }) = parse_client_command(&request(&args)) {
+            panic!(
+                "{} is in the command table, but not known to the parser",
+                name
+            );
+        }
+    }
+}
+
+#[tokio::test]
+async fn command_count_matches_the_table() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    assert_eq!(
+        client.command(&["COMMAND", "COUNT"]).await,
+        Message::Integer(all_command_names().count() as isize)
+    );
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn command_list_filters_by_pattern() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    let names = |reply: Message| match reply {
+        Message::Array(names) => names
+            .into_iter()
+            .map(|name| match name {
+                Message::BulkString(name) => name,
+                name => panic!("unexpected command name {:?}", name),
+            })
+            .collect::<Vec<_>>(),
+        reply => panic!("unexpected COMMAND LIST reply {:?}", reply),
+    };
+
+    let mut get_family = names(
+        client
+            .command(&["COMMAND", "LIST", "FILTERBY", "PATTERN", "get*"])
+            .await,
+    );
+    get_family.sort();
+    assert_eq!(get_family, ["get", "getbit", "getex", "getrange"]);
+
+    assert_eq!(
+        names(client.command(&["COMMAND", "LIST"]).await).len(),
+        all_command_names().count()
+    );
+    assert!(names(
+        client
+            .command(&["COMMAND", "LIST", "FILTERBY", "ACLCAT", "write"])
+            .await
+    )
+    .contains(&"set".to_string()));
+    assert_eq!(
+        client
+            .command(&["COMMAND", "LIST", "FILTERBY", "NOPE", "x"])
+            .await,
+        Message::Error("ERR syntax error".to_string())
+    );
+
+    server.stop().await;
+}
+
+#[test]
+fn null_replies_round_trip() {
+    for (message, encoded) in [
+        (Message::Null, "$-1\r\n"),
+        (Message::NullArray, "*-1\r\n"),
+        (
+            Message::Array(vec![Message::NullArray, Message::Null]),
+            "*2\r\n*-1\r\n$-1\r\n",
+        ),
+    ] {
+        assert_eq!(message.serialize().unwrap(), encoded);
+        assert_eq!(
+            Message::parse(encoded.as_bytes()).unwrap(),
+            (message, encoded.len())
+        );
+    }
+}
+
+// The format! based serializer write_to replaced, for the messages it knew about. Its bulk lengths
+// were char counts, which only match the byte length for ascii.
+fn format_serialize(message: &Message) -> String {
+    match message {
+        Message::Error(s) => format!("-{}\r\n", s),
+        Message::SimpleString(s) => format!("+{}\r\n", s),
+        Message::BulkString(s) => format!("${}\r\n{}\r\n", s.chars().count(), s),
+        Message::Array(items) => {
+            let parts: Vec<String> = items.iter().map(format_serialize).collect();
+            format!("*{}\r\n{}", parts.len(), parts.join(""))
+        }
+        Message::Integer(value) => format!(":{}\r\n", value),
+        Message::Null => "$-1\r\n".to_string(),
+        message => panic!("{:?} didn't exist yet", message),
+    }
+}
+
+#[test]
+fn write_to_matches_the_format_serializer() {
+    // Like an XRANGE reply of entries holding replies of their own, nested a few levels deep
+    let mut message = Message::Array(vec![]);
+
+    for depth in 0..8 {
+        message = Message::Array(vec![
+            Message::BulkString(format!("{}-0", depth)),
+            Message::Array(vec![
+                Message::BulkString("field".to_string()),
+                Message::BulkString(String::new()),
+                Message::Integer(-(depth as isize)),
+                Message::Null,
+            ]),
+            Message::SimpleString("OK".to_string()),
+            Message::Error("ERR nope".to_string()),
+            message,
+            Message::Array(vec![]),
+        ]);
+    }
+
+    let mut buffer = BytesMut::new();
+    message.write_to(&mut buffer);
+
+    assert_eq!(buffer, format_serialize(&message).as_bytes());
+    assert_eq!(message.serialize().unwrap(), format_serialize(&message));
+}
@@ -0,0 +1,179 @@
+#![allow(dead_code)]
+
+use std::{path::Path, sync::Arc, time::Duration};
+
+use bytes::{Buf, BytesMut};
+use redis_starter_rust::{CommandLineArgs, Message, Server};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpStream, UnixStream},
+    task::JoinHandle,
+    time::{sleep, timeout, Instant},
+};
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct TestServer {
+    pub port: u16,
+    pub server: Arc<Server>,
+    handle: JoinHandle<()>,
+}
+
+impl TestServer {
+    pub async fn start(args: &[&str]) -> Self {
+        // Every server gets an ephemeral port, so the tests can run in parallel
+        let mut argv = vec!["redis", "--port", "0"];
+        argv.extend_from_slice(args);
+
+        let argv = argv.into_iter().map(str::to_string).collect();
+        let config = CommandLineArgs::load(argv).expect("unable to load the config");
+        let server = Arc::new(Server::bind(config).await.expect("unable to bind"));
+        let port = server.local_addrs()[0].port();
+
+        let handle = tokio::spawn({
+            let server = server.clone();
+
+            async move { server.run().await.expect("server failed") }
+        });
+
+        Self {
+            port,
+            server,
+            handle,
+        }
+    }
+
+    pub async fn start_replica_of(master: &TestServer) -> Self {
+        let mut client = master.client().await;
+        let connected = client.connected_replicas().await;
+
+        let master_port = master.port.to_string();
+        let replica = Self::start(&["--replicaof", "127.0.0.1", &master_port]).await;
+
+        // The handshake is done once the master knows about us
+        let deadline = Instant::now() + STARTUP_TIMEOUT;
+
+        while client.connected_replicas().await == connected {
+            assert!(Instant::now() < deadline, "replica never connected");
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        replica
+    }
+
+    pub async fn client(&self) -> TestClient {
+        TestClient::connect(self.port).await
+    }
+
+    pub async fn stop(mut self) {
+        self.server.shutdown();
+
+        _ = (&mut self.handle).await;
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+// Either end of a tcp or a unix socket connection
+pub trait Socket: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Socket for T {}
+
+pub struct TestClient {
+    stream: Box<dyn Socket>,
+    buffer: BytesMut,
+}
+
+impl TestClient {
+    pub async fn connect(port: u16) -> Self {
+        let stream = TcpStream::connect(("127.0.0.1", port))
+            .await
+            .expect("unable to connect");
+
+        Self::from_stream(stream)
+    }
+
+    pub async fn connect_unix(path: &Path) -> Self {
+        let stream = UnixStream::connect(path).await.expect("unable to connect");
+
+        Self::from_stream(stream)
+    }
+
+    // For the other end of a connection a server made, like the one of a replica to its master
+    pub fn from_stream(stream: impl Socket + 'static) -> Self {
+        Self {
+            stream: Box::new(stream),
+            buffer: BytesMut::new(),
+        }
+    }
+
+    pub async fn send(&mut self, args: &[&str]) {
+        self.send_message(&request(args)).await;
+    }
+
+    // Unlike `send`, the arguments may hold binary data
+    pub async fn send_message(&mut self, message: &Message) {
+        let mut buffer = BytesMut::new();
+        message.write_to(&mut buffer);
+
+        self.send_raw(&buffer).await;
+    }
+
+    pub async fn send_raw(&mut self, data: &[u8]) {
+        self.stream.write_all(data).await.expect("unable to write");
+    }
+
+    pub async fn command(&mut self, args: &[&str]) -> Message {
+        self.send(args).await;
+        self.read().await
+    }
+
+    pub async fn connected_replicas(&mut self) -> usize {
+        let Message::BulkString(info) = self.command(&["INFO", "replication"]).await else {
+            panic!("INFO didn't reply with a bulk string");
+        };
+
+        info.lines()
+            .find_map(|line| line.strip_prefix("connected_slaves:"))
+            .and_then(|count| count.parse().ok())
+            .expect("INFO is missing connected_slaves")
+    }
+
+    pub async fn read(&mut self) -> Message {
+        timeout(REPLY_TIMEOUT, self.read_message())
+            .await
+            .expect("timed out waiting for a reply")
+            .expect("connection closed before a reply")
+    }
+
+    // None once the server closed the connection
+    pub async fn read_message(&mut self) -> Option<Message> {
+        loop {
+            if let Ok((message, length)) = Message::parse(&self.buffer) {
+                self.buffer.advance(length);
+                return Some(message);
+            }
+
+            if self.stream.read_buf(&mut self.buffer).await.ok()? == 0 {
+                return None;
+            }
+        }
+    }
+}
+
+pub fn request(args: &[&str]) -> Message {
+    Message::Array(args.iter().map(|arg| bulk(arg)).collect())
+}
+
+pub fn bulk(value: &str) -> Message {
+    Message::BulkString(value.to_string())
+}
+
+pub fn simple(value: &str) -> Message {
+    Message::SimpleString(value.to_string())
+}
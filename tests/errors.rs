@@ -0,0 +1,402 @@
+mod common;
+
+use common::{request, simple, TestServer};
+use redis_starter_rust::{parse_client_command, CommandError, Message};
+
+fn error(text: &str) -> Message {
+    Message::Error(text.to_string())
+}
+
+#[test]
+fn parser_reports_typed_errors() {
+    assert_eq!(
+        parse_client_command(&request(&["GET"])).unwrap_err(),
+        CommandError::WrongArity {
+            cmd: "get".to_string()
+        }
+    );
+    assert_eq!(
+        parse_client_command(&request(&["SETRANGE", "key", "x", "value"])).unwrap_err(),
+        CommandError::NotAnInteger
+    );
+    assert_eq!(
+        parse_client_command(&request(&["NOPE", "a"])).unwrap_err(),
+        CommandError::unknown_command(b"NOPE", &[b"a".to_vec()])
+    );
+    assert_eq!(
+        parse_client_command(&request(&["SCAN", "abc"])).unwrap_err(),
+        CommandError::InvalidCursor
+    );
+}
+
+#[tokio::test]
+async fn errors_use_the_redis_wording() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    assert_eq!(
+        client.command(&["GET"]).await,
+        error("ERR wrong number of arguments for 'get' command")
+    );
+    assert_eq!(
+        client.command(&["GETRANGE", "key", "0", "end"]).await,
+        error("ERR value is not an integer or out of range")
+    );
+    assert_eq!(
+        client.command(&["SETRANGE", "key", "-1", "value"]).await,
+        error("ERR offset is out of range")
+    );
+
+    client.command(&["XADD", "stream", "1-1", "a", "1"]).await;
+
+    assert_eq!(
+        client.command(&["GET", "stream"]).await,
+        error("WRONGTYPE Operation against a key holding the wrong kind of value")
+    );
+    assert_eq!(
+        client.command(&["XADD", "stream", "1-1", "a", "1"]).await,
+        error("ERR The ID specified in XADD is equal or smaller than the target stream top item")
+    );
+    assert_eq!(
+        client
+            .command(&["XADD", "stream", "invalid", "a", "1"])
+            .await,
+        error("ERR Invalid stream ID specified as stream command argument")
+    );
+
+    server.stop().await;
+}
+
+#[test]
+fn set_rejects_invalid_expire_times() {
+    let invalid = CommandError::InvalidExpireTime {
+        cmd: "set".to_string(),
+    };
+
+    for args in [
+        &["SET", "key", "value", "EX", "0"][..],
+        &["SET", "key", "value", "EX", "-1"],
+        &["SET", "key", "value", "PX", "0"],
+        &["SET", "key", "value", "EX", "9223372036854775807"],
+    ] {
+        assert_eq!(parse_client_command(&request(args)).unwrap_err(), invalid);
+    }
+
+    assert_eq!(
+        invalid.to_string(),
+        "ERR invalid expire time in 'set' command"
+    );
+    assert!(parse_client_command(&request(&["SET", "key", "value", "EX", "10"])).is_ok());
+}
+
+#[tokio::test]
+async fn string_commands_reject_other_types() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+    let wrong_type = error("WRONGTYPE Operation against a key holding the wrong kind of value");
+
+    client.command(&["XADD", "stream", "1-1", "a", "1"]).await;
+
+    assert_eq!(client.command(&["INCR", "stream"]).await, wrong_type);
+    assert_eq!(client.command(&["DECRBY", "stream", "2"]).await, wrong_type);
+    assert_eq!(client.command(&["APPEND", "stream", "x"]).await, wrong_type);
+
+    assert_eq!(
+        client.command(&["APPEND", "key", "Hello"]).await,
+        Message::Integer(5)
+    );
+    assert_eq!(
+        client.command(&["APPEND", "key", " World"]).await,
+        Message::Integer(11)
+    );
+    assert_eq!(
+        client.command(&["GET", "key"]).await,
+        Message::BulkString("Hello World".to_string())
+    );
+
+    server.stop().await;
+}
+
+#[test]
+fn unknown_commands_echo_the_start_of_their_arguments() {
+    let unknown = |cmd: &str, args: &[&[u8]]| {
+        let args: Vec<_> = args.iter().map(|arg| arg.to_vec()).collect();
+        CommandError::unknown_command(cmd.as_bytes(), &args).to_string()
+    };
+
+    assert_eq!(
+        unknown("foo", &[]),
+        "ERR unknown command 'foo', with args beginning with: "
+    );
+    assert_eq!(
+        unknown("Foo", &[b"a", b""]),
+        "ERR unknown command 'Foo', with args beginning with: 'a' '' "
+    );
+
+    // Arguments stop being added once 128 bytes of them are shown, the last one cut to fit
+    let long = "x".repeat(100);
+    let many: Vec<&[u8]> = vec![long.as_bytes(); 5];
+    assert_eq!(
+        unknown("foo", &many),
+        format!(
+            "ERR unknown command 'foo', with args beginning with: '{}' '{}' ",
+            long,
+            "x".repeat(25)
+        )
+    );
+
+    // Still a single line, whatever bytes the arguments hold
+    assert_eq!(
+        unknown("foo", &[b"a\r\nb", b"\x00\x7f\xff"]),
+        "ERR unknown command 'foo', with args beginning with: 'a  b' '\\x00\\x7f\u{fffd}' "
+    );
+}
+
+#[tokio::test]
+async fn unknown_commands_are_errors() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    assert_eq!(
+        client.command(&["NOPE", "key", "value"]).await,
+        error("ERR unknown command 'NOPE', with args beginning with: 'key' 'value' ")
+    );
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn streams_have_no_consumer_groups() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    client.command(&["XADD", "stream", "1-1", "a", "1"]).await;
+
+    assert_eq!(
+        client.command(&["XINFO", "GROUPS", "stream"]).await,
+        Message::Array(vec![])
+    );
+    assert_eq!(
+        client
+            .command(&["XINFO", "CONSUMERS", "stream", "group"])
+            .await,
+        error("NOGROUP No such consumer group 'group' for key name 'stream'")
+    );
+    assert_eq!(
+        client.command(&["XINFO", "GROUPS", "missing"]).await,
+        error("ERR no such key")
+    );
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn stream_commands_reject_bad_ids_and_other_types() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+    let invalid_id = "ERR Invalid stream ID specified as stream command argument";
+
+    client.command(&["SET", "string", "value"]).await;
+
+    assert_eq!(
+        client.command(&["XADD", "stream", "abc-1", "a", "1"]).await,
+        error(invalid_id)
+    );
+    assert_eq!(
+        client.command(&["XADD", "stream", "0-0", "a", "1"]).await,
+        error("ERR The ID specified in XADD must be greater than 0-0")
+    );
+    assert_eq!(
+        client.command(&["XRANGE", "stream", "abc", "+"]).await,
+        error(invalid_id)
+    );
+    assert_eq!(
+        client.command(&["XREAD", "STREAMS", "stream", "1-x"]).await,
+        error(invalid_id)
+    );
+    assert_eq!(
+        client.command(&["XRANGE", "stream", "-", "+"]).await,
+        Message::Array(vec![])
+    );
+
+    let wrong_type = error("WRONGTYPE Operation against a key holding the wrong kind of value");
+    assert_eq!(
+        client.command(&["XADD", "string", "1-1", "a", "1"]).await,
+        wrong_type
+    );
+    assert_eq!(
+        client.command(&["XRANGE", "string", "-", "+"]).await,
+        wrong_type
+    );
+    assert_eq!(
+        client.command(&["XREAD", "STREAMS", "string", "0-0"]).await,
+        wrong_type
+    );
+    assert_eq!(
+        client.command(&["GET", "string"]).await,
+        Message::BulkString("value".to_string())
+    );
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn config_rejects_unknown_subcommands() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    assert_eq!(
+        client.command(&["CONFIG", "FOO"]).await,
+        error("ERR unknown subcommand 'FOO'. Try CONFIG HELP.")
+    );
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn info_of_an_unknown_section_is_empty() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    assert_eq!(
+        client.command(&["INFO", "nope"]).await,
+        Message::BulkString(String::new())
+    );
+
+    server.stop().await;
+}
+
+// xorshift64, so every run of the fuzz test goes through the same inputs
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next() % bound as u64) as usize
+    }
+}
+
+#[test]
+fn mutated_frames_never_panic_the_parser() {
+    let valid: [&[u8]; 6] = [
+        b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n",
+        b"*2\r\n$4\r\nECHO\r\n$4\r\n\x00\xFF\r\n\r\n",
+        b"*3\r\n:1\r\n*2\r\n+OK\r\n-ERR no\r\n*1\r\n*1\r\n$-1\r\n",
+        b"*-1\r\n",
+        b"$0\r\n\r\n",
+        b"SET inline value\r\n",
+    ];
+    // Bytes that mean something to the parser are picked more often than the others
+    let interesting = b"*$:+-\r\n0123456789";
+    let mut rng = Rng(0x5EED_1234_ABCD_0042);
+
+    for _ in 0..20_000 {
+        let mut frame = valid[rng.below(valid.len())].to_vec();
+
+        for _ in 0..1 + rng.below(4) {
+            let byte = if rng.below(2) == 0 {
+                interesting[rng.below(interesting.len())]
+            } else {
+                rng.next() as u8
+            };
+            let position = rng.below(frame.len() + 1);
+
+            match rng.below(5) {
+                0 if position < frame.len() => frame[position] = byte,
+                1 => frame.insert(position, byte),
+                2 if position < frame.len() => {
+                    frame.remove(position);
+                }
+                3 => frame.truncate(position),
+                _ => {
+                    // A length that is far too big, or negative
+                    let digits = if rng.below(2) == 0 {
+                        "99999999999"
+                    } else {
+                        "-7"
+                    };
+                    frame.splice(position..position, digits.bytes());
+                }
+            }
+        }
+
+        for parse in [Message::parse, Message::parse_request] {
+            if let Ok((_, length)) = parse(&frame) {
+                assert!(length <= frame.len(), "{:?}", frame);
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn malformed_commands_leave_the_connection_usable() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    for frame in [
+        &b"*1\r\n:1\r\n"[..],
+        b"*2\r\n*1\r\n$3\r\nGET\r\n$1\r\na\r\n",
+        b"*2\r\n$4\r\nECHO\r\n:5\r\n",
+        b"*3\r\n$8\r\nREPLCONF\r\n:1\r\n$1\r\nx\r\n",
+        b"*2\r\n$5\r\nPSYNC\r\n*0\r\n",
+        b"*1\r\n$-1\r\n",
+    ] {
+        client.send_raw(frame).await;
+
+        assert_eq!(
+            client.read().await,
+            error("ERR syntax error"),
+            "{:?}",
+            frame
+        );
+        assert_eq!(client.command(&["PING"]).await, simple("PONG"));
+    }
+
+    // An empty array is skipped without a reply, like redis does
+    client.send_raw(b"*0\r\n").await;
+    assert_eq!(client.command(&["PING"]).await, simple("PONG"));
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn protocol_errors_are_replied_to_before_closing() {
+    let server = TestServer::start(&[]).await;
+
+    for (frame, detail) in [
+        (&b"*abc\r\n"[..], "invalid multibulk length"),
+        (b"*1\r\nx\r\n", "expected '$', got 'x'"),
+        (b"*1\r\n$-5\r\n", "invalid bulk length"),
+        (
+            b"*1\r\n$2\r\nabcd\r\n",
+            "bulk string is not terminated by CRLF",
+        ),
+    ] {
+        let mut client = server.client().await;
+
+        // Nothing after the broken frame runs, there is no telling where it would start
+        let mut data = frame.to_vec();
+        data.extend_from_slice(b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$1\r\n1\r\n");
+        client.send_raw(&data).await;
+
+        assert_eq!(
+            client.read().await,
+            error(&format!("ERR Protocol error: {}", detail))
+        );
+        assert_eq!(client.read_message().await, None);
+    }
+
+    let mut client = server.client().await;
+    assert_eq!(
+        client.command(&["EXISTS", "key"]).await,
+        Message::Integer(0)
+    );
+
+    server.stop().await;
+}
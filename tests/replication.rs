@@ -0,0 +1,650 @@
+mod common;
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::BytesMut;
+use common::{bulk, request, simple, TestClient, TestServer};
+use redis_starter_rust::{Message, Store};
+use tokio::{
+    net::TcpListener,
+    time::{sleep, Instant},
+};
+
+// Replicas apply the propagated commands asynchronously, so poll for the expected reply
+async fn wait_for_reply(server: &TestServer, args: &[&str], expected: Message) {
+    let mut client = server.client().await;
+    let deadline = Instant::now() + Duration::from_secs(5);
+
+    loop {
+        let reply = client.command(args).await;
+
+        if reply == expected {
+            return;
+        }
+
+        assert!(Instant::now() < deadline, "last reply was {:?}", reply);
+        sleep(Duration::from_millis(10)).await;
+    }
+}
+
+#[tokio::test]
+async fn writes_are_propagated_to_replicas() {
+    let master = TestServer::start(&[]).await;
+    let replica = TestServer::start_replica_of(&master).await;
+    let mut client = master.client().await;
+
+    client.command(&["SET", "a", "1"]).await;
+    client.command(&["SET", "b", "2"]).await;
+    client.command(&["SETRANGE", "a", "1", "23"]).await;
+
+    wait_for_reply(&replica, &["GET", "a"], bulk("123")).await;
+    wait_for_reply(&replica, &["GET", "b"], bulk("2")).await;
+
+    replica.stop().await;
+    master.stop().await;
+}
+
+#[tokio::test]
+async fn replicas_receive_the_existing_dataset() {
+    let master = TestServer::start(&[]).await;
+    let mut client = master.client().await;
+
+    client.command(&["SET", "existing", "value"]).await;
+
+    let replica = TestServer::start_replica_of(&master).await;
+
+    wait_for_reply(&replica, &["GET", "existing"], bulk("value")).await;
+
+    replica.stop().await;
+    master.stop().await;
+}
+
+#[tokio::test]
+async fn wait_counts_acknowledging_replicas() {
+    let master = TestServer::start(&[]).await;
+    let first = TestServer::start_replica_of(&master).await;
+    let second = TestServer::start_replica_of(&master).await;
+    let mut client = master.client().await;
+
+    client.command(&["SET", "key", "value"]).await;
+
+    assert_eq!(
+        client.command(&["WAIT", "2", "1000"]).await,
+        Message::Integer(2)
+    );
+
+    first.stop().await;
+    second.stop().await;
+    master.stop().await;
+}
+
+#[tokio::test]
+async fn wait_reports_fewer_replicas_than_requested() {
+    let master = TestServer::start(&[]).await;
+    let replica = TestServer::start_replica_of(&master).await;
+    let mut client = master.client().await;
+
+    client.command(&["SET", "key", "value"]).await;
+
+    assert_eq!(
+        client.command(&["WAIT", "3", "200"]).await,
+        Message::Integer(1)
+    );
+
+    replica.stop().await;
+    master.stop().await;
+}
+
+#[tokio::test]
+async fn interleaved_writes_and_waits_keep_their_order() {
+    let master = TestServer::start(&[]).await;
+    let replica = TestServer::start_replica_of(&master).await;
+    let mut client = master.client().await;
+
+    let mut expected = String::new();
+
+    for i in 0..30 {
+        let digit = (i % 10).to_string();
+
+        client.command(&["SET", "counter", &i.to_string()]).await;
+        client
+            .command(&["SETRANGE", "log", &i.to_string(), &digit])
+            .await;
+        expected.push_str(&digit);
+
+        if i % 7 == 0 {
+            assert_eq!(
+                client.command(&["WAIT", "1", "1000"]).await,
+                Message::Integer(1)
+            );
+        }
+    }
+
+    assert_eq!(
+        client.command(&["WAIT", "1", "1000"]).await,
+        Message::Integer(1)
+    );
+
+    // Everything before the WAIT has been applied once it returns
+    let mut replica_client = replica.client().await;
+
+    assert_eq!(
+        replica_client.command(&["GET", "counter"]).await,
+        bulk("29")
+    );
+    assert_eq!(
+        replica_client.command(&["GET", "log"]).await,
+        bulk(&expected)
+    );
+
+    replica.stop().await;
+    master.stop().await;
+}
+
+#[tokio::test]
+async fn wait_without_writes_counts_every_replica() {
+    let master = TestServer::start(&[]).await;
+    let first = TestServer::start_replica_of(&master).await;
+    let second = TestServer::start_replica_of(&master).await;
+    let mut client = master.client().await;
+
+    assert_eq!(
+        client.command(&["WAIT", "5", "0"]).await,
+        Message::Integer(2)
+    );
+
+    first.stop().await;
+    second.stop().await;
+    master.stop().await;
+}
+
+#[tokio::test]
+async fn expire_reaches_replicas_as_an_absolute_time() {
+    let master = TestServer::start(&[]).await;
+    let replica = TestServer::start_replica_of(&master).await;
+    let mut client = master.client().await;
+
+    client.command(&["SET", "key", "value"]).await;
+    assert_eq!(
+        client.command(&["EXPIRE", "key", "100"]).await,
+        Message::Integer(1)
+    );
+
+    assert_eq!(
+        client.command(&["WAIT", "1", "1000"]).await,
+        Message::Integer(1)
+    );
+
+    let key = "key".to_string();
+    let on_master = master.server.store().read().await.expiry_at(&key);
+    let on_replica = replica.server.store().read().await.expiry_at(&key);
+
+    // A relative ttl would have been applied a little later on the replica
+    assert!(on_master.is_some());
+    assert_eq!(on_master, on_replica);
+
+    replica.stop().await;
+    master.stop().await;
+}
+
+#[tokio::test]
+async fn expiring_in_the_past_deletes_the_key_everywhere() {
+    let master = TestServer::start(&[]).await;
+    let replica = TestServer::start_replica_of(&master).await;
+    let mut client = master.client().await;
+
+    client.command(&["SET", "key", "value"]).await;
+    wait_for_reply(&replica, &["GET", "key"], bulk("value")).await;
+
+    assert_eq!(
+        client.command(&["PEXPIREAT", "key", "1000"]).await,
+        Message::Integer(1)
+    );
+    assert_eq!(
+        client.command(&["PEXPIREAT", "key", "1000"]).await,
+        Message::Integer(0)
+    );
+    assert_eq!(client.command(&["GET", "key"]).await, Message::Null);
+
+    wait_for_reply(&replica, &["GET", "key"], Message::Null).await;
+
+    replica.stop().await;
+    master.stop().await;
+}
+
+#[tokio::test]
+async fn replicas_get_normalized_writes() {
+    let master = TestServer::start(&[]).await;
+    let replica = TestServer::start_replica_of(&master).await;
+    let mut client = master.client().await;
+
+    client
+        .command(&["SET", "key", "value", "NX", "PX", "100000"])
+        .await;
+    client
+        .command(&["SET", "key", "other", "XX", "GET", "KEEPTTL"])
+        .await;
+    client.command(&["XADD", "stream", "*", "a", "1"]).await;
+    client.command(&["XADD", "stream", "*", "b", "2"]).await;
+
+    let entries = client.command(&["XRANGE", "stream", "-", "+"]).await;
+
+    wait_for_reply(&replica, &["XRANGE", "stream", "-", "+"], entries).await;
+    wait_for_reply(&replica, &["GET", "key"], bulk("other")).await;
+
+    // Propagated with millisecond precision, like every expiry redis stores
+    let key = "key".to_string();
+    let master_expiry = master.server.store().read().await.expiry_at(&key);
+    let replica_expiry = replica.server.store().read().await.expiry_at(&key);
+    let millis = |time: SystemTime| time.duration_since(UNIX_EPOCH).unwrap().as_millis();
+
+    assert!(master_expiry.is_some());
+    assert_eq!(replica_expiry.map(millis), master_expiry.map(millis));
+
+    replica.stop().await;
+    master.stop().await;
+}
+
+#[tokio::test]
+async fn local_commands_are_not_propagated() {
+    let master = TestServer::start(&[]).await;
+    let replica = TestServer::start_replica_of(&master).await;
+    let mut client = master.client().await;
+
+    client.command(&["SET", "key", "value"]).await;
+    wait_for_reply(&replica, &["GET", "key"], bulk("value")).await;
+
+    client.command(&["CONFIG", "SET", "maxmemory", "100"]).await;
+    client.command(&["FLUSHALL"]).await;
+
+    wait_for_reply(&replica, &["GET", "key"], Message::Null).await;
+    wait_for_reply(
+        &replica,
+        &["CONFIG", "GET", "maxmemory"],
+        Message::Array(vec![bulk("maxmemory"), bulk("0")]),
+    )
+    .await;
+
+    replica.stop().await;
+    master.stop().await;
+}
+
+// Plays the master for a replica, so the test decides when every command reaches it
+async fn fake_master_for(listener: &TcpListener) -> TestClient {
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut replica = TestClient::from_stream(stream);
+
+    // PING, the two REPLCONFs, then PSYNC which gets an empty dataset
+    for _ in 0..3 {
+        replica.read().await;
+        replica.send_raw(b"+OK\r\n").await;
+    }
+
+    replica.read().await;
+
+    let rdb = Store::new().export();
+    let mut reply = format!("+FULLRESYNC {} 0\r\n${}\r\n", "0".repeat(40), rdb.len()).into_bytes();
+    reply.extend_from_slice(&rdb);
+    replica.send_raw(&reply).await;
+
+    replica
+}
+
+#[tokio::test]
+async fn replicas_hide_expired_keys_until_the_master_deletes_them() {
+    let master = TestServer::start(&[]).await;
+    let replica = TestServer::start_replica_of(&master).await;
+    let mut client = master.client().await;
+
+    // Left to the lazy expiry of the master, so it stays around on the replica for a while
+    client.command(&["DEBUG", "SET-ACTIVE-EXPIRE", "0"]).await;
+    client.command(&["SET", "key", "value", "PX", "200"]).await;
+    wait_for_reply(&replica, &["GET", "key"], bulk("value")).await;
+
+    sleep(Duration::from_millis(400)).await;
+
+    let mut replica_client = replica.client().await;
+    assert_eq!(replica_client.command(&["GET", "key"]).await, Message::Null);
+    assert_eq!(
+        replica_client.command(&["EXISTS", "key"]).await,
+        Message::Integer(0)
+    );
+    assert_eq!(
+        replica_client.command(&["DBSIZE"]).await,
+        Message::Integer(1)
+    );
+
+    // A GET on the master removes the key, and the replica with it
+    assert_eq!(client.command(&["GET", "key"]).await, Message::Null);
+    wait_for_reply(&replica, &["DBSIZE"], Message::Integer(0)).await;
+
+    // So does the active expiry
+    client.command(&["DEBUG", "SET-ACTIVE-EXPIRE", "1"]).await;
+    client
+        .command(&["SET", "swept", "value", "PX", "100"])
+        .await;
+    wait_for_reply(&replica, &["DBSIZE"], Message::Integer(1)).await;
+    wait_for_reply(&replica, &["DBSIZE"], Message::Integer(0)).await;
+
+    replica.stop().await;
+    master.stop().await;
+}
+
+// The replica is expected back offering `replid` and the offset of the byte it misses first
+async fn fake_master_reconnect(listener: &TcpListener, replid: &str, offset: usize) -> TestClient {
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut replica = TestClient::from_stream(stream);
+
+    for _ in 0..3 {
+        replica.read().await;
+        replica.send_raw(b"+OK\r\n").await;
+    }
+
+    assert_eq!(
+        replica.read().await,
+        request(&["PSYNC", replid, &(offset + 1).to_string()])
+    );
+
+    replica
+}
+
+fn encoded_len(args: &[&str]) -> usize {
+    let mut buffer = BytesMut::new();
+    request(args).write_to(&mut buffer);
+
+    buffer.len()
+}
+
+#[tokio::test]
+async fn replicas_continue_where_they_left_off_after_a_reconnect() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let master_port = listener.local_addr().unwrap().port().to_string();
+
+    let replica = TestServer::start(&["--replicaof", "127.0.0.1", &master_port]).await;
+    let mut master = fake_master_for(&listener).await;
+
+    let incr = ["INCR", "counter"];
+
+    for _ in 0..3 {
+        master.send(&incr).await;
+    }
+    wait_for_reply(&replica, &["GET", "counter"], bulk("3")).await;
+
+    drop(master);
+
+    // No rdb follows a CONTINUE, the replica has to take the next command as it comes
+    let first_replid = "0".repeat(40);
+    let mut master = fake_master_reconnect(&listener, &first_replid, 3 * encoded_len(&incr)).await;
+
+    let new_replid = "1".repeat(40);
+    master
+        .send_raw(format!("+CONTINUE {}\r\n", new_replid).as_bytes())
+        .await;
+
+    for _ in 0..2 {
+        master.send(&incr).await;
+    }
+    wait_for_reply(&replica, &["GET", "counter"], bulk("5")).await;
+
+    drop(master);
+
+    // The new replid is the one offered next, with a CONTINUE that keeps it
+    let mut master = fake_master_reconnect(&listener, &new_replid, 5 * encoded_len(&incr)).await;
+    master.send_raw(b"+CONTINUE\r\n").await;
+    master.send(&incr).await;
+
+    wait_for_reply(&replica, &["GET", "counter"], bulk("6")).await;
+
+    master.send(&["REPLCONF", "GETACK", "*"]).await;
+    assert_eq!(
+        master.read().await,
+        request(&["REPLCONF", "ACK", &(6 * encoded_len(&incr)).to_string()])
+    );
+
+    replica.stop().await;
+}
+
+#[tokio::test]
+async fn replicas_ignore_replconfs_they_dont_know() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let master_port = listener.local_addr().unwrap().port().to_string();
+
+    let replica = TestServer::start(&["--replicaof", "127.0.0.1", &master_port]).await;
+    let mut master = fake_master_for(&listener).await;
+
+    let commands: [&[&str]; 3] = [
+        &["REPLCONF"],
+        &["REPLCONF", "UNKNOWN"],
+        &["INCR", "counter"],
+    ];
+
+    for command in commands {
+        master.send(command).await;
+    }
+    wait_for_reply(&replica, &["GET", "counter"], bulk("1")).await;
+
+    // They still count towards the offset
+    let offset: usize = commands.iter().map(|command| encoded_len(command)).sum();
+
+    master.send(&["REPLCONF", "GETACK", "*"]).await;
+    assert_eq!(
+        master.read().await,
+        request(&["REPLCONF", "ACK", &offset.to_string()])
+    );
+
+    replica.stop().await;
+}
+
+#[tokio::test]
+async fn writes_during_a_full_resync_are_neither_lost_nor_repeated() {
+    let master = TestServer::start(&[]).await;
+    let stop = Arc::new(AtomicBool::new(false));
+
+    // Big enough for the rdb to take a while to send
+    let big = "x".repeat(32 * 1024 * 1024);
+    master.client().await.command(&["SET", "big", &big]).await;
+
+    let mut writers = Vec::new();
+
+    for writer in 0..4 {
+        let mut client = master.client().await;
+        let stop = stop.clone();
+
+        writers.push(tokio::spawn(async move {
+            let mut count = 0;
+
+            while !stop.load(Ordering::Relaxed) {
+                count += 1;
+                // INCR isn't idempotent, a write that reaches the replica twice shows
+                client.command(&["INCR", "counter"]).await;
+                client
+                    .command(&["SET", &format!("key:{}:{}", writer, count), "value"])
+                    .await;
+            }
+
+            count
+        }));
+    }
+
+    let replica = TestServer::start_replica_of(&master).await;
+    sleep(Duration::from_millis(50)).await;
+    stop.store(true, Ordering::Relaxed);
+
+    let mut count = 0;
+    for writer in writers {
+        count += writer.await.unwrap();
+    }
+
+    wait_for_reply(&replica, &["GET", "counter"], bulk(&count.to_string())).await;
+    wait_for_reply(&replica, &["DBSIZE"], Message::Integer(count as isize + 2)).await;
+
+    // Still in sync and still reading a valid stream after all of that
+    master
+        .client()
+        .await
+        .command(&["SET", "after", "resync"])
+        .await;
+    wait_for_reply(&replica, &["GET", "after"], bulk("resync")).await;
+
+    replica.stop().await;
+    master.stop().await;
+}
+
+#[tokio::test]
+async fn info_lists_the_attached_replicas() {
+    let master = TestServer::start(&[]).await;
+    let replica = TestServer::start_replica_of(&master).await;
+    let mut client = master.client().await;
+
+    client.command(&["SET", "a", "1"]).await;
+    client.command(&["WAIT", "1", "1000"]).await;
+
+    let info = match client.command(&["INFO", "replication"]).await {
+        Message::BulkString(info) => info,
+        reply => panic!("INFO replied with {:?}", reply),
+    };
+
+    assert!(info.contains("connected_slaves:1\n"), "{}", info);
+
+    // The offset the replica acked for WAIT covers the SET, but not the GETACK that asked for it
+    let set_length = request(&["SET", "a", "1"]).serialize().unwrap().len();
+    assert!(
+        info.contains(&format!(
+            "slave0:ip=127.0.0.1,port={},state=online,offset={},lag=0\n",
+            replica.port, set_length
+        )),
+        "{}",
+        info
+    );
+
+    replica.stop().await;
+    master.stop().await;
+}
+
+#[tokio::test]
+async fn full_resyncs_start_at_the_current_offset() {
+    let master = TestServer::start(&[]).await;
+    let mut client = master.client().await;
+
+    client.command(&["SET", "a", "1"]).await;
+    let set_length = request(&["SET", "a", "1"]).serialize().unwrap().len();
+
+    let mut replica = master.client().await;
+    let reply = replica.command(&["PSYNC", "?", "-1"]).await;
+
+    assert_eq!(
+        reply,
+        Message::SimpleString(format!(
+            "FULLRESYNC 8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb {}",
+            set_length
+        ))
+    );
+
+    // A replica attached after the write acks offsets that include it
+    let late = TestServer::start_replica_of(&master).await;
+    client.command(&["SET", "b", "2"]).await;
+
+    assert_eq!(
+        client.command(&["WAIT", "1", "1000"]).await,
+        Message::Integer(1)
+    );
+
+    late.stop().await;
+    master.stop().await;
+}
+
+#[tokio::test]
+async fn replicas_within_the_backlog_continue_where_they_left_off() {
+    let master = TestServer::start(&[]).await;
+    let mut client = master.client().await;
+    let replid = "8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb";
+
+    client.command(&["SET", "a", "1"]).await;
+    let offset = request(&["SET", "a", "1"]).serialize().unwrap().len();
+
+    // Attached and gone again, having processed up to the offset of the full resync
+    let mut replica = master.client().await;
+    assert_eq!(
+        replica.command(&["PSYNC", "?", "-1"]).await,
+        simple(&format!("FULLRESYNC {} {}", replid, offset))
+    );
+    drop(replica);
+
+    client.command(&["SET", "b", "2"]).await;
+    client.command(&["INCR", "counter"]).await;
+
+    let mut replica = master.client().await;
+    let next = (offset + 1).to_string();
+    assert_eq!(
+        replica.command(&["PSYNC", replid, &next]).await,
+        simple(&format!("CONTINUE {}", replid))
+    );
+
+    // What was missed comes first, then whatever is written after
+    assert_eq!(replica.read().await, request(&["SET", "b", "2"]));
+    assert_eq!(replica.read().await, request(&["INCR", "counter"]));
+
+    client.command(&["SET", "c", "3"]).await;
+    assert_eq!(replica.read().await, request(&["SET", "c", "3"]));
+
+    // Another replid, or an offset we never reached, needs the whole dataset
+    let mut other = master.client().await;
+    let reply = other.command(&["PSYNC", &"0".repeat(40), &next]).await;
+    assert!(
+        matches!(&reply, Message::SimpleString(reply) if reply.starts_with("FULLRESYNC")),
+        "{:?}",
+        reply
+    );
+
+    let mut ahead = master.client().await;
+    let reply = ahead.command(&["PSYNC", replid, "1000000"]).await;
+    assert!(
+        matches!(&reply, Message::SimpleString(reply) if reply.starts_with("FULLRESYNC")),
+        "{:?}",
+        reply
+    );
+
+    master.stop().await;
+}
+
+#[tokio::test]
+async fn wait_is_not_held_up_by_a_silent_replica() {
+    let master = TestServer::start(&[]).await;
+    let replica = TestServer::start_replica_of(&master).await;
+
+    // Attached, but never reads or acks anything
+    let mut silent = master.client().await;
+    silent.send(&["PSYNC", "?", "-1"]).await;
+    assert!(matches!(silent.read().await, Message::SimpleString(_)));
+
+    let mut client = master.client().await;
+    client.command(&["SET", "key", "value"]).await;
+
+    let started = Instant::now();
+    assert_eq!(
+        client.command(&["WAIT", "2", "500"]).await,
+        Message::Integer(1)
+    );
+
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(500), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(900), "{:?}", elapsed);
+
+    // The responsive replica alone is enough when only one is asked for
+    let started = Instant::now();
+    assert_eq!(
+        client.command(&["WAIT", "1", "500"]).await,
+        Message::Integer(1)
+    );
+    assert!(started.elapsed() < Duration::from_millis(400));
+
+    drop(silent);
+    replica.stop().await;
+    master.stop().await;
+}
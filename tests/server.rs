@@ -0,0 +1,1703 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+use common::{bulk, request, simple, TestClient, TestServer};
+use redis_starter_rust::{CommandLineArgs, Message, Server};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::sleep,
+};
+
+#[tokio::test]
+async fn set_and_get() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    assert_eq!(client.command(&["SET", "key", "value"]).await, simple("OK"));
+    assert_eq!(client.command(&["GET", "key"]).await, bulk("value"));
+    assert_eq!(client.command(&["GET", "missing"]).await, Message::Null);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn set_with_px_expires() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    assert_eq!(
+        client.command(&["SET", "key", "value", "px", "100"]).await,
+        simple("OK")
+    );
+    assert_eq!(client.command(&["GET", "key"]).await, bulk("value"));
+
+    sleep(Duration::from_millis(150)).await;
+
+    assert_eq!(client.command(&["GET", "key"]).await, Message::Null);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn pipelined_commands_reply_in_order() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    let mut pipeline = String::new();
+    for args in [
+        &["SET", "a", "1"][..],
+        &["ECHO", "hello"],
+        &["GET", "a"],
+        &["PING"],
+    ] {
+        pipeline.push_str(&request(args).serialize().unwrap());
+    }
+
+    client.send_raw(pipeline.as_bytes()).await;
+
+    assert_eq!(client.read().await, simple("OK"));
+    assert_eq!(client.read().await, bulk("hello"));
+    assert_eq!(client.read().await, bulk("1"));
+    assert_eq!(client.read().await, simple("PONG"));
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn xadd_and_xrange() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    assert_eq!(
+        client.command(&["XADD", "stream", "1-1", "a", "1"]).await,
+        bulk("1-1")
+    );
+    assert_eq!(
+        client.command(&["XADD", "stream", "1-2", "b", "2"]).await,
+        bulk("1-2")
+    );
+    assert!(matches!(
+        client.command(&["XADD", "stream", "1-1", "c", "3"]).await,
+        Message::Error(_)
+    ));
+
+    let entry = |id: &str, field: &str, value: &str| {
+        Message::Array(vec![
+            bulk(id),
+            Message::Array(vec![bulk(field), bulk(value)]),
+        ])
+    };
+
+    assert_eq!(
+        client.command(&["XRANGE", "stream", "-", "+"]).await,
+        Message::Array(vec![entry("1-1", "a", "1"), entry("1-2", "b", "2")])
+    );
+    assert_eq!(
+        client.command(&["XRANGE", "stream", "1-2", "+"]).await,
+        Message::Array(vec![entry("1-2", "b", "2")])
+    );
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn setrange_pads_missing_keys_with_zeroes() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    assert_eq!(
+        client.command(&["SETRANGE", "key", "5", "data"]).await,
+        Message::Integer(9)
+    );
+    assert_eq!(
+        client.command(&["GET", "key"]).await,
+        bulk("\0\0\0\0\0data")
+    );
+
+    assert_eq!(
+        client.command(&["SETRANGE", "empty", "3", ""]).await,
+        Message::Integer(0)
+    );
+    assert_eq!(client.command(&["GET", "empty"]).await, Message::Null);
+
+    // The value is taken as it is, whether or not it is valid utf-8
+    client.command(&["SET", "binary", "hello"]).await;
+    client
+        .send_message(&Message::Array(vec![
+            bulk("SETRANGE"),
+            bulk("binary"),
+            bulk("1"),
+            Message::BulkBytes(vec![0xFF, 0xFE]),
+        ]))
+        .await;
+    assert_eq!(client.read().await, Message::Integer(5));
+    assert_eq!(
+        client.command(&["GET", "binary"]).await,
+        Message::BulkBytes(b"h\xFF\xFElo".to_vec())
+    );
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn getrange_with_inverted_indices_is_empty() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    client.command(&["SET", "key", "hello"]).await;
+
+    assert_eq!(
+        client.command(&["GETRANGE", "key", "3", "1"]).await,
+        bulk("")
+    );
+    assert_eq!(
+        client.command(&["GETRANGE", "key", "-1", "-3"]).await,
+        bulk("")
+    );
+    assert_eq!(
+        client.command(&["GETRANGE", "key", "-3", "-1"]).await,
+        bulk("llo")
+    );
+    assert_eq!(
+        client.command(&["GETRANGE", "missing", "0", "-1"]).await,
+        bulk("")
+    );
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn quit_replies_then_closes_without_running_pipelined_commands() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    let mut pipeline = String::new();
+    for args in [&["SET", "a", "1"][..], &["QUIT"], &["SET", "b", "2"]] {
+        pipeline.push_str(&request(args).serialize().unwrap());
+    }
+
+    client.send_raw(pipeline.as_bytes()).await;
+
+    assert_eq!(client.read().await, simple("OK"));
+    assert_eq!(client.read().await, simple("OK"));
+    assert_eq!(client.read_message().await, None);
+
+    let mut client = server.client().await;
+
+    assert_eq!(client.command(&["GET", "a"]).await, bulk("1"));
+    assert_eq!(client.command(&["GET", "b"]).await, Message::Null);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn object_idletime_resets_on_access() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    client.command(&["SET", "key", "value"]).await;
+
+    sleep(Duration::from_millis(1100)).await;
+
+    // Looking at the idle time doesn't count as an access
+    assert_eq!(
+        client.command(&["OBJECT", "IDLETIME", "key"]).await,
+        Message::Integer(1)
+    );
+    assert_eq!(
+        client.command(&["OBJECT", "IDLETIME", "key"]).await,
+        Message::Integer(1)
+    );
+
+    client.command(&["GET", "key"]).await;
+
+    assert_eq!(
+        client.command(&["OBJECT", "IDLETIME", "key"]).await,
+        Message::Integer(0)
+    );
+    assert_eq!(
+        client.command(&["OBJECT", "IDLETIME", "missing"]).await,
+        Message::Null
+    );
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn expired_keys_are_counted_in_info() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    client.command(&["SET", "short", "value", "px", "10"]).await;
+    client
+        .command(&["SET", "long", "value", "px", "100000"])
+        .await;
+
+    // Give the active expiry a few rounds to sweep the key
+    sleep(Duration::from_millis(350)).await;
+
+    // The test macro doesn't understand let-else
+    let info = match client.command(&["INFO", "stats"]).await {
+        Message::BulkString(info) => info,
+        reply => panic!("unexpected INFO reply {:?}", reply),
+    };
+
+    assert!(info.contains("expired_keys:1\n"), "{}", info);
+    assert!(info.contains("evicted_keys:0\n"), "{}", info);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn scan_filters_by_pattern_and_type() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    for n in 0..20 {
+        let (user, stream, other) = (format!("user:{n}"), format!("user:s{n}"), format!("x{n}"));
+        client.command(&["SET", &user, "value"]).await;
+        client.command(&["XADD", &stream, "1-1", "a", "1"]).await;
+        client.command(&["SET", &other, "value"]).await;
+    }
+
+    let mut found = Vec::new();
+    let mut cursor = "0".to_string();
+
+    loop {
+        let reply = client
+            .command(&[
+                "SCAN", &cursor, "MATCH", "user:*", "TYPE", "string", "COUNT", "7",
+            ])
+            .await;
+        let (next, keys) = match reply {
+            Message::Array(reply) => match &reply[..] {
+                [Message::BulkString(next), Message::Array(keys)] => (next.clone(), keys.clone()),
+                _ => panic!("unexpected SCAN reply {reply:?}"),
+            },
+            reply => panic!("unexpected SCAN reply {reply:?}"),
+        };
+
+        found.extend(keys.into_iter().map(|key| match key {
+            Message::BulkString(key) => key,
+            key => panic!("unexpected key {key:?}"),
+        }));
+        cursor = next.clone();
+
+        if cursor == "0" {
+            break;
+        }
+    }
+
+    let mut expected: Vec<_> = (0..20).map(|n| format!("user:{n}")).collect();
+    found.sort();
+    expected.sort();
+    assert_eq!(found, expected);
+
+    assert_eq!(
+        client.command(&["SCAN", "0", "COUNT", "0"]).await,
+        Message::Error("ERR syntax error".to_string())
+    );
+    assert_eq!(
+        client.command(&["SCAN", "0", "MATCH"]).await,
+        Message::Error("ERR syntax error".to_string())
+    );
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn allkeys_lfu_evicts_cold_keys_before_a_hot_one() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    client.command(&["SET", "hot", "value"]).await;
+
+    // Without an LFU policy the counter isn't available
+    assert_eq!(
+        client.command(&["OBJECT", "FREQ", "hot"]).await,
+        Message::Error(
+            "ERR An LFU maxmemory policy is not selected, access frequency not tracked. \
+             Please note that when switching between policies at runtime LRU and LFU data will \
+             take some time to adjust."
+                .to_string()
+        )
+    );
+
+    client
+        .command(&["CONFIG", "SET", "maxmemory-policy", "allkeys-lfu"])
+        .await;
+
+    for _ in 0..100 {
+        client.command(&["GET", "hot"]).await;
+    }
+
+    // A new key starts at 5, and the first access always counts
+    let frequency = match client.command(&["OBJECT", "FREQ", "hot"]).await {
+        Message::Integer(frequency) => frequency,
+        reply => panic!("unexpected OBJECT FREQ reply {:?}", reply),
+    };
+    assert!(frequency > 5, "{}", frequency);
+
+    client
+        .command(&["CONFIG", "SET", "maxmemory", "4000"])
+        .await;
+
+    for index in 0..500 {
+        let key = format!("cold:{}", index);
+        client.command(&["SET", &key, "value"]).await;
+    }
+
+    assert_eq!(client.command(&["GET", "hot"]).await, bulk("value"));
+
+    let size = match client.command(&["DBSIZE"]).await {
+        Message::Integer(size) => size,
+        reply => panic!("unexpected DBSIZE reply {:?}", reply),
+    };
+    assert!(size < 100, "{}", size);
+
+    let info = match client.command(&["INFO", "stats"]).await {
+        Message::BulkString(info) => info,
+        reply => panic!("unexpected INFO reply {:?}", reply),
+    };
+    assert!(
+        info.contains(&format!("evicted_keys:{}\n", 501 - size)),
+        "{}",
+        info
+    );
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn flushall_async_empties_a_large_keyspace_right_away() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    // Pipelined, so filling the keyspace doesn't take a round trip per key
+    const KEYS: usize = 50_000;
+    let mut buffer = BytesMut::new();
+
+    for index in 0..KEYS {
+        let key = format!("key:{}", index);
+        request(&["SET", &key, "value"]).write_to(&mut buffer);
+    }
+
+    client.send_raw(&buffer).await;
+
+    for _ in 0..KEYS {
+        client.read().await;
+    }
+
+    assert_eq!(
+        client.command(&["FLUSHALL", "LATER"]).await,
+        Message::Error("ERR syntax error".to_string())
+    );
+
+    let started = Instant::now();
+
+    assert_eq!(client.command(&["FLUSHALL", "ASYNC"]).await, simple("OK"));
+    assert!(started.elapsed() < Duration::from_millis(500));
+
+    assert_eq!(client.command(&["DBSIZE"]).await, Message::Integer(0));
+    assert_eq!(client.command(&["GET", "key:0"]).await, Message::Null);
+
+    // The keyspace is usable right away
+    assert_eq!(client.command(&["SET", "key:0", "new"]).await, simple("OK"));
+    assert_eq!(client.command(&["FLUSHDB", "SYNC"]).await, simple("OK"));
+    assert_eq!(client.command(&["DBSIZE"]).await, Message::Integer(0));
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn growing_a_string_past_proto_max_bulk_len_fails() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    // The lowest limit redis accepts
+    const LIMIT: usize = 1024 * 1024;
+
+    assert_eq!(
+        client
+            .command(&["CONFIG", "SET", "proto-max-bulk-len", "1000"])
+            .await,
+        Message::Error(
+            "ERR CONFIG SET failed (possibly related to argument 'proto-max-bulk-len') - \
+             argument couldn't be parsed"
+                .to_string()
+        )
+    );
+    client
+        .command(&["CONFIG", "SET", "proto-max-bulk-len", &LIMIT.to_string()])
+        .await;
+
+    let value = "a".repeat(LIMIT - 2);
+    client.command(&["SET", "key", &value]).await;
+
+    let too_long =
+        Message::Error("ERR string exceeds maximum allowed size (proto-max-bulk-len)".to_string());
+
+    assert_eq!(client.command(&["APPEND", "key", "bcd"]).await, too_long);
+    assert_eq!(
+        client
+            .command(&["SETRANGE", "key", &LIMIT.to_string(), "b"])
+            .await,
+        too_long
+    );
+    assert_eq!(
+        client
+            .command(&["SETBIT", "key", &(LIMIT * 8).to_string(), "1"])
+            .await,
+        Message::Error("ERR bit offset is not an integer or out of range".to_string())
+    );
+    assert_eq!(client.command(&["GET", "key"]).await, bulk(&value));
+
+    // Right up to the limit is fine
+    assert_eq!(
+        client.command(&["APPEND", "key", "bc"]).await,
+        Message::Integer(LIMIT as isize)
+    );
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn xread_without_entries_replies_with_a_null_array() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    client.command(&["XADD", "stream", "1-1", "a", "1"]).await;
+
+    assert_eq!(
+        client
+            .command(&["XREAD", "BLOCK", "50", "STREAMS", "stream", "$"])
+            .await,
+        Message::NullArray
+    );
+
+    // Without BLOCK too, nothing newer than the last entry is there to read
+    assert_eq!(
+        client.command(&["XREAD", "STREAMS", "stream", "1-1"]).await,
+        Message::NullArray
+    );
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn getex_only_changes_the_expiry_when_its_condition_holds() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    client.command(&["SET", "key", "value", "PX", "400"]).await;
+    client.command(&["SET", "persistent", "value"]).await;
+
+    // A shorter expiry isn't greater, and a key without one has nothing for XX to replace
+    assert_eq!(
+        client.command(&["GETEX", "key", "GT", "PX", "50"]).await,
+        bulk("value")
+    );
+    assert_eq!(
+        client
+            .command(&["GETEX", "persistent", "XX", "PX", "50"])
+            .await,
+        bulk("value")
+    );
+
+    sleep(Duration::from_millis(150)).await;
+
+    assert_eq!(client.command(&["GET", "key"]).await, bulk("value"));
+    assert_eq!(client.command(&["GET", "persistent"]).await, bulk("value"));
+
+    assert_eq!(
+        client.command(&["GETEX", "key", "LT", "PX", "50"]).await,
+        bulk("value")
+    );
+    assert_eq!(
+        client
+            .command(&["GETEX", "persistent", "NX", "PX", "50"])
+            .await,
+        bulk("value")
+    );
+
+    sleep(Duration::from_millis(150)).await;
+
+    assert_eq!(client.command(&["GET", "key"]).await, Message::Null);
+    assert_eq!(client.command(&["GET", "persistent"]).await, Message::Null);
+    assert_eq!(
+        client.command(&["GETEX", "missing", "PX", "50"]).await,
+        Message::Null
+    );
+
+    // A condition needs an expiry to apply to, and conflicts like it does for EXPIRE
+    client.command(&["SET", "key", "value"]).await;
+
+    let syntax_error = Message::Error("ERR syntax error".to_string());
+
+    assert_eq!(client.command(&["GETEX", "key", "NX"]).await, syntax_error);
+    assert_eq!(
+        client.command(&["GETEX", "key", "PERSIST", "GT"]).await,
+        syntax_error
+    );
+    assert_eq!(
+        client
+            .command(&["GETEX", "key", "EX", "10", "PERSIST"])
+            .await,
+        syntax_error
+    );
+    assert_eq!(
+        client
+            .command(&["GETEX", "key", "NX", "GT", "EX", "10"])
+            .await,
+        Message::Error(
+            "ERR NX and XX, GT or LT options at the same time are not compatible".to_string()
+        )
+    );
+    assert_eq!(
+        client.command(&["GETEX", "key", "EX", "0"]).await,
+        Message::Error("ERR invalid expire time in 'getex' command".to_string())
+    );
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn blocking_xread_wakes_up_on_xadd() {
+    let server = TestServer::start(&[]).await;
+    let mut reader = server.client().await;
+    let mut writer = server.client().await;
+
+    reader
+        .send(&[
+            "XREAD", "BLOCK", "1000", "STREAMS", "other", "stream", "$", "$",
+        ])
+        .await;
+
+    // Long enough for the reader to be waiting, far from its timeout
+    sleep(Duration::from_millis(100)).await;
+
+    writer.command(&["XADD", "stream", "1-1", "a", "1"]).await;
+    let added = Instant::now();
+
+    // Any of the streams is enough, the other one never gets an entry
+    assert_eq!(
+        reader.read().await,
+        Message::Array(vec![Message::Array(vec![
+            bulk("stream"),
+            Message::Array(vec![Message::Array(vec![
+                bulk("1-1"),
+                Message::Array(vec![bulk("a"), bulk("1")])
+            ])])
+        ])])
+    );
+    assert!(added.elapsed() < Duration::from_millis(100));
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn type_reports_every_kind_of_key() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    client.command(&["SET", "string", "value"]).await;
+    client.command(&["XADD", "stream", "1-1", "a", "1"]).await;
+
+    for (key, value_type) in [
+        ("string", "string"),
+        ("stream", "stream"),
+        ("missing", "none"),
+    ] {
+        assert_eq!(client.command(&["TYPE", key]).await, simple(value_type));
+    }
+
+    assert_eq!(
+        client.command(&["TYPE"]).await,
+        Message::Error("ERR wrong number of arguments for 'type' command".to_string())
+    );
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn del_counts_the_keys_it_removed() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    client.command(&["SET", "string", "value"]).await;
+    client.command(&["XADD", "stream", "1-1", "a", "1"]).await;
+
+    assert_eq!(
+        client
+            .command(&["DEL", "string", "stream", "missing"])
+            .await,
+        Message::Integer(2)
+    );
+    assert_eq!(
+        client.command(&["DEL", "string"]).await,
+        Message::Integer(0)
+    );
+    assert_eq!(client.command(&["TYPE", "stream"]).await, simple("none"));
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn renamed_commands_only_run_under_their_new_name() {
+    let server = TestServer::start(&[
+        "--rename-command",
+        "FLUSHALL",
+        "wipe",
+        "--rename-command",
+        "DEBUG",
+        "",
+    ])
+    .await;
+    let mut client = server.client().await;
+
+    client.command(&["SET", "key", "value"]).await;
+
+    assert_eq!(
+        client.command(&["FLUSHALL"]).await,
+        Message::Error("ERR unknown command 'FLUSHALL', with args beginning with: ".to_string())
+    );
+    assert_eq!(
+        client.command(&["DEBUG", "SLEEP", "0"]).await,
+        Message::Error(
+            "ERR unknown command 'DEBUG', with args beginning with: 'SLEEP' '0' ".to_string()
+        )
+    );
+    assert_eq!(client.command(&["GET", "key"]).await, bulk("value"));
+
+    assert_eq!(client.command(&["WIPE"]).await, simple("OK"));
+    assert_eq!(client.command(&["GET", "key"]).await, Message::Null);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn config_files_rename_commands() {
+    let path = std::env::temp_dir().join(format!("redis-test-{}.conf", std::process::id()));
+    std::fs::write(
+        &path,
+        "# Hardening\n\
+         rename-command FLUSHALL \"\"\n\
+         rename-command DEBUG 'dbg'\n\
+         \n\
+         port 1\n",
+    )
+    .unwrap();
+
+    // The --port 0 every test server gets wins over the port of the file
+    let server = TestServer::start(&[path.to_str().unwrap()]).await;
+    let mut client = server.client().await;
+
+    assert_eq!(
+        client.command(&["FLUSHALL"]).await,
+        Message::Error("ERR unknown command 'FLUSHALL', with args beginning with: ".to_string())
+    );
+    assert_eq!(client.command(&["DBG", "SLEEP", "0"]).await, simple("OK"));
+
+    server.stop().await;
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn debug_sleep_stalls_the_other_clients() {
+    let server = TestServer::start(&[]).await;
+    let mut sleeper = server.client().await;
+    let mut client = server.client().await;
+
+    client.command(&["SET", "key", "value"]).await;
+
+    let started = Instant::now();
+    sleeper.send(&["DEBUG", "SLEEP", "0.3"]).await;
+    // Gives the sleep a head start to take the store
+    sleep(Duration::from_millis(50)).await;
+
+    assert_eq!(client.command(&["GET", "key"]).await, bulk("value"));
+    assert!(started.elapsed() >= Duration::from_millis(300));
+    assert_eq!(sleeper.read().await, simple("OK"));
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn streams_expire_like_any_other_key() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    client.command(&["XADD", "stream", "1-1", "a", "1"]).await;
+
+    assert_eq!(
+        client.command(&["EXPIRE", "stream", "100"]).await,
+        Message::Integer(1)
+    );
+    // NX only holds for keys without an expiry
+    assert_eq!(
+        client.command(&["EXPIRE", "stream", "200", "NX"]).await,
+        Message::Integer(0)
+    );
+    assert_eq!(
+        client.command(&["PERSIST", "stream"]).await,
+        Message::Integer(1)
+    );
+    assert_eq!(
+        client.command(&["PERSIST", "stream"]).await,
+        Message::Integer(0)
+    );
+
+    assert_eq!(
+        client.command(&["PEXPIRE", "stream", "50"]).await,
+        Message::Integer(1)
+    );
+    sleep(Duration::from_millis(100)).await;
+
+    assert_eq!(client.command(&["TYPE", "stream"]).await, simple("none"));
+    assert_eq!(
+        client.command(&["XRANGE", "stream", "-", "+"]).await,
+        Message::Array(vec![])
+    );
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn unix_socket_clients_run_commands() {
+    let path = std::env::temp_dir().join(format!("redis-test-{}.sock", std::process::id()));
+    let server = TestServer::start(&["--unixsocket", path.to_str().unwrap()]).await;
+    let mut client = TestClient::connect_unix(&path).await;
+
+    assert_eq!(client.command(&["PING"]).await, simple("PONG"));
+    assert_eq!(client.command(&["SET", "key", "value"]).await, simple("OK"));
+    assert_eq!(client.command(&["GET", "key"]).await, bulk("value"));
+
+    let list = match client.command(&["CLIENT", "LIST"]).await {
+        Message::BulkString(list) => list,
+        reply => panic!("unexpected CLIENT LIST reply {:?}", reply),
+    };
+    assert!(
+        list.contains(&format!("addr={}:0 ", path.display())),
+        "{}",
+        list
+    );
+
+    server.stop().await;
+    _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn gets_count_keyspace_hits_and_misses() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    client.command(&["SET", "present", "value"]).await;
+    client.command(&["GET", "present"]).await;
+    client.command(&["GET", "present"]).await;
+    client.command(&["GET", "missing"]).await;
+
+    let info = match client.command(&["INFO", "stats"]).await {
+        Message::BulkString(info) => info,
+        reply => panic!("unexpected INFO reply {:?}", reply),
+    };
+
+    assert!(info.contains("keyspace_hits:2\n"), "{}", info);
+    assert!(info.contains("keyspace_misses:1\n"), "{}", info);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn exists_counts_a_lookup_per_key() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    client.command(&["SET", "present", "value"]).await;
+    client
+        .command(&["EXISTS", "present", "missing", "present", "other"])
+        .await;
+
+    let info = match client.command(&["INFO", "stats"]).await {
+        Message::BulkString(info) => info,
+        reply => panic!("unexpected INFO reply {:?}", reply),
+    };
+
+    assert!(info.contains("keyspace_hits:2\n"), "{}", info);
+    assert!(info.contains("keyspace_misses:2\n"), "{}", info);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn keys_over_a_large_keyspace_lets_writers_in() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    const KEYS: usize = 100_000;
+    let mut buffer = BytesMut::new();
+
+    for index in 0..KEYS {
+        let key = format!("key:{}", index);
+        request(&["SET", &key, "value"]).write_to(&mut buffer);
+    }
+
+    client.send_raw(&buffer).await;
+
+    for _ in 0..KEYS {
+        client.read().await;
+    }
+
+    let mut keys_client = server.client().await;
+    keys_client.send(&["KEYS", "key:*"]).await;
+
+    let keys = tokio::spawn(async move { keys_client.read().await });
+
+    // Writes keep going while KEYS walks the keyspace
+    let mut writes = 0;
+
+    while !keys.is_finished() {
+        assert_eq!(
+            client.command(&["SET", "other", "value"]).await,
+            simple("OK")
+        );
+        writes += 1;
+    }
+
+    match keys.await.unwrap() {
+        Message::Array(keys) => assert_eq!(keys.len(), KEYS),
+        reply => panic!("unexpected KEYS reply {:?}", reply),
+    }
+
+    // A KEYS holding the store for the whole walk only lets through the SET that was waiting, and
+    // the few that run while its reply is being read
+    assert!(writes >= 20, "{}", writes);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn dump_and_restore_round_trip() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    client.command(&["SET", "key", "value"]).await;
+
+    let payload = match client.command(&["DUMP", "key"]).await {
+        Message::BulkString(payload) => payload.into_bytes(),
+        Message::BulkBytes(payload) => payload,
+        reply => panic!("unexpected DUMP reply {:?}", reply),
+    };
+    let restore = |key: &str, options: &[&str]| {
+        let mut args = vec![bulk("RESTORE"), bulk(key), bulk("0")];
+        args.push(Message::BulkBytes(payload.clone()));
+        args.extend(options.iter().map(|option| bulk(option)));
+
+        Message::Array(args)
+    };
+
+    client.send_message(&restore("copy", &[])).await;
+    assert_eq!(client.read().await, simple("OK"));
+    assert_eq!(client.command(&["GET", "copy"]).await, bulk("value"));
+
+    client.send_message(&restore("copy", &[])).await;
+    assert_eq!(
+        client.read().await,
+        Message::Error("BUSYKEY Target key name already exists.".to_string())
+    );
+
+    client.command(&["SET", "copy", "other"]).await;
+    client.send_message(&restore("copy", &["REPLACE"])).await;
+    assert_eq!(client.read().await, simple("OK"));
+    assert_eq!(client.command(&["GET", "copy"]).await, bulk("value"));
+
+    assert_eq!(client.command(&["DUMP", "missing"]).await, Message::Null);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn dump_and_restore_round_trip_a_stream() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    // Enough entries for several nodes, not all of them with the same fields
+    for n in 1..=150 {
+        let id = format!("{n}-0");
+        let value = n.to_string();
+        let field = if n % 3 == 0 { "b" } else { "a" };
+        client
+            .command(&["XADD", "stream", &id, field, &value])
+            .await;
+    }
+
+    let payload = match client.command(&["DUMP", "stream"]).await {
+        Message::BulkString(payload) => payload.into_bytes(),
+        Message::BulkBytes(payload) => payload,
+        reply => panic!("unexpected DUMP reply {:?}", reply),
+    };
+
+    client
+        .send_message(&Message::Array(vec![
+            bulk("RESTORE"),
+            bulk("copy"),
+            bulk("0"),
+            Message::BulkBytes(payload),
+        ]))
+        .await;
+    assert_eq!(client.read().await, simple("OK"));
+
+    assert_eq!(client.command(&["TYPE", "copy"]).await, simple("stream"));
+    assert_eq!(
+        client.command(&["XRANGE", "copy", "-", "+"]).await,
+        client.command(&["XRANGE", "stream", "-", "+"]).await
+    );
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn restore_rejects_corrupted_payloads() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    client.command(&["SET", "key", "value"]).await;
+
+    let mut payload = match client.command(&["DUMP", "key"]).await {
+        Message::BulkString(payload) => payload.into_bytes(),
+        Message::BulkBytes(payload) => payload,
+        reply => panic!("unexpected DUMP reply {:?}", reply),
+    };
+    payload[3] ^= 0xFF;
+
+    client
+        .send_message(&Message::Array(vec![
+            bulk("RESTORE"),
+            bulk("copy"),
+            bulk("0"),
+            Message::BulkBytes(payload),
+        ]))
+        .await;
+
+    assert_eq!(
+        client.read().await,
+        Message::Error("ERR DUMP payload version or checksum are wrong".to_string())
+    );
+    assert_eq!(client.command(&["GET", "copy"]).await, Message::Null);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn pidfile_lives_as_long_as_the_server() {
+    let path = std::env::temp_dir().join(format!("redis-test-{}.pid", std::process::id()));
+    let path_arg = path.to_str().unwrap();
+
+    let server = TestServer::start(&["--pidfile", path_arg, "--daemonize", "yes"]).await;
+
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        format!("{}\n", std::process::id())
+    );
+
+    server.stop().await;
+
+    assert!(!path.exists());
+}
+
+#[tokio::test]
+async fn cluster_keyslot_follows_hash_tags() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    // Check value of the crc16 implementation in the redis source, 0x31C3 modulo 16384
+    for (key, slot) in [
+        ("123456789", 12739),
+        ("somekey", 11058),
+        ("foo{hash_tag}", 2515),
+        ("{user1000}.following", 3443),
+        ("{user1000}.followers", 3443),
+        ("foo{}{bar}", 8363),
+        ("foo{{bar}}zap", 4015),
+    ] {
+        assert_eq!(
+            client.command(&["CLUSTER", "KEYSLOT", key]).await,
+            Message::Integer(slot),
+            "{}",
+            key
+        );
+    }
+
+    assert_eq!(
+        client.command(&["CLUSTER", "SLOTS"]).await,
+        Message::Array(vec![])
+    );
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn config_get_replies_with_name_value_pairs() {
+    let server = TestServer::start(&["--dbfilename", "dump.rdb"]).await;
+    let mut client = server.client().await;
+
+    assert_eq!(
+        client.command(&["CONFIG", "GET", "dbfilename"]).await,
+        Message::Array(vec![bulk("dbfilename"), bulk("dump.rdb")])
+    );
+    assert_eq!(
+        client.command(&["CONFIG", "GET", "port"]).await,
+        Message::Array(vec![bulk("port"), bulk(&server.port.to_string())])
+    );
+    assert_eq!(
+        client
+            .command(&["CONFIG", "GET", "appendonly", "maxmemory"])
+            .await,
+        Message::Array(vec![
+            bulk("maxmemory"),
+            bulk("0"),
+            bulk("appendonly"),
+            bulk("no")
+        ])
+    );
+    assert_eq!(
+        client
+            .command(&["CONFIG", "GET", "no-such-parameter"])
+            .await,
+        Message::Array(vec![])
+    );
+
+    assert_eq!(
+        client.command(&["CONFIG", "SET", "maxmemory", "100"]).await,
+        simple("OK")
+    );
+    assert_eq!(
+        client.command(&["CONFIG", "GET", "maxmemory"]).await,
+        Message::Array(vec![bulk("maxmemory"), bulk("100")])
+    );
+    assert!(matches!(
+        client.command(&["CONFIG", "SET", "port", "1234"]).await,
+        Message::Error(_)
+    ));
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn listens_on_every_bind_address() {
+    let server = TestServer::start(&["--bind", "127.0.0.1 127.0.0.2"]).await;
+
+    let addrs = server.server.local_addrs();
+    assert_eq!(addrs.len(), 2);
+    assert!(addrs.iter().all(|addr| addr.port() == server.port));
+
+    for addr in addrs {
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"*1\r\n$4\r\nPING\r\n").await.unwrap();
+
+        let mut reply = [0; 7];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"+PONG\r\n");
+    }
+
+    let mut client = server.client().await;
+    let port = server.port.to_string();
+
+    assert_eq!(
+        client.command(&["CONFIG", "GET", "port"]).await,
+        Message::Array(vec![bulk("port"), bulk(&port)])
+    );
+
+    let info = match client.command(&["INFO", "server"]).await {
+        Message::BulkString(info) => info,
+        reply => panic!("unexpected INFO reply {:?}", reply),
+    };
+    assert!(info.contains(&format!("tcp_port:{}\n", port)), "{}", info);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn counters_are_stored_as_integers() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    assert_eq!(
+        client.command(&["INCR", "counter"]).await,
+        Message::Integer(1)
+    );
+    assert_eq!(
+        client.command(&["INCRBY", "counter", "41"]).await,
+        Message::Integer(42)
+    );
+    assert_eq!(
+        client.command(&["OBJECT", "ENCODING", "counter"]).await,
+        bulk("int")
+    );
+    assert_eq!(client.command(&["GET", "counter"]).await, bulk("42"));
+
+    // Digits written in place are a plain string, but still count up the same
+    client.command(&["SETRANGE", "digits", "0", "12"]).await;
+    assert_eq!(
+        client.command(&["OBJECT", "ENCODING", "digits"]).await,
+        bulk("raw")
+    );
+    assert_eq!(
+        client.command(&["DECR", "digits"]).await,
+        Message::Integer(11)
+    );
+
+    client.command(&["SET", "padded", "007"]).await;
+    assert_eq!(
+        client.command(&["INCR", "padded"]).await,
+        Message::Error("ERR value is not an integer or out of range".to_string())
+    );
+
+    client.command(&["SET", "max", &i64::MAX.to_string()]).await;
+    assert_eq!(
+        client.command(&["INCR", "max"]).await,
+        Message::Error("ERR increment or decrement would overflow".to_string())
+    );
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn set_conditions_and_get() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    assert_eq!(
+        client.command(&["SET", "key", "first", "XX"]).await,
+        Message::Null
+    );
+    assert_eq!(
+        client.command(&["SET", "key", "first", "NX"]).await,
+        simple("OK")
+    );
+    assert_eq!(
+        client.command(&["SET", "key", "second", "NX", "GET"]).await,
+        bulk("first")
+    );
+    assert_eq!(
+        client.command(&["SET", "key", "third", "GET"]).await,
+        bulk("first")
+    );
+    assert_eq!(client.command(&["GET", "key"]).await, bulk("third"));
+
+    client
+        .command(&["SET", "ttl", "value", "PX", "100000"])
+        .await;
+    client.command(&["SET", "ttl", "other", "KEEPTTL"]).await;
+
+    let expiry_at = server
+        .server
+        .store()
+        .read()
+        .await
+        .expiry_at(&"ttl".to_string());
+    assert!(expiry_at.is_some());
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn bitmaps_are_raw_strings() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    assert_eq!(
+        client.command(&["SETBIT", "bitmap", "1", "1"]).await,
+        Message::Integer(0)
+    );
+    assert_eq!(
+        client.command(&["SETBIT", "bitmap", "7", "1"]).await,
+        Message::Integer(0)
+    );
+    assert_eq!(
+        client.command(&["SETBIT", "bitmap", "7", "1"]).await,
+        Message::Integer(1)
+    );
+
+    assert_eq!(client.command(&["GET", "bitmap"]).await, bulk("A"));
+    assert_eq!(
+        client.command(&["GETBIT", "bitmap", "1"]).await,
+        Message::Integer(1)
+    );
+    assert_eq!(
+        client.command(&["GETBIT", "bitmap", "100"]).await,
+        Message::Integer(0)
+    );
+
+    assert_eq!(client.command(&["TYPE", "bitmap"]).await, simple("string"));
+    assert_eq!(
+        client.command(&["OBJECT", "ENCODING", "bitmap"]).await,
+        bulk("raw")
+    );
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn oversized_inline_requests_close_the_connection() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    client
+        .send_raw(b"SET inline  value\r\n\r\nGET inline\n")
+        .await;
+
+    assert_eq!(client.read().await, simple("OK"));
+    assert_eq!(client.read().await, bulk("value"));
+
+    client.send_raw(&vec![b'a'; 100 * 1024]).await;
+
+    assert_eq!(
+        client.read().await,
+        Message::Error("ERR Protocol error: too big inline request".to_string())
+    );
+    assert_eq!(client.read_message().await, None);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn oversized_length_headers_close_the_connection() {
+    let server = TestServer::start(&[]).await;
+
+    for (header, error) in [
+        (
+            b"*".to_vec(),
+            "ERR Protocol error: too big mbulk count string",
+        ),
+        (
+            b"*1\r\n$".to_vec(),
+            "ERR Protocol error: too big bulk count string",
+        ),
+    ] {
+        let mut client = server.client().await;
+        let mut request = header;
+        request.extend(vec![b'1'; 100 * 1024]);
+
+        client.send_raw(&request).await;
+
+        assert_eq!(client.read().await, Message::Error(error.to_string()));
+        assert_eq!(client.read_message().await, None);
+    }
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn debug_stringmatch_len_runs_the_glob_matcher() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    for (pattern, string, matched) in [
+        ("h[a-c]llo", "hbllo", 1),
+        ("h[a-c]llo", "hdllo", 0),
+        ("h\\*llo", "h*llo", 1),
+        ("h\\*llo", "hello", 0),
+        ("h?llo", "hello", 1),
+        ("h?llo", "hllo", 0),
+    ] {
+        assert_eq!(
+            client
+                .command(&["DEBUG", "STRINGMATCH-LEN", pattern, string])
+                .await,
+            Message::Integer(matched),
+            "{:?} against {:?}",
+            pattern,
+            string
+        );
+    }
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn debug_set_active_expire_pauses_the_sweep() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    assert_eq!(
+        client.command(&["DEBUG", "SET-ACTIVE-EXPIRE", "0"]).await,
+        simple("OK")
+    );
+    assert_eq!(
+        client
+            .command(&["DEBUG", "QUICKLIST-PACKED-THRESHOLD", "100"])
+            .await,
+        simple("OK")
+    );
+
+    client.command(&["SET", "key", "value", "PX", "1"]).await;
+    sleep(Duration::from_millis(300)).await;
+
+    assert_eq!(client.command(&["DBSIZE"]).await, Message::Integer(1));
+    assert_eq!(client.command(&["GET", "key"]).await, Message::Null);
+    assert_eq!(client.command(&["DBSIZE"]).await, Message::Integer(0));
+
+    client.command(&["DEBUG", "SET-ACTIVE-EXPIRE", "1"]).await;
+    client.command(&["SET", "key", "value", "PX", "1"]).await;
+    sleep(Duration::from_millis(300)).await;
+
+    assert_eq!(client.command(&["DBSIZE"]).await, Message::Integer(0));
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn client_reply_off_and_skip_drop_replies() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    let mut pipeline = String::new();
+    for args in [
+        &["CLIENT", "REPLY", "OFF"][..],
+        &["SET", "a", "1"],
+        &["SET", "b", "2"],
+        &["GET", "a"],
+        &["CLIENT", "REPLY", "ON"],
+        &["CLIENT", "REPLY", "SKIP"],
+        &["GET", "a"],
+        &["GET", "b"],
+    ] {
+        pipeline.push_str(&request(args).serialize().unwrap());
+    }
+
+    client.send_raw(pipeline.as_bytes()).await;
+
+    assert_eq!(client.read().await, simple("OK"));
+    assert_eq!(client.read().await, bulk("2"));
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn connections_have_independent_client_state() {
+    let server = TestServer::start(&[]).await;
+    let mut first = server.client().await;
+    let mut second = server.client().await;
+
+    let (first_id, second_id) = match (
+        first.command(&["CLIENT", "ID"]).await,
+        second.command(&["CLIENT", "ID"]).await,
+    ) {
+        (Message::Integer(first_id), Message::Integer(second_id)) => (first_id, second_id),
+        replies => panic!("CLIENT ID didn't reply with integers: {:?}", replies),
+    };
+    assert_ne!(first_id, second_id);
+
+    assert_eq!(
+        first.command(&["CLIENT", "SETNAME", "first"]).await,
+        simple("OK")
+    );
+    assert_eq!(first.command(&["CLIENT", "GETNAME"]).await, bulk("first"));
+    assert_eq!(second.command(&["CLIENT", "GETNAME"]).await, Message::Null);
+
+    first.send(&["CLIENT", "REPLY", "OFF"]).await;
+    assert_eq!(second.command(&["PING"]).await, simple("PONG"));
+
+    let list = match second.command(&["CLIENT", "LIST"]).await {
+        Message::BulkString(list) => list,
+        reply => panic!("CLIENT LIST didn't reply with a bulk string: {:?}", reply),
+    };
+    assert!(list.contains(&format!("id={} ", first_id)) && list.contains("name=first "));
+    assert!(list.contains(&format!("id={} ", second_id)) && list.contains("cmd=client"));
+
+    first.send(&["CLIENT", "REPLY", "ON"]).await;
+    assert_eq!(first.read().await, simple("OK"));
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn tcp_socket_options_are_configurable() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    assert_eq!(
+        client.command(&["CONFIG", "GET", "tcp-*"]).await,
+        Message::Array(vec![
+            bulk("tcp-nodelay"),
+            bulk("yes"),
+            bulk("tcp-keepalive"),
+            bulk("0")
+        ])
+    );
+
+    server.stop().await;
+
+    let server = TestServer::start(&["--tcp-nodelay", "no", "--tcp-keepalive", "0"]).await;
+    let mut client = server.client().await;
+
+    assert_eq!(client.command(&["PING"]).await, simple("PONG"));
+    assert_eq!(
+        client.command(&["CONFIG", "GET", "tcp-*"]).await,
+        Message::Array(vec![
+            bulk("tcp-nodelay"),
+            bulk("no"),
+            bulk("tcp-keepalive"),
+            bulk("0")
+        ])
+    );
+
+    server.stop().await;
+
+    // There is no way to turn keepalive on, so asking for it fails rather than being ignored
+    let argv = ["redis", "--port", "0", "--tcp-keepalive", "60"];
+    let config = CommandLineArgs::load(argv.iter().map(|arg| arg.to_string()).collect()).unwrap();
+    assert!(Server::bind(config).await.is_err());
+}
+
+#[tokio::test]
+async fn memory_usage_grows_with_the_value() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    client.command(&["SET", "short", "a"]).await;
+    client.command(&["SET", "long", &"a".repeat(1000)]).await;
+
+    let (short, long) = match (
+        client.command(&["MEMORY", "USAGE", "short"]).await,
+        client
+            .command(&["MEMORY", "USAGE", "long", "SAMPLES", "0"])
+            .await,
+    ) {
+        (Message::Integer(short), Message::Integer(long)) => (short, long),
+        replies => panic!("MEMORY USAGE didn't reply with integers: {:?}", replies),
+    };
+
+    assert!(long > short + 900);
+    assert_eq!(
+        client.command(&["MEMORY", "USAGE", "missing"]).await,
+        Message::Null
+    );
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn stream_entries_keep_their_field_order() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    client
+        .command(&[
+            "XADD", "stream", "1-1", "sensor", "1", "temp", "20", "humidity", "95",
+        ])
+        .await;
+
+    let mut stream = TcpStream::connect(server.server.local_addrs()[0])
+        .await
+        .unwrap();
+    stream
+        .write_all(
+            &request(&["XRANGE", "stream", "-", "+"])
+                .serialize()
+                .unwrap()
+                .into_bytes(),
+        )
+        .await
+        .unwrap();
+
+    let expected = b"*1\r\n*2\r\n$3\r\n1-1\r\n*6\r\n\
+        $6\r\nsensor\r\n$1\r\n1\r\n$4\r\ntemp\r\n$2\r\n20\r\n$8\r\nhumidity\r\n$2\r\n95\r\n";
+    let mut reply = vec![0; expected.len()];
+    stream.read_exact(&mut reply).await.unwrap();
+
+    assert_eq!(reply, expected);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn debug_reload_keeps_every_type() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    client.command(&["SET", "text", "hello world"]).await;
+    client.command(&["SET", "counter", "-123456789"]).await;
+    client
+        .command(&["SET", "volatile", "value", "EX", "100"])
+        .await;
+
+    // Enough entries for more than one node, not all of them with the fields of the first
+    for entry in 1..=150 {
+        // Every tenth entry moves on to the next millisecond
+        let id = format!("{}-{}", 1000 + entry / 10, entry);
+        let value = entry.to_string();
+
+        if entry % 7 == 0 {
+            client
+                .command(&["XADD", "stream", &id, "other", &value])
+                .await;
+        } else {
+            client
+                .command(&["XADD", "stream", &id, "sensor", "1", "temp", &value])
+                .await;
+        }
+    }
+    client.command(&["XADD", "single", "5-5", "a", "b"]).await;
+
+    let keys = ["text", "counter", "volatile"];
+    let mut before = Vec::new();
+    for key in keys {
+        before.push(client.command(&["GET", key]).await);
+    }
+    let stream = client.command(&["XRANGE", "stream", "-", "+"]).await;
+
+    assert_eq!(client.command(&["DEBUG", "RELOAD"]).await, simple("OK"));
+
+    for (key, value) in keys.into_iter().zip(before) {
+        assert_eq!(client.command(&["GET", key]).await, value);
+    }
+    assert_eq!(
+        client.command(&["XRANGE", "stream", "-", "+"]).await,
+        stream
+    );
+    assert_eq!(
+        client.command(&["XRANGE", "single", "-", "+"]).await,
+        Message::Array(vec![Message::Array(vec![
+            bulk("5-5"),
+            Message::Array(vec![bulk("a"), bulk("b")]),
+        ])])
+    );
+    assert_eq!(client.command(&["DBSIZE"]).await, Message::Integer(5));
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn xinfo_stream_describes_the_stream() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    client.command(&["XADD", "stream", "1-1", "a", "1"]).await;
+    client.command(&["XADD", "stream", "2-1", "b", "2"]).await;
+
+    let info = match client.command(&["XINFO", "STREAM", "stream"]).await {
+        Message::Array(info) => info,
+        reply => panic!("XINFO replied with {:?}", reply),
+    };
+    let field = |name: &str| {
+        info.chunks(2)
+            .find(|pair| pair[0] == bulk(name))
+            .map(|pair| pair[1].clone())
+            .unwrap_or_else(|| panic!("XINFO is missing {}", name))
+    };
+
+    assert_eq!(field("length"), Message::Integer(2));
+    assert_eq!(field("radix-tree-keys"), Message::Integer(1));
+    assert_eq!(field("radix-tree-nodes"), Message::Integer(2));
+    assert_eq!(field("last-generated-id"), bulk("2-1"));
+    assert_eq!(
+        field("first-entry"),
+        Message::Array(vec![
+            bulk("1-1"),
+            Message::Array(vec![bulk("a"), bulk("1")])
+        ])
+    );
+
+    client.command(&["SET", "string", "value"]).await;
+    assert_eq!(
+        client.command(&["XINFO", "STREAM", "string"]).await,
+        Message::Error(
+            "WRONGTYPE Operation against a key holding the wrong kind of value".to_string()
+        )
+    );
+    assert_eq!(
+        client.command(&["XINFO", "STREAM", "missing"]).await,
+        Message::Error("ERR no such key".to_string())
+    );
+
+    server.stop().await;
+}
+
+async fn writes_processed(client: &mut TestClient) -> usize {
+    let info = match client.command(&["INFO", "stats"]).await {
+        Message::BulkString(info) => info,
+        reply => panic!("unexpected INFO reply {:?}", reply),
+    };
+
+    info.lines()
+        .find_map(|line| line.strip_prefix("total_writes_processed:"))
+        .and_then(|count| count.parse().ok())
+        .expect("INFO is missing total_writes_processed")
+}
+
+#[tokio::test]
+async fn pipelined_replies_are_ordered_and_written_together() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+    let writes_before = writes_processed(&mut client).await;
+
+    let mut pipeline = Vec::new();
+    for key in 0..1000 {
+        let key = key.to_string();
+        pipeline.extend(
+            request(&["SET", &key, &key])
+                .serialize()
+                .unwrap()
+                .into_bytes(),
+        );
+    }
+    for key in 0..1000 {
+        pipeline.extend(
+            request(&["GET", &key.to_string()])
+                .serialize()
+                .unwrap()
+                .into_bytes(),
+        );
+    }
+    client.send_raw(&pipeline).await;
+
+    for _ in 0..1000 {
+        assert_eq!(client.read().await, simple("OK"));
+    }
+    for key in 0..1000 {
+        assert_eq!(client.read().await, bulk(&key.to_string()));
+    }
+
+    // A write per read of the pipeline, rather than one for each of the 2000 replies
+    let writes = writes_processed(&mut client).await - writes_before;
+    assert!(writes < 100, "{} writes", writes);
+
+    server.stop().await;
+}
+
+#[tokio::test]
+async fn copy_expires_with_its_source() {
+    let server = TestServer::start(&[]).await;
+    let mut client = server.client().await;
+
+    client
+        .command(&["SET", "source", "value", "PX", "200"])
+        .await;
+
+    assert_eq!(
+        client.command(&["COPY", "source", "copy", "DB", "0"]).await,
+        Message::Integer(1)
+    );
+    assert_eq!(client.command(&["GET", "copy"]).await, bulk("value"));
+    assert_eq!(
+        client.command(&["COPY", "source", "copy"]).await,
+        Message::Integer(0)
+    );
+    assert_eq!(
+        client
+            .command(&["COPY", "source", "other", "DB", "1"])
+            .await,
+        Message::Error("ERR DB index is out of range".to_string())
+    );
+
+    sleep(Duration::from_millis(250)).await;
+
+    assert_eq!(client.command(&["GET", "source"]).await, Message::Null);
+    assert_eq!(client.command(&["GET", "copy"]).await, Message::Null);
+
+    server.stop().await;
+}
@@ -0,0 +1,475 @@
+use std::{
+    collections::HashSet,
+    time::{Duration, SystemTime},
+};
+
+use redis_starter_rust::{CommandError, Entry, ExpireCondition, Store, StreamData};
+
+// Nothing runs the active expiry on a bare store, so the expired key stays in the map
+#[test]
+fn expired_keys_are_invisible_before_they_are_removed() {
+    let mut store = Store::new();
+
+    store.set_kv_value(
+        "expired".to_string(),
+        Entry {
+            value: "value".to_string().into(),
+            expiry_at: Some(SystemTime::now() - Duration::from_millis(50)),
+        },
+    );
+    store.set_kv_value("live".to_string(), Entry::new("value".to_string(), None));
+
+    let expired = "expired".to_string();
+
+    assert_eq!(store.len(), 2);
+    assert!(!store.exists(&expired));
+    assert!(store.get_value(&expired).is_none());
+    assert!(store.get_kv_value(&expired).unwrap().is_none());
+    assert_eq!(store.scan(0, 10), (0, vec!["live".to_string()]));
+
+    for _ in 0..20 {
+        assert_eq!(store.random_key(), Some("live".to_string()));
+    }
+}
+
+// The queue keeps the expiries a key had before, only the latest one may remove it
+#[test]
+fn overwritten_expiries_are_neither_early_nor_missed() {
+    let soon = SystemTime::now() + Duration::from_millis(30);
+    let later = SystemTime::now() + Duration::from_secs(100);
+    let key = "key".to_string();
+
+    let with_expiry = |expiry_at| Entry {
+        value: "value".to_string().into(),
+        expiry_at,
+    };
+    let stores = |count| (0..count).map(|_| Store::new()).collect::<Vec<_>>();
+
+    // Every store gets the key with an expiry that is soon due, which is then replaced
+    let mut kept = stores(4);
+    for store in &mut kept {
+        store.set_kv_value(key.clone(), with_expiry(Some(soon)));
+    }
+    kept[0].set_kv_value(key.clone(), with_expiry(Some(later)));
+    assert!(kept[1].set_expiry(&key, later, &[]));
+    assert!(kept[2].persist(&key));
+    assert!(kept[3].delete(&key));
+    kept[3].set_kv_value(key.clone(), with_expiry(None));
+
+    // Or the other way around, with a later expiry replaced by one that is soon due
+    let mut removed = stores(3);
+    for store in &mut removed {
+        store.set_kv_value(key.clone(), with_expiry(Some(later)));
+    }
+    removed[0].set_kv_value(key.clone(), with_expiry(Some(soon)));
+    assert!(removed[1].set_expiry(&key, soon, &[]));
+    for _ in 0..10 {
+        removed[2].set_kv_value(key.clone(), with_expiry(Some(soon)));
+    }
+
+    std::thread::sleep(Duration::from_millis(50));
+
+    for (index, store) in kept.iter_mut().enumerate() {
+        assert!(store.remove_expired_keys(100).is_empty(), "{}", index);
+        assert!(store.exists(&key), "{}", index);
+    }
+
+    // Removed once, however often the expiry was set
+    for (index, store) in removed.iter_mut().enumerate() {
+        assert_eq!(
+            store.remove_expired_keys(100),
+            vec![key.clone()],
+            "{}",
+            index
+        );
+        assert!(store.remove_expired_keys(100).is_empty(), "{}", index);
+        assert_eq!(store.len(), 0, "{}", index);
+    }
+}
+
+#[test]
+fn scan_returns_every_key_present_throughout() {
+    let mut store = Store::new();
+
+    let original: Vec<_> = (0..500).map(|i| format!("key:{}", i)).collect();
+    for key in &original {
+        store.set_kv_value(key.clone(), Entry::new("value".to_string(), None));
+    }
+
+    let mut seen = HashSet::new();
+    let mut cursor = 0;
+    let mut round = 0;
+
+    loop {
+        let (next, keys) = store.scan(cursor, 10);
+        seen.extend(keys);
+
+        // Grow the map well past its size while iterating, and drop some of what was added
+        for i in 0..20 {
+            store.set_kv_value(
+                format!("new:{}:{}", round, i),
+                Entry::new("value".to_string(), None),
+            );
+        }
+        for i in 0..10 {
+            store.delete(&format!("new:{}:{}", round, i));
+        }
+
+        round += 1;
+        cursor = next;
+
+        if cursor == 0 {
+            break;
+        }
+    }
+
+    assert!(original.iter().all(|key| seen.contains(key)));
+}
+
+#[test]
+fn expire_conditions_follow_the_redis_truth_table() {
+    use ExpireCondition::*;
+
+    let now = SystemTime::now();
+    let current = now + Duration::from_secs(100);
+    let earlier = now + Duration::from_secs(50);
+    let later = now + Duration::from_secs(200);
+
+    // (conditions, current expiry, new expiry, whether it is set)
+    let table: [(&[ExpireCondition], _, _, _); 18] = [
+        (&[], None, later, true),
+        (&[], Some(current), earlier, true),
+        (&[NotExists], None, later, true),
+        (&[NotExists], Some(current), later, false),
+        (&[Exists], None, later, false),
+        (&[Exists], Some(current), earlier, true),
+        (&[GreaterThan], None, later, false),
+        (&[GreaterThan], Some(current), later, true),
+        (&[GreaterThan], Some(current), earlier, false),
+        (&[GreaterThan], Some(current), current, false),
+        (&[LessThan], None, later, true),
+        (&[LessThan], Some(current), earlier, true),
+        (&[LessThan], Some(current), later, false),
+        (&[LessThan], Some(current), current, false),
+        // XX still needs an expiry to be there, which LT on its own doesn't
+        (&[Exists, LessThan], None, earlier, false),
+        (&[Exists, LessThan], Some(current), earlier, true),
+        (&[Exists, GreaterThan], None, later, false),
+        (&[Exists, GreaterThan], Some(current), later, true),
+    ];
+
+    let key = "key".to_string();
+
+    for (conditions, expiry_at, new_expiry, set) in table {
+        let mut store = Store::new();
+        store.set_kv_value(
+            key.clone(),
+            Entry {
+                value: "value".to_string().into(),
+                expiry_at,
+            },
+        );
+
+        assert_eq!(
+            store.set_expiry(&key, new_expiry, conditions),
+            set,
+            "{:?} with {:?}",
+            conditions,
+            expiry_at
+        );
+        assert_eq!(
+            store.expiry_at(&key),
+            if set { Some(new_expiry) } else { expiry_at }
+        );
+    }
+
+    let missing = "missing".to_string();
+    assert!(!Store::new().set_expiry(&missing, later, &[]));
+}
+
+#[test]
+fn rdb_files_with_several_databases_load_database_zero() {
+    let mut rdb = b"REDIS0011".to_vec();
+    // An aux field, then db 0, db 1 and db 0 again, each with a resizedb hint
+    rdb.extend_from_slice(b"\xFA\x09redis-ver\x057.2.0");
+    rdb.extend_from_slice(b"\xFE\x00\xFB\x01\x00\x00\x03one\x011");
+    rdb.extend_from_slice(b"\xFE\x01\xFB\x01\x00\x00\x03two\x012");
+    rdb.extend_from_slice(b"\xFE\x00\xFB\x01\x00\x00\x05three\x013");
+    rdb.extend_from_slice(b"\xFF\x00\x00\x00\x00\x00\x00\x00\x00");
+
+    let mut store = Store::new();
+    store.import(&rdb);
+
+    assert_eq!(store.len(), 2);
+    assert!(store.exists(&"one".to_string()));
+    assert!(store.exists(&"three".to_string()));
+    assert!(!store.exists(&"two".to_string()));
+}
+
+#[test]
+fn rdb_aux_fields_are_read_as_name_value_pairs() {
+    let mut rdb = b"REDIS0011".to_vec();
+    // The second value is an integer whose bytes hold a 0xFE, which isn't a database selector
+    rdb.extend_from_slice(b"\xFA\x09redis-ver\x057.2.0");
+    rdb.extend_from_slice(b"\xFA\x05ctime\xC2\xFE\x01\x00\x00");
+    rdb.extend_from_slice(b"\xFA\x08aof-base\xC0\x00");
+    rdb.extend_from_slice(b"\xFE\x00\xFB\x01\x00\x00\x03one\x011");
+    rdb.extend_from_slice(b"\xFF\x00\x00\x00\x00\x00\x00\x00\x00");
+
+    let mut store = Store::new();
+    store.import(&rdb);
+
+    assert_eq!(store.len(), 1);
+    assert!(store.exists(&"one".to_string()));
+}
+
+// An rdb length as redis writes it: 6 bits, 14 bits, or a 32 bit big endian number
+fn rdb_length(rdb: &mut Vec<u8>, length: usize) {
+    if length < 1 << 6 {
+        rdb.push(length as u8);
+    } else if length < 1 << 14 {
+        rdb.extend_from_slice(&(0x4000 | length as u16).to_be_bytes());
+    } else {
+        rdb.push(0x80);
+        rdb.extend_from_slice(&(length as u32).to_be_bytes());
+    }
+}
+
+// A single database of `count` string keys, with a resizedb hint matching it
+fn rdb_with_keys(count: usize) -> Vec<u8> {
+    let mut rdb = b"REDIS0011\xFE\x00\xFB".to_vec();
+    rdb_length(&mut rdb, count);
+    rdb_length(&mut rdb, 0);
+
+    for index in 0..count {
+        // A string type entry, the key and the value each prefixed with their length
+        rdb.push(0x00);
+
+        for string in [format!("key:{}", index), format!("value:{}", index)] {
+            rdb_length(&mut rdb, string.len());
+            rdb.extend_from_slice(string.as_bytes());
+        }
+    }
+
+    rdb.extend_from_slice(b"\xFF\x00\x00\x00\x00\x00\x00\x00\x00");
+    rdb
+}
+
+#[test]
+fn rdb_files_with_large_resizedb_hints_load_every_key() {
+    for count in [100, 20000] {
+        let mut store = Store::new();
+        store.import(&rdb_with_keys(count));
+
+        assert_eq!(store.len(), count);
+
+        let last = format!("key:{}", count - 1);
+        let entry = store.get_kv_value(&last).unwrap().unwrap();
+        assert_eq!(
+            entry.value.to_bytes(),
+            format!("value:{}", count - 1).into_bytes()
+        );
+    }
+}
+
+fn rdb_string(rdb: &mut Vec<u8>, string: &str) {
+    rdb_length(rdb, string.len());
+    rdb.extend_from_slice(string.as_bytes());
+}
+
+#[test]
+fn streams_with_consumer_groups_load_without_them() {
+    let key = "stream".to_string();
+    let mut store = Store::new();
+
+    for index in 1..=1000 {
+        let data = StreamData {
+            data: vec![("field".to_string(), index.to_string())],
+        };
+        store
+            .append_stream_value(&key, &format!("{}-0", index), data)
+            .unwrap();
+    }
+
+    // The writer ends the stream with an empty list of groups, swap in one the way redis saves
+    // it, with a pending entry owned by its only consumer
+    let mut rdb = store.export();
+    let end = rdb.len() - 10;
+    assert_eq!(rdb[end..end + 2], [0x00, 0xFF]);
+    rdb.truncate(end);
+
+    let mut pending_id = 1000u64.to_be_bytes().to_vec();
+    pending_id.extend(0u64.to_be_bytes());
+
+    rdb_length(&mut rdb, 1);
+    rdb_string(&mut rdb, "group");
+    for value in [1000, 0, 1000] {
+        rdb_length(&mut rdb, value);
+    }
+    rdb_length(&mut rdb, 1);
+    rdb.extend(&pending_id);
+    rdb.extend(1_700_000_000_000u64.to_le_bytes());
+    rdb_length(&mut rdb, 1);
+
+    rdb_length(&mut rdb, 1);
+    rdb_string(&mut rdb, "consumer");
+    rdb.extend(1_700_000_000_000u64.to_le_bytes());
+    rdb.extend(1_700_000_000_000u64.to_le_bytes());
+    rdb_length(&mut rdb, 1);
+    rdb.extend(&pending_id);
+
+    // A key after the stream only loads when the groups were read past exactly
+    rdb.push(0x00);
+    rdb_string(&mut rdb, "after");
+    rdb_string(&mut rdb, "value");
+    rdb.extend_from_slice(b"\xFF\x00\x00\x00\x00\x00\x00\x00\x00");
+
+    let mut store = Store::new();
+    store.import(&rdb);
+
+    let stream = store.get_stream(&key).unwrap();
+    assert_eq!(stream.entries.len(), 1000);
+    assert_eq!(
+        stream.entries[999].1.data,
+        vec![("field".to_string(), "1000".to_string())]
+    );
+    assert!(store.exists(&"after".to_string()));
+}
+
+#[test]
+fn copies_keep_the_expiry_and_are_independent() {
+    let mut store = Store::new();
+    let expiry_at = SystemTime::now() + Duration::from_millis(1500);
+
+    store.set_kv_value(
+        "volatile".to_string(),
+        Entry {
+            value: "value".to_string().into(),
+            expiry_at: Some(expiry_at),
+        },
+    );
+
+    let volatile = "volatile".to_string();
+    assert_eq!(store.copy(&volatile, "copy".to_string(), false), Ok(true));
+    assert_eq!(store.expiry_at(&"copy".to_string()), Some(expiry_at));
+
+    // Replacing takes the expiry of the source along, even when it has none
+    store.set_kv_value("plain".to_string(), Entry::new("plain".to_string(), None));
+    assert_eq!(store.copy(&volatile, "plain".to_string(), false), Ok(false));
+    assert_eq!(
+        store.copy(&"plain".to_string(), "copy".to_string(), true),
+        Ok(true)
+    );
+    assert_eq!(store.expiry_at(&"copy".to_string()), None);
+
+    let stream = "stream".to_string();
+    let data = |value: &str| StreamData {
+        data: vec![("field".to_string(), value.to_string())],
+    };
+    store
+        .append_stream_value(&stream, "1-1", data("first"))
+        .unwrap();
+    assert_eq!(
+        store.copy(&stream, "stream-copy".to_string(), false),
+        Ok(true)
+    );
+    store
+        .append_stream_value(&stream, "2-1", data("second"))
+        .unwrap();
+
+    let copy = store.get_stream(&"stream-copy".to_string()).unwrap();
+    assert_eq!(copy.entries.len(), 1);
+    assert_eq!(store.get_stream(&stream).unwrap().entries.len(), 2);
+
+    assert_eq!(
+        store.copy(&stream, stream.clone(), true),
+        Err(CommandError::SameObject)
+    );
+    assert_eq!(
+        store.copy(&"missing".to_string(), "copy".to_string(), true),
+        Ok(false)
+    );
+}
+
+// The running estimate has to end up where measuring every key from scratch would
+#[test]
+fn dataset_memory_usage_follows_every_write() {
+    let mut store = Store::new();
+    let recount = |store: &Store| -> usize {
+        let (_, keys) = store.scan(0, usize::MAX);
+        keys.iter()
+            .map(|key| store.memory_usage(key, 0).unwrap())
+            .sum()
+    };
+
+    let string = "string".to_string();
+    let counter = "counter".to_string();
+    let stream = "stream".to_string();
+
+    store.set_kv_value(string.clone(), Entry::new("value".to_string(), None));
+    store.append(&string, b"appended", usize::MAX).unwrap();
+    store.set_range(&string, 30, b"far", usize::MAX).unwrap();
+    store
+        .set_bit(&"bits".to_string(), 100, true, usize::MAX)
+        .unwrap();
+    store.incr_by(&counter, 5).unwrap();
+    store.set_kv_value(
+        counter.clone(),
+        Entry::new("a longer value".to_string(), None),
+    );
+    store.incr_by(&"other".to_string(), 1).unwrap();
+    assert_eq!(store.dataset_memory_usage(), recount(&store));
+
+    for id in ["1-1", "2-1", "3-1"] {
+        let data = StreamData {
+            data: vec![("field".to_string(), id.repeat(4))],
+        };
+        store.append_stream_value(&stream, id, data).unwrap();
+    }
+    store
+        .copy(&stream, "stream-copy".to_string(), false)
+        .unwrap();
+    assert_eq!(store.dataset_memory_usage(), recount(&store));
+
+    store.delete(&string);
+    store.delete(&stream);
+    store.set_kv_value(counter.clone(), Entry::new("short".to_string(), None));
+    assert_eq!(store.dataset_memory_usage(), recount(&store));
+
+    store.flush();
+    assert_eq!(store.dataset_memory_usage(), 0);
+}
+
+// A file cut short anywhere loads the keys before the cut, and never takes the server down
+#[test]
+fn truncated_rdb_files_load_what_they_can() {
+    let mut source = Store::new();
+    source.set_kv_value(
+        "volatile".to_string(),
+        Entry::new("value".to_string(), Some(Duration::from_secs(100))),
+    );
+    source.set_kv_value("number".to_string(), Entry::new("12345".to_string(), None));
+    source.set_kv_value("long".to_string(), Entry::new("a".repeat(200), None));
+    source
+        .append_stream_value(
+            &"stream".to_string(),
+            "1-1",
+            StreamData {
+                data: vec![("field".to_string(), "value".to_string())],
+            },
+        )
+        .unwrap();
+
+    let rdb = source.export();
+
+    for length in 0..rdb.len() {
+        let mut store = Store::new();
+        store.import(&rdb[..length]);
+
+        assert!(store.len() <= source.len(), "cut at {}", length);
+    }
+
+    let mut store = Store::new();
+    store.import(&rdb);
+    assert_eq!(store.len(), source.len());
+}